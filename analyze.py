@@ -1,6 +1,18 @@
+import ast
+import csv
+import email.utils
+from frapi import parse_date
+import hashlib
+import html
+import http.server
+import json
 import math
+import os
 import pandas as pd
 import matplotlib.pyplot as plt
+import re
+import sqlite3
+import urllib.parse
 
 def make_autopct(values):
     def my_autopct(pct):
@@ -10,6 +22,129 @@ def make_autopct(values):
     return my_autopct
 
 
+def merge_shards(results_dir, read_csv, table="fr_doc_analysis"):
+    '''
+    Merge the per-Part shards of a backend.py run made with --shard-by into a single table of FR documents, or of another
+    sharded table, e.g. "cfr_coverage". A document attributed to several Parts appears in each of their shards, so it is
+    de-duplicated by FR document number.
+    '''
+    with open(os.path.join(results_dir, "manifest.json"), "r") as f:
+        manifest = json.load(f)
+    # Results written with an export profile may leave out a table
+    shards = [read_csv(os.path.join(results_dir, shard[table])) for shard in manifest["shards"] if table in shard]
+    if len(shards) == 0:
+        return pd.DataFrame()
+    merged = pd.concat(shards, ignore_index=True)
+    if "fr-docno" in merged.columns:
+        merged = merged.drop_duplicates(subset="fr-docno", ignore_index=True)
+    return merged
+
+
+def csv_read_options(results_dir):
+    '''
+    The keyword arguments of read_csv for the tables of a results directory, by the --csv-separator, --csv-quoting, and
+    --csv-encoding recorded in its run.json. Results written before these options existed are plain CSV.
+    '''
+    try:
+        with open(os.path.join(results_dir, "run.json"), "r") as f:
+            arguments = json.load(f).get("arguments", {})
+    except FileNotFoundError:
+        arguments = {}
+    separator = arguments.get("csv_separator") or ","
+    options = {"sep": "\t" if separator == "tab" else separator, "encoding": arguments.get("csv_encoding") or "utf-8"}
+    if arguments.get("csv_quoting") == "none":
+        options.update(quoting=csv.QUOTE_NONE, escapechar="\\")
+    return options
+
+
+def run_metadata(inputs):
+    '''
+    The --label and --tag metadata recorded in the run.json of each results directory in inputs, as [{"run", "label",
+    "tags"}]. Inputs that aren't results directories of a run are left out.
+    '''
+    runs = []
+    for input in inputs:
+        try:
+            with open(os.path.join(input, "run.json"), "r") as f:
+                manifest = json.load(f)
+        except (FileNotFoundError, NotADirectoryError):
+            continue
+        runs.append({"run": os.path.basename(os.path.realpath(input)), "label": manifest.get("label"), "tags": manifest.get("tags", {})})
+    return runs
+
+
+def load_tables(inputs):
+    '''
+    Load the results of backend.py as {name: table}. Each .csv file in inputs, or in an input results directory, compressed
+    or not, is loaded as a table named after the file, e.g. fr_doc_analysis and cfr_coverage, with the shards of a sharded
+    results directory merged back together. Tables of the same name in several inputs are concatenated. List columns are
+    left as their string form.
+    '''
+    tables = {}
+    for input in inputs:
+        if not os.path.isdir(input):
+            tables.setdefault(os.path.basename(input).split(".")[0], []).append(pd.read_csv(input, index_col=0))
+            continue
+        options = csv_read_options(input)
+        for fname in sorted(os.listdir(input)):
+            # Results written with --compress are e.g. fr_doc_analysis.csv.gz, which read_csv decompresses
+            if fname.endswith((".csv", ".csv.gz", ".csv.zst")):
+                tables.setdefault(fname.split(".")[0], []).append(pd.read_csv(os.path.join(input, fname), index_col=0, **options))
+        if os.path.exists(os.path.join(input, "manifest.json")):
+            read_csv = lambda path: pd.read_csv(path, index_col=0, **options)
+            for table in ["fr_doc_analysis", "cfr_coverage"]:
+                tables.setdefault(table, []).append(merge_shards(input, read_csv, table))
+    return {name: pd.concat(dfs, ignore_index=True) for name, dfs in tables.items()}
+
+
+def query(inputs, sql):
+    '''
+    Run a read-only SQL query over the results of backend.py, loaded with load_tables. List columns are left as their string
+    form, so match them with LIKE.
+    '''
+    db = sqlite3.connect(":memory:")
+    for name, table in load_tables(inputs).items():
+        table.to_sql(name, db, index=False)
+    db.execute("PRAGMA query_only = ON")
+    try:
+        return pd.read_sql_query(sql, db)
+    finally:
+        db.close()
+
+
+# The FR document number column of the v0.1 proof-of-concept's result CSVs, by the name it may have
+LEGACY_DOCNO_COLUMNS = ["document_number", "docno", "fr-docno", "document-number"]
+
+
+def verify_against(inputs, legacy_path):
+    '''
+    Compare the FR documents in the results of backend.py with those in a result CSV of the v0.1 proof-of-concept, whose
+    rag.py analyzed a time slice of the FR rather than the documents attributed to the CFR. Returns a table of the
+    differences, one row per FR document: "missing" if only the proof-of-concept analyzed it, "new" if only backend.py did,
+    or "differing-answer" if both did but one answered yes and the other no.
+    '''
+    results = load_tables(inputs).get("fr_doc_analysis", pd.DataFrame(columns=["fr-docno"]))
+    legacy = pd.read_csv(legacy_path)
+    docno_columns = [col for col in LEGACY_DOCNO_COLUMNS if col in legacy.columns]
+    if len(docno_columns) == 0:
+        raise ValueError(f"{legacy_path} has none of the FR document number columns {', '.join(LEGACY_DOCNO_COLUMNS)}")
+    legacy = legacy.rename(columns={docno_columns[0]: "fr-docno"}).drop_duplicates(subset="fr-docno")
+    results = results.drop_duplicates(subset="fr-docno")
+    yes_no = lambda answer: answer.strip().lower().split(".")[0].split(",")[0] if isinstance(answer, str) else None
+    legacy_answers = dict(zip(legacy["fr-docno"], legacy["answer"].map(yes_no))) if "answer" in legacy.columns else {}
+    answers = dict(zip(results["fr-docno"], results["llm-answer"].map(yes_no))) if "llm-answer" in results.columns else {}
+
+    rows = []
+    for docno in sorted(set(legacy["fr-docno"]) - set(results["fr-docno"])):
+        rows.append({"fr-docno": docno, "difference": "missing", "legacy-answer": legacy_answers.get(docno), "llm-answer": None})
+    for docno in sorted(set(results["fr-docno"]) - set(legacy["fr-docno"])):
+        rows.append({"fr-docno": docno, "difference": "new", "legacy-answer": None, "llm-answer": answers.get(docno)})
+    for docno in sorted(set(legacy["fr-docno"]) & set(results["fr-docno"])):
+        legacy_answer, answer = legacy_answers.get(docno), answers.get(docno)
+        if legacy_answer in ("yes", "no") and answer in ("yes", "no") and legacy_answer != answer:
+            rows.append({"fr-docno": docno, "difference": "differing-answer", "legacy-answer": legacy_answer, "llm-answer": answer})
+    return pd.DataFrame(rows, columns=["fr-docno", "difference", "legacy-answer", "llm-answer"])
+
 def summary_by_agency(results):
     # fig, (ax1, ax2, ax3) = plt.subplots(1, 3, num="Summary By Agency")
     # ax2_ov = ax2.twinx()
@@ -114,15 +249,532 @@ def summary_by_rule(results):
     plt.title('Percentage of Unstatutory Rules Grouped by Rule Size')
     
 
+################################################
+# Functions for exporting a static results site #
+################################################
+
+SITE_STYLE = """
+body { font-family: sans-serif; max-width: 60em; margin: 2em auto; padding: 0 1em; line-height: 1.4; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border-bottom: 1px solid #ddd; padding: 0.3em 0.5em; text-align: left; vertical-align: top; }
+img { max-width: 100%; }
+nav { margin-bottom: 1em; }
+"""
+
+
+def parse_list(text):
+    '''
+    Parse a list column of the result CSVs, e.g. "['EPA', 'DOT']", into a list. Empty or unparseable cells are [].
+    '''
+    if isinstance(text, list):
+        return text
+    try:
+        value = ast.literal_eval(text) if isinstance(text, str) else []
+    except (ValueError, SyntaxError):
+        return []
+    return list(value) if isinstance(value, (list, tuple)) else []
+
+
+def site_slug(text):
+    return re.sub(r"[^A-Za-z0-9.-]+", "-", str(text)).strip("-")
+
+
+def write_page(site_dir, name, title, body):
+    with open(os.path.join(site_dir, name), "w") as f:
+        f.write(f"<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{html.escape(title)}</title><style>{SITE_STYLE}</style></head>\n")
+        f.write(f"<body><nav><a href=\"index.html\">Doge Guard results</a></nav><h1>{html.escape(title)}</h1>\n{body}\n</body></html>\n")
+
+
+def html_table(header, rows):
+    '''
+    An HTML table of rows of cells, which are already escaped HTML.
+    '''
+    head = "".join(f"<th>{html.escape(cell)}</th>" for cell in header)
+    body = "".join("<tr>" + "".join(f"<td>{cell}</td>" for cell in row) + "</tr>\n" for row in rows)
+    return f"<table><tr>{head}</tr>\n{body}</table>"
+
+
+def rules_per_year_chart(years, path, title):
+    '''
+    Save an SVG bar chart of the number of FR documents published each year, from a Series of their years, to path.
+    '''
+    counts = years.dropna().astype(int).value_counts().sort_index()
+    if len(counts) > 0:
+        counts = counts.reindex(range(counts.index.min(), counts.index.max() + 1), fill_value=0)
+    fig, ax = plt.subplots(1, 1, figsize=(8, 3))
+    counts.plot.bar(ax=ax, rot=-45, width=0.8)
+    ax.set_title(title, wrap=True)
+    ax.set_xlabel("Year Published")
+    ax.set_ylabel("Final Rules")
+    fig.tight_layout()
+    fig.savefig(path, format="svg")
+    plt.close(fig)
+
+
+def write_rules_per_year_charts(tables, charts_dir):
+    '''
+    Save a chart of the Final Rules published per year for each CFR Part and each agency of the results of backend.py,
+    loaded with load_tables, to charts_dir. Returns {("part", titleno, partno) or ("agency", name): file name}.
+    '''
+    os.makedirs(charts_dir, exist_ok=True)
+    docs = tables.get("fr_doc_analysis", pd.DataFrame(columns=["fr-docno", "fr-doc-publication-date"])).drop_duplicates(subset="fr-docno")
+    cfr_cov = tables.get("cfr_coverage", pd.DataFrame(columns=["cfr-title", "cfr-part", "fr-docs-attributed"]))
+    years = pd.Series(pd.to_datetime(docs["fr-doc-publication-date"], errors="coerce").dt.year.values, index=docs["fr-docno"])
+    charts = {}
+    for _, part in cfr_cov.iterrows():
+        docnos = [docno for docno in parse_list(part["fr-docs-attributed"]) if docno in years.index]
+        if len(docnos) == 0:
+            continue
+        name = f"part-{site_slug(part['cfr-title'])}-{site_slug(part['cfr-part'])}.svg"
+        rules_per_year_chart(years[docnos], os.path.join(charts_dir, name), f"Final Rules Per Year, {part['cfr-title']} CFR Part {part['cfr-part']}")
+        charts[("part", str(part["cfr-title"]), str(part["cfr-part"]))] = name
+    if "fr-doc-agencies" in docs.columns:
+        agencies = docs.set_index("fr-docno")["fr-doc-agencies"].map(parse_list).explode().dropna()
+        for agency, docnos in agencies.groupby(agencies).groups.items():
+            name = f"agency-{site_slug(agency)}.svg"
+            rules_per_year_chart(years[list(docnos)], os.path.join(charts_dir, name), f"Final Rules Per Year, {agency}")
+            charts[("agency", agency)] = name
+    print(f"[*] Saved {len(charts)} charts of Final Rules per year to {charts_dir}")
+    return charts
+
+
+def write_site(tables, site_dir, runs=[]):
+    '''
+    Write a static HTML site of the results of backend.py, loaded with load_tables, to site_dir: an index of the CFR Titles
+    with charts, a page per Title listing its Parts, a page per Part listing its sections and FR documents, and a page per
+    FR document and per agency. The index lists the runs of the results with their labels and tags, from run_metadata. Any
+    static web host can serve it.
+    '''
+    os.makedirs(site_dir, exist_ok=True)
+    year_charts = write_rules_per_year_charts(tables, os.path.join(site_dir, "charts"))
+    docs = tables.get("fr_doc_analysis", pd.DataFrame(columns=["fr-docno"])).drop_duplicates(subset="fr-docno")
+    cfr_cov = tables.get("cfr_coverage", pd.DataFrame(columns=["cfr-title", "cfr-part", "fr-docs-attributed"]))
+    divisions = tables.get("cfr_divisions")
+    answer_columns = [col for col in docs.columns if col.startswith("llm-answer")]
+    docs_by_no = {doc["fr-docno"]: doc for _, doc in docs.iterrows()}
+
+    def doc_link(docno):
+        doc = docs_by_no.get(docno)
+        title = doc["fr-doc-title"] if doc is not None and "fr-doc-title" in doc and pd.notna(doc["fr-doc-title"]) else docno
+        return f"<a href=\"doc-{site_slug(docno)}.html\">{html.escape(str(title))}</a>"
+
+    def doc_rows(docnos):
+        rows = []
+        for docno in docnos:
+            doc = docs_by_no.get(docno, {})
+            rows.append([
+                doc_link(docno),
+                html.escape(str(doc.get("fr-doc-citation", ""))),
+                html.escape(str(doc.get("fr-doc-publication-date", ""))),
+                *[html.escape(str(doc.get(col, ""))) for col in answer_columns],
+            ])
+        return html_table(["FR document", "Citation", "Published", *answer_columns], rows)
+
+    # Charts of the agencies issuing the most FR documents, and of the answers to each question
+    charts = []
+    if "fr-doc-agencies-shorthand" in docs.columns and len(docs) > 0:
+        fig, ax = plt.subplots(1, 1)
+        docs["fr-doc-agencies-shorthand"].map(parse_list).explode().dropna().value_counts().head(10).plot.bar(ax=ax, rot=-45)
+        ax.set_title("Top 10 Issuing Agencies")
+        ax.set_ylabel("FR Documents")
+        fig.tight_layout()
+        fig.savefig(os.path.join(site_dir, "agencies.png"))
+        plt.close(fig)
+        charts.append("agencies.png")
+    for col in answer_columns:
+        answers = docs[col].dropna().astype(str).str.strip().str.split(".").str[0].str.lower().value_counts().head(8)
+        if len(answers) == 0:
+            continue
+        fig, ax = plt.subplots(1, 1)
+        answers.plot.pie(ax=ax, autopct="%1.1f%%")
+        ax.set_title(col)
+        ax.set_ylabel("")
+        fig.savefig(os.path.join(site_dir, f"{col}.png"))
+        plt.close(fig)
+        charts.append(f"{col}.png")
+
+    # Index of Titles
+    titles = sorted(cfr_cov["cfr-title"].dropna().unique(), key=lambda titleno: int(titleno) if str(titleno).isdigit() else 0)
+    rows = [[f"<a href=\"title-{site_slug(titleno)}.html\">{titleno} CFR</a>", str((cfr_cov["cfr-title"] == titleno).sum())] for titleno in titles]
+    body = f"<p>{len(docs)} FR documents attributed to {len(cfr_cov)} CFR Parts.</p>\n"
+    if len(runs) > 0:
+        run_rows = [[html.escape(run["run"]), html.escape(run["label"] or ""), html.escape(", ".join(f"{key}={value}" for key, value in sorted(run["tags"].items())))] for run in runs]
+        body += html_table(["Run", "Label", "Tags"], run_rows)
+    body += "".join(f"<img src=\"{chart}\">\n" for chart in charts)
+    body += "<h2>CFR Titles</h2>\n" + html_table(["Title", "Parts"], rows)
+    body += "<h2>Agencies</h2>\n"
+    agencies = sorted(set(docs["fr-doc-agencies"].map(parse_list).explode().dropna())) if "fr-doc-agencies" in docs.columns else []
+    body += "<ul>" + "".join(f"<li><a href=\"agency-{site_slug(agency)}.html\">{html.escape(agency)}</a></li>" for agency in agencies) + "</ul>"
+    write_page(site_dir, "index.html", "Doge Guard results", body)
+
+    # Title and Part pages
+    for titleno in titles:
+        parts = cfr_cov[cfr_cov["cfr-title"] == titleno]
+        rows = [[f"<a href=\"part-{site_slug(titleno)}-{site_slug(part['cfr-part'])}.html\">Part {html.escape(str(part['cfr-part']))}</a>", str(len(parse_list(part["fr-docs-attributed"])))] for _, part in parts.iterrows()]
+        write_page(site_dir, f"title-{site_slug(titleno)}.html", f"{titleno} CFR", html_table(["Part", "FR documents"], rows))
+        for _, part in parts.iterrows():
+            body = ""
+            chart = year_charts.get(("part", str(titleno), str(part["cfr-part"])))
+            if chart is not None:
+                body += f"<img src=\"charts/{chart}\">\n"
+            if divisions is not None:
+                part_divs = divisions[(divisions["cfr-title"].astype(str) == str(titleno)) & (divisions["cfr-part"].astype(str) == str(part["cfr-part"]))]
+                rows = [[html.escape(str(div["cfr-division"])), ", ".join(doc_link(docno) for docno in parse_list(div["fr-docnos"]))] for _, div in part_divs.iterrows()]
+                body += "<h2>Divisions</h2>\n" + html_table(["Division", "FR documents"], rows)
+            body += "<h2>FR documents</h2>\n" + doc_rows(parse_list(part["fr-docs-attributed"]))
+            write_page(site_dir, f"part-{site_slug(titleno)}-{site_slug(part['cfr-part'])}.html", f"{titleno} CFR Part {part['cfr-part']}", body)
+
+    # FR document pages
+    fields = ["fr-doc-citation", "fr-doc-publication-date", "fr-doc-type", "fr-doc-agencies", "fr-doc-abstract", "cfr-divs-referenced-in", "cfr-words-in-force"]
+    for docno, doc in docs_by_no.items():
+        rows = [[html.escape(field), html.escape(str(doc[field]))] for field in fields + answer_columns if field in doc and pd.notna(doc[field])]
+        rows.append(["FederalRegister.gov", f"<a href=\"https://www.federalregister.gov/d/{html.escape(str(docno))}\">{html.escape(str(docno))}</a>"])
+        title = doc["fr-doc-title"] if "fr-doc-title" in doc and pd.notna(doc["fr-doc-title"]) else docno
+        write_page(site_dir, f"doc-{site_slug(docno)}.html", str(title), html_table(["Field", "Value"], rows))
+
+    # Agency pages
+    for agency in agencies:
+        docnos = [doc["fr-docno"] for _, doc in docs.iterrows() if agency in parse_list(doc["fr-doc-agencies"])]
+        chart = year_charts.get(("agency", agency))
+        body = f"<img src=\"charts/{chart}\">\n" if chart is not None else ""
+        write_page(site_dir, f"agency-{site_slug(agency)}.html", agency, body + doc_rows(docnos))
+    print(f"[*] Wrote a site of {len(docs)} FR documents to {site_dir}. Open {os.path.join(site_dir, 'index.html')}")
+
+
+#######################################################
+# Functions for serving the results by issuing agency #
+#######################################################
+
+# The columns of the levels of the CFR's hierarchy in cfr_divisions.csv. See cfr.DIVISION_LEVELS.
+DIVISION_LEVEL_COLUMNS = ["cfr-chapter", "cfr-subchapter", "cfr-part", "cfr-subpart", "cfr-subject-group", "cfr-section", "cfr-appendix"]
+# The columns of the FR documents served by /api/v1/agencies/{slug}/docs
+AGENCY_DOC_COLUMNS = [
+    "fr-docno", "fr-doc-type", "fr-doc-citation", "fr-doc-title", "fr-doc-abstract", "fr-doc-publication-date",
+    "fr-doc-effective-date", "fr-doc-significant", "fr-doc-agencies", "fr-doc-agencies-shorthand", "cfr-divs-referenced-in",
+]
+
+
+def agency_index(tables):
+    '''
+    Map the slug of each agency issuing the FR documents of the results, e.g. environmental-protection-agency as in its
+    FederalRegister.gov URL, to its name, short name, and FR docnos. Its short name, lowercased, e.g. epa, also maps to it.
+    '''
+    docs = tables.get("fr_doc_analysis", pd.DataFrame(columns=["fr-docno", "fr-doc-agencies"]))
+    agencies = tables.get("agencies", pd.DataFrame(columns=["agency", "agency-short-name", "agency-url"]))
+    info = {row["agency"]: row for _, row in agencies.iterrows()}
+    index = {}
+    for _, doc in docs.drop_duplicates(subset="fr-docno").iterrows():
+        for name in parse_list(doc["fr-doc-agencies"]):
+            row = info.get(name)
+            url = row["agency-url"] if row is not None and isinstance(row["agency-url"], str) else None
+            slug = url.rstrip("/").split("/")[-1] if url else site_slug(name).lower()
+            agency = index.setdefault(slug, {"slug": slug, "name": name, "short-name": row["agency-short-name"] if row is not None else None, "fr-docnos": []})
+            agency["fr-docnos"].append(doc["fr-docno"])
+    for agency in list(index.values()):
+        if isinstance(agency["short-name"], str):
+            index.setdefault(agency["short-name"].lower(), agency)
+    return index
+
+
+def filter_docs(docs, params):
+    '''
+    The rows of docs filtered by the query parameters since and until, publication dates like 2020-01-31, and significant,
+    true or false. Documents without a publication date are left out by since and until. Raises a ValueError for a bad
+    parameter.
+    '''
+    try:
+        since, until = parse_date(params.get("since")), parse_date(params.get("until"))
+    except (TypeError, ValueError):
+        raise ValueError(f"since and until must be dates like 2020-01-31, not {params.get('since')} and {params.get('until')}")
+    if params.get("significant", "true").lower() not in ("true", "false"):
+        raise ValueError(f"significant must be true or false, not {params['significant']}")
+    if since is not None or until is not None:
+        dates = docs["fr-doc-publication-date"].map(lambda date : parse_date(date) if isinstance(date, str) else None)
+        docs = docs[dates.map(lambda date : date is not None and (since is None or date >= since) and (until is None or date <= until))]
+    if "significant" in params:
+        significant = docs["fr-doc-significant"].astype(str).str.lower().isin(["true", "1", "1.0"])
+        docs = docs[significant == (params["significant"].lower() == "true")]
+    return docs
+
+
+def filter_agency_docs(docs, agency, params):
+    '''
+    The rows of docs of an agency from agency_index, filtered by the query parameters. See filter_docs.
+    '''
+    return filter_docs(docs[docs["fr-docno"].isin(agency["fr-docnos"])].drop_duplicates(subset="fr-docno"), params)
+
+
+def results_version(inputs):
+    '''
+    The time the results files in inputs were last modified, as a Unix time, and an ETag of them, from the paths, sizes, and
+    modification times of the files. Results only change when a run completes, so these change with them.
+    '''
+    paths = []
+    for input in inputs:
+        if os.path.isdir(input):
+            paths.extend(os.path.join(dirpath, fname) for dirpath, _, fnames in os.walk(input) for fname in fnames)
+        else:
+            paths.append(input)
+    stats = sorted((os.path.realpath(path), os.stat(path)) for path in paths)
+    etag = hashlib.sha256("".join(f"{path}:{stat.st_size}:{stat.st_mtime_ns}\n" for path, stat in stats).encode()).hexdigest()[:32]
+    return max((stat.st_mtime for _, stat in stats), default=0), f'"{etag}"'
+
+
+def serve_results(inputs, host, port):
+    '''
+    Serve the results of backend.py, loaded with load_tables, as a read-only JSON API by issuing agency, for consumers that
+    don't know the CFR's structure:
+
+    GET /api/v1/runs                      the runs of the results, with their labels and tags (see run_metadata)
+    GET /api/v1/agencies                  the agencies, with their slugs and FR document counts
+    GET /api/v1/agencies/{slug}/docs      the FR documents an agency issued
+    GET /api/v1/agencies/{slug}/analysis  the LLM analysis of those documents
+    GET /api/v1/cfr/{title}/{part}/divisions  the divisions of a CFR Part citing the FR, with their word counts, FR
+                                              citations, and the FR documents those are attributed to
+    GET /api/v1/parts/{title}/{part}/analysis the share of a CFR Part's FR documents with each answer to each question,
+                                              e.g. how many impose a reporting requirement, from part_answers, with the
+                                              documents of each answer and a link to their per-document answers
+    GET /api/v1/export/docs?format=ndjson     every FR document and its analysis, one JSON object per line, streamed with
+                                              chunked transfer, optionally of one ?agency=epa or ?part=40:63
+
+    The documents can be filtered with ?since=2020-01-01&until=2024-12-31&significant=true. The results are loaded once,
+    when the server starts, so every result has the same ETag and Last-Modified, and a request with a matching
+    If-None-Match or an If-Modified-Since no older than the results gets a 304 Not Modified (see results_version).
+    '''
+    tables = load_tables(inputs)
+    docs = tables.get("fr_doc_analysis", pd.DataFrame(columns=AGENCY_DOC_COLUMNS))
+    cfr_cov = tables.get("cfr_coverage", pd.DataFrame(columns=["cfr-title", "cfr-part", "fr-docs-attributed"]))
+    index = agency_index(tables)
+    runs = run_metadata(inputs)
+    last_modified, etag = results_version(inputs)
+    divisions = tables.get("cfr_divisions")
+    if divisions is not None:
+        # Of the same division in several inputs, the last input's is served
+        divisions = divisions.drop_duplicates(subset=["cfr-title", "cfr-part", "cfr-division", "cfr-division-type"], keep="last")
+    part_answers = tables.get("part_answers")
+    if part_answers is not None:
+        part_answers = part_answers.drop_duplicates(subset=["cfr-title", "cfr-part", "question-id", "answer"], keep="last")
+    analysis_columns = ["fr-docno"] + [col for col in docs.columns if col.startswith("llm-answer") or col.startswith("llm-citations") or col.startswith("llm-error") or col.startswith("llm-model")]
+
+    def records(table):
+        return table.astype(object).where(table.notna(), None).to_dict(orient="records")
+
+    def part_divisions(titleno, partno):
+        part_divs = divisions[(divisions["cfr-title"].astype(str) == titleno) & (divisions["cfr-part"].astype(str) == partno)]
+        return [
+            {
+                "name": division["cfr-division"],
+                "type": division["cfr-division-type"],
+                "hierarchy": {level: division[level] for level in DIVISION_LEVEL_COLUMNS if division.get(level) is not None},
+                "operative-status": division.get("cfr-division-operative-status"),
+                "word-count": division.get("cfr-division-word-count"),
+                "fr-citations": parse_list(division.get("fr-citations")),
+                "fr-docnos": parse_list(division["fr-docnos"]),
+            }
+            for division in records(part_divs)
+        ]
+
+    def part_analysis(titleno, partno):
+        answers = part_answers[(part_answers["cfr-title"].astype(str) == titleno) & (part_answers["cfr-part"].astype(str) == partno)]
+        questions = {}
+        for answer in records(answers):
+            questions.setdefault(answer["question-id"], []).append({
+                "answer": answer["answer"],
+                "fr-doc-count": answer["fr-doc-count"],
+                "fr-doc-share": answer["fr-doc-share"],
+                "fr-docnos": parse_list(answer["fr-docnos"]),
+            })
+        return [{"question-id": question_id, "answers": question_answers} for question_id, question_answers in questions.items()]
+
+    def export_docs(params):
+        '''
+        The rows of docs to export, of the agency and CFR Part of params, if any, filtered by filter_docs. Raises a
+        ValueError for a bad parameter.
+        '''
+        export = docs.drop_duplicates(subset="fr-docno")
+        if "agency" in params:
+            agency = index.get(params["agency"]) or index.get(params["agency"].lower())
+            if agency is None:
+                raise ValueError(f"no agency {params['agency']} in the results")
+            export = export[export["fr-docno"].isin(agency["fr-docnos"])]
+        if "part" in params:
+            titleno, _, partno = params["part"].partition(":")
+            if partno == "":
+                raise ValueError(f"part must be TITLE:PART, e.g. 40:63, not {params['part']}")
+            part_cov = cfr_cov[(cfr_cov["cfr-title"].astype(str) == titleno) & (cfr_cov["cfr-part"].astype(str) == partno)]
+            export = export[export["fr-docno"].isin({docno for docnos in part_cov["fr-docs-attributed"] for docno in parse_list(docnos)})]
+        return filter_docs(export, params)
+
+    class ResultsHandler(http.server.BaseHTTPRequestHandler):
+        # For the chunked transfer of /api/v1/export/docs
+        protocol_version = "HTTP/1.1"
+
+        def not_modified(self):
+            '''
+            Reply 304 Not Modified if the request's If-None-Match or If-Modified-Since says the client has the results.
+            Returns whether it did. Only checked in place of a 200, so a bad path or parameter still gets its 404 or 400.
+            '''
+            if_none_match = self.headers.get("If-None-Match")
+            if if_none_match is not None:
+                fresh = if_none_match.strip() == "*" or etag in [tag.strip().removeprefix("W/") for tag in if_none_match.split(",")]
+            else:
+                try:
+                    since = email.utils.parsedate_to_datetime(self.headers.get("If-Modified-Since", ""))
+                    fresh = since.timestamp() >= int(last_modified)
+                except (TypeError, ValueError):
+                    fresh = False
+            if fresh:
+                self.send_response(304)
+                self.send_validators()
+                self.send_header("Content-Length", "0")
+                self.end_headers()
+            return fresh
+
+        def send_validators(self):
+            self.send_header("ETag", etag)
+            self.send_header("Last-Modified", email.utils.formatdate(last_modified, usegmt=True))
+
+        def reply(self, status, content):
+            if status == 200 and self.not_modified():
+                return
+            body = json.dumps(content, default=str).encode()
+            self.send_response(status)
+            if status == 200:
+                self.send_validators()
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Content-Length", str(len(body)))
+            self.end_headers()
+            self.wfile.write(body)
+
+        def stream_ndjson(self, rows, batch_size=100):
+            if self.not_modified():
+                return
+            self.send_response(200)
+            self.send_validators()
+            self.send_header("Content-Type", "application/x-ndjson")
+            self.send_header("Transfer-Encoding", "chunked")
+            self.end_headers()
+            for i in range(0, len(rows), batch_size):
+                chunk = "".join(json.dumps(row, default=str) + "\n" for row in records(rows.iloc[i : i + batch_size])).encode()
+                self.wfile.write(f"{len(chunk):X}\r\n".encode() + chunk + b"\r\n")
+            self.wfile.write(b"0\r\n\r\n")
+
+        def do_GET(self):
+            request = urllib.parse.urlsplit(self.path)
+            params = {key: values[0] for key, values in urllib.parse.parse_qs(request.query).items()}
+            path = request.path.rstrip("/").split("/")
+            if path == ["", "api", "v1", "runs"]:
+                return self.reply(200, runs)
+            if path == ["", "api", "v1", "export", "docs"]:
+                if params.get("format", "ndjson") != "ndjson":
+                    return self.reply(400, {"error": f"format must be ndjson, not {params['format']}"})
+                try:
+                    rows = export_docs(params)
+                except (KeyError, ValueError) as e:
+                    return self.reply(400, {"error": f"{type(e).__name__}: {e}"})
+                return self.stream_ndjson(rows)
+            if len(path) == 7 and path[:4] == ["", "api", "v1", "cfr"] and path[6] == "divisions":
+                if divisions is None:
+                    return self.reply(404, {"error": "the results have no cfr_divisions table"})
+                part_divs = part_divisions(path[4], path[5])
+                if len(part_divs) == 0:
+                    return self.reply(404, {"error": f"no divisions of {path[4]} CFR Part {path[5]} citing the FR in the results"})
+                return self.reply(200, part_divs)
+            if len(path) == 7 and path[:4] == ["", "api", "v1", "parts"] and path[6] == "analysis":
+                if part_answers is None:
+                    return self.reply(404, {"error": "the results have no part_answers table"})
+                analysis = part_analysis(path[4], path[5])
+                if len(analysis) == 0:
+                    return self.reply(404, {"error": f"no LLM analysis of the FR documents of {path[4]} CFR Part {path[5]} in the results"})
+                return self.reply(200, {
+                    "cfr-title": path[4],
+                    "cfr-part": path[5],
+                    "questions": analysis,
+                    "fr-doc-answers": f"/api/v1/export/docs?format=ndjson&part={path[4]}:{path[5]}",
+                })
+            if path == ["", "api", "v1", "agencies"]:
+                agencies = {agency["slug"]: agency for agency in index.values()}.values()
+                return self.reply(200, [{"slug": a["slug"], "name": a["name"], "short-name": a["short-name"], "fr-doc-count": len(set(a["fr-docnos"]))} for a in agencies])
+            if len(path) != 6 or path[:4] != ["", "api", "v1", "agencies"] or path[5] not in ("docs", "analysis"):
+                return self.reply(404, {"error": "use /api/v1/runs, /api/v1/agencies, /api/v1/agencies/{slug}/docs, /api/v1/agencies/{slug}/analysis, /api/v1/cfr/{title}/{part}/divisions, /api/v1/parts/{title}/{part}/analysis, or /api/v1/export/docs"})
+            agency = index.get(path[4]) or index.get(path[4].lower())
+            if agency is None:
+                return self.reply(404, {"error": f"no agency {path[4]} in the results"})
+            try:
+                agency_docs = filter_agency_docs(docs, agency, params)
+            except (KeyError, ValueError) as e:
+                return self.reply(400, {"error": f"{type(e).__name__}: {e}"})
+            columns = AGENCY_DOC_COLUMNS if path[5] == "docs" else analysis_columns
+            self.reply(200, records(agency_docs[[col for col in columns if col in agency_docs.columns]]))
+
+    server = http.server.ThreadingHTTPServer((host, port), ResultsHandler)
+    print(f"[*] Serving the results of {len({agency['slug'] for agency in index.values()})} agencies on {host}:{port}, e.g. http://{host}:{port}/api/v1/agencies. Press Ctrl-C to stop.")
+    try:
+        server.serve_forever()
+    except KeyboardInterrupt:
+        server.server_close()
+
+
 if __name__ == "__main__":
     import argparse
-    parser = argparse.ArgumentParser()
-    parser.add_argument("input", nargs="+", help="Input .csv file of rag.py results")
+    from completion import CompletionAction
+    examples = '''
+examples:
+  # Plot the results of a run
+  python analyze.py documents/runs/latest/fr_doc_analysis.csv
+
+  # Ask an ad-hoc question of a (possibly sharded) results directory in SQL
+  python analyze.py documents/runs/20250114T093000-cfr-2024-12-30-Title-40 --query "select [cfr-part], [fr-cita-unattributed] from cfr_coverage"
+
+  # Export a static site of the last run's results, to host anywhere
+  python analyze.py documents/runs/latest --site site/
+
+  # Compare the last run with the results of the v0.1 proof-of-concept, before retiring them
+  python analyze.py documents/runs/latest --verify-against v0.1-results.csv
+
+  # Serve the results by agency, e.g. GET http://127.0.0.1:8001/api/v1/agencies/epa/docs?since=2020-01-01&significant=true
+  python analyze.py documents/runs/latest --serve --port 8001
+
+  # Save only the charts of Final Rules per year of each CFR Part and agency, as SVG
+  python analyze.py documents/runs/latest --charts charts/
+'''
+    parser = argparse.ArgumentParser(epilog=examples, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--completion", action=CompletionAction, script="analyze.py", help="Print the shell completion script of analyze.py and exit.")
+    parser.add_argument("input", nargs="+", help="Input .csv file of rag.py results, or a results directory of backend.py written with --shard-by")
+    parser.add_argument("--query", metavar="SQL", default=None, help="Instead of plotting, run a read-only SQL query over the result tables of the inputs and print the result. E.g., --query \"select * from cfr_coverage where [fr-cita-unattributed] > 0\"")
+    parser.add_argument("--site", metavar="DIR", default=None, help="Instead of plotting, write a static HTML site of the results to DIR: an index by CFR Title, Part, and division, with a page per FR document and agency, and charts.")
+    parser.add_argument("--charts", metavar="DIR", default=None, help="Instead of plotting, save SVG charts of the Final Rules published per year for each CFR Part and agency of the results to DIR.")
+    parser.add_argument("--verify-against", metavar="CSV", default=None, help="Instead of plotting, list the FR documents that the results and a result CSV of the v0.1 proof-of-concept don't both have, or answered differently.")
+    parser.add_argument("--serve", action="store_true", default=False, help="Instead of plotting, serve the results as a JSON API by issuing agency. See serve_results.")
+    parser.add_argument("--host", default="127.0.0.1", help="The address --serve listens on, e.g. 0.0.0.0 for every network interface.")
+    parser.add_argument("--port", type=int, default=8001, help="The port --serve listens on.")
     args = parser.parse_args()
+
+    if args.serve:
+        serve_results(args.input, args.host, args.port)
+        exit(0)
+    if args.verify_against is not None:
+        differences = verify_against(args.input, args.verify_against)
+        counts = differences["difference"].value_counts()
+        print(f"[*] {counts.get('missing', 0)} FR documents only in {args.verify_against}, {counts.get('new', 0)} only in the results, and {counts.get('differing-answer', 0)} answered differently.")
+        with pd.option_context("display.max_rows", None, "display.max_columns", None, "display.width", None):
+            print(differences[differences["difference"] != "new"])
+        exit(0)
+    if args.charts is not None:
+        write_rules_per_year_charts(load_tables(args.input), args.charts)
+        exit(0)
+    if args.site is not None:
+        write_site(load_tables(args.input), args.site, run_metadata(args.input))
+        exit(0)
+    if args.query is not None:
+        with pd.option_context("display.max_rows", None, "display.max_columns", None, "display.width", None):
+            print(query(args.input, args.query))
+        exit(0)
     
     # converters={"agencies": pd.eval, "agency-shorthand": pd.eval}
     eval_lists = lambda x: x.strip("[]").replace("'","").split(", ")
-    results = [pd.read_csv(input, dtype={"rule_length": int}, converters={"agencies": eval_lists, "agency-shorthand": eval_lists}) for input in args.input]
+    read_csv = lambda path: pd.read_csv(path, dtype={"rule_length": int}, converters={"agencies": eval_lists, "agency-shorthand": eval_lists})
+    results = [merge_shards(input, read_csv) if os.path.isdir(input) else read_csv(input) for input in args.input]
     results = pd.concat(results, ignore_index=True)
     summary_by_rule(results)
     summary_by_agency(results)
@@ -1,769 +1,365 @@
-import cohere
-import datetime
-from dotenv import load_dotenv
-import hnswlib
-import json
-import lxml.etree as ET
+import atexit
+import cfr
+from cfr import CfrPart, cache_title_xml, extract_part_info, prefetch_structures
+from frapi import CFR_TITLES, ECFR_DATE, parse_date
+import frapi
+import llm
+from llm import API_CALL_RATE_LIMIT, DEFAULT_QUESTIONS, StreamingAnalysis, TOKEN_RATE_LIMIT, canary_check, division_groups, estimate_group_workload, estimate_llm_workload, group_analysis, llm_analysis, load_canaries, load_questions, part_briefs, projected_llm_time
 import os
-import pandas as pd
-from pathlib import Path
-import re
-import requests
+from output import CSV_QUOTING, EXPORT_PROFILES, NULL_POLICIES, agencies_table, cfr_divisions_table, court_cases, coverage_gaps_table, coverage_summary_table, csv_options, division_timeline_table, graphics_table, llm_stats_tables, load_computed_columns, omb_control_number_table, part_answers_table, pending_fr_docs, search_changes_table, text_survival_table, topic_tables, write_raw_json, write_results, write_run_manifest
+from pipeline import AttributionStore, cfr_to_fr_docs
+from plugins import load_plugins, post_collection, transform_rows
+from providers import LLM_PROVIDERS
+import random
+from report import ERRORS, EXIT_BUDGET_EXCEEDED, EXIT_CANARY_FAILED, EXIT_CONFIG, EXIT_INTERRUPTED, EXIT_PARTIAL, EXIT_SUCCESS, FAILURE_CATEGORIES, exit_with_summary, format_finish_time, timed_stage, write_errors
+import report
+import signal
+from store import DatadirLock, link_latest_run, parse_tags, run_dir, safe_path_regex
 import sys
-import time
-import toml
-from unstructured.partition.html import partition_html
-from unstructured.chunking.title import chunk_by_title
-import uuid
 
-########################################
-# Global constants for parsing the CFR #
-########################################
 
-# Since a freeze in issued regulations is likely shortly after the inauguration, a good way to have synchronized data and results
-# across multiple users is to have a fixed end date for data, likely whenever that rule freeze is put in place.
-ECFR_DATE = "2024-12-30"
-CFR_TITLES = [str(num) for num in range(1, 51)]
-# For now, we aren't using the date. Maybe when diff-ing algo
-# fr_citation_pattern = r"([0-9]+ FR [0-9]+, (Jan.|Feb.|Mar.|Apr.|May|June|July|Aug.|Sept.|Oct.|Nov.|Dec.) [0-9]{1,2}, [0-9]{4})"
-citation_regex = re.compile(r"[0-9]+ FR [0-9]+")
-non_alphabet_regex = re.compile(r"\D")
+BACKEND_EXAMPLES = '''
+examples:
+  # Analyze 40 CFR Parts 50 and 180
+  python backend.py --Part 40 50 --Part 40 180 documents/
 
-#####################################
-# Global constants for using Cohere #
-#####################################
+  # Analyze all of Title 40, one set of result files per Part, and resume it if interrupted
+  python backend.py --Title 40 --shard-by part --spill-to-disk documents/
+  python backend.py --Title 40 --shard-by part --resume documents/
 
-load_dotenv()
-api_key = os.getenv("COHERE_API_KEY")
-co = cohere.Client(api_key)
+  # Also fetch the full FR document metadata and write only the columns needed for economic analysis
+  python backend.py --Part 40 63 --enrich --profile econ-analysis documents/
 
-USING_COHERE_TRIAL_KEY = False
+  # Attribute all of 40 CFR Part 63, but only ask the LLM about the rules published since January 20th, 2017
+  python backend.py --Part 40 63 --docs-since 2017-01-20 documents/
 
-# THESE ARE MUTATED!!!
-TOKENS = 0
-CALLS = 0
-RATE_LIMIT_PAUSES = 0
+  # Analyze 40 CFR Part 63 as of GPO's 2024 annual edition, downloaded from govinfo.gov's bulk data to CFR-2024/
+  python backend.py --Part 40 63 --cfr-source bulk:CFR-2024/ documents-2024/
 
-##############################################
-# Functions and classes for LLM RAG analysis #
-##############################################
+  # Pilot new questions on a reproducible sample of 20 of the FR documents of 40 CFR Part 63
+  python backend.py --Part 40 63 --questions questions.toml --sample 20 --seed 1 --temperature 0 documents/
 
-def rate_limit_check(additional_toks):
-    '''
-    Enforces pauses for Cohere's rate limits. Not thread-safe. Call before every Cohere request. E.g.,
-    
-    texts = [item["text"] for item in batch]   
-    rate_limit_check(sum(map(lambda x : len(x), texts)))
-    docs_embs_batch = co.embed(
-        texts=texts, model="embed-english-v3.0", input_type="search_document"
-    ).embeddings        
-    '''
-    global TOKENS
-    global CALLS
-    global RATE_LIMIT_PAUSES
-
-    if USING_COHERE_TRIAL_KEY:
-        # Trial key rate limits
-        API_CALL_RATE_LIMIT = 10 # calls/min
-        TOKEN_RATE_LIMIT = 100000 # tokens/min
-    else:
-        # Production key rate limits
-        API_CALL_RATE_LIMIT = 100000 # calls/min guess? This is supposedly 2,000 calls/min for embed, but experimentally, this limit worked...
-        TOKEN_RATE_LIMIT = 2000000 # tokens/min
-
-    print("--- Rate Limit Pause internals ---")
-    print("TOKENS:", TOKENS)
-    print("CALLS:", CALLS)
-    print("RATE_LIMIT_PAUSES:", RATE_LIMIT_PAUSES)
-    print("-----------------------------------")
-
-    CALLS += 1
-    TOKENS += additional_toks
-    if TOKENS / TOKEN_RATE_LIMIT - RATE_LIMIT_PAUSES >= 1 or CALLS / API_CALL_RATE_LIMIT >= 1:
-        print("\tPause for rate-limit...")
-        time.sleep(60)
-        CALLS = 0
-        RATE_LIMIT_PAUSES += 1
-
-
-class VectorStoreIndex:
-    '''
-    Interface for creating and calling an Hnswlib vectorstore for a single document
-    '''
-    def __init__(self, raw_doc_path, index_path, outf=sys.stdout):
-        self.raw_doc_path = raw_doc_path
-        self.docs = []
-        self.docs_embs = []
-        self.retrieve_top_k = 15
-        self.rerank_top_k = 5
-        self.idx = hnswlib.Index(space="ip", dim=1024)
-        self.input_doc_tok_len = 0
-        self.input_doc_word_len = 0
-        self.outf = outf
-        
-        self.load_and_chunk()
-        if os.path.exists(index_path):
-            self.idx.load_index(index_path)
-        else:
-            self.embed()
-            self.index(index_path)
-        print(f"Indexing complete with {self.idx.get_current_count()} document chunks.", file=self.outf)
-
-
-    def load_and_chunk(self):
-        print("Loading documents...", file=self.outf)
-
-        with open(self.raw_doc_path, "r", encoding="windows-1252") as f:
-            html_content = f.read()
-        
-        t0 = time.time()
-        print("\tPartition HTML", file=self.outf)
-        elements = partition_html(text=html_content)
-        print(f"\t\t{time.time() - t0} s", file=self.outf)
-        
-        t0 = time.time()
-        print("\tChunk by title", file=self.outf)
-        chunks = chunk_by_title(elements)
-        print(f"\t\t{time.time() - t0} s", file=self.outf)
-        
-        print(f"\tChunking {self.raw_doc_path}", end="", file=self.outf)
-        t0 = time.time()
-        for chunk in chunks:
-            chunk = str(chunk)
-            self.input_doc_tok_len += len(chunk)
-            self.input_doc_word_len += len(list(filter(lambda word : not (word.isspace() or word == ""), chunk.split(" "))))
-            self.docs.append(
-                {
-                    "title": self.raw_doc_path,
-                    "text": chunk,
-                }
-            )
-        print(f"\t\t{time.time() - t0} s", file=self.outf)
-
-    
-    def embed(self):
-        print("Embedding document chunks...", file=self.outf)
-
-        batch_size = 90
-        self.docs_len = len(self.docs)
-        for i in range(0, self.docs_len, batch_size):
-            batch = self.docs[i : min(i + batch_size, self.docs_len)]
-            texts = [item["text"] for item in batch]
-            
-            rate_limit_check(sum(map(lambda x : len(x), texts)))
-            print(f"\tSending...", file=self.outf)
-            docs_embs_batch = co.embed(
-                texts=texts, model="embed-english-v3.0", input_type="search_document"
-            ).embeddings
-            self.docs_embs.extend(docs_embs_batch)
-            
-   
-    def index(self, index_path):
-        print("Indexing document chunks...", file=self.outf)
-
-        self.idx.init_index(max_elements=self.docs_len, ef_construction=512, M=64)
-        self.idx.add_items(self.docs_embs, list(range(len(self.docs_embs))))
-
-        print("Saving idx to disc...", file=self.outf)
-        self.idx.save_index(index_path)
-
-    
-    def retrieve(self, query: str):
-        # Retrieve
-        rate_limit_check(len(query))
-        query_emb = co.embed(
-            texts=[query], model="embed-english-v3.0", input_type="search_query"
-        ).embeddings
-
-        doc_ids = self.idx.knn_query(query_emb, k=self.retrieve_top_k)[0][0]
-
-        # Rerank
-        rank_fields = ["title", "text"]
-
-        docs_to_rerank = [self.docs[doc_id] for doc_id in doc_ids]
-        print("Docs to rerank:", docs_to_rerank, file=self.outf)
-
-        rate_limit_check(len(query))
-        rerank_results = co.rerank(
-            query=query,
-            documents=docs_to_rerank,
-            top_n=self.rerank_top_k,
-            model="rerank-english-v3.0",
-            rank_fields=rank_fields
-        )
-
-        doc_ids_reranked = [doc_ids[result.index] for result in rerank_results.results]
-
-        docs_retrieved = []
-        for doc_id in doc_ids_reranked:
-            docs_retrieved.append(
-                {
-                    "title": self.docs[doc_id]["title"],
-                    "text": self.docs[doc_id]["text"],
-                }
-            )
-
-        print("Docs reranked:", docs_retrieved, file=self.outf)
-
-        return docs_retrieved
-
-
-class Chatbot:
-    def __init__(self, vectorstore: VectorStoreIndex, outf=sys.stdout):
-        self.vectorstore = vectorstore
-        self.conversation_id = str(uuid.uuid4())
-        self.outf = outf
- 
-    
-    def run(self, preamble, prompt):
-        result = {}
-        print(f"\n{'-'*100}\n", file=self.outf)
-        
-        try:
-            toks_in_query = len(preamble) + len(prompt)
-
-            # Generate search queries (if any)
-            rate_limit_check(toks_in_query)
-            response = co.chat(
-                preamble=preamble,
-                message=prompt,
-                model="command-r",
-                search_queries_only=True
-            )
-
-            # If there are search queries, retrieve document chunks and respond
-            if response.search_queries:
-                print("Retrieving information...", end="", file=self.outf)
-
-                # Retrieve document chunks for each query
-                documents = []
-                for query in response.search_queries:
-                    documents.extend(self.vectorstore.retrieve(query.text))
-                result["chunks_used"] = documents
-                result["fr_doc_tok_len"] = self.vectorstore.input_doc_tok_len
-                result["fr_doc_word_len"] = self.vectorstore.input_doc_word_len
-
-                # Use document chunks to respond
-                rate_limit_check(toks_in_query)
-                response = co.chat(
-                    preamble=preamble,
-                    message=prompt,
-                    model="command-r-plus",
-                    documents=documents,
-                    conversation_id=self.conversation_id,
-                )
-            else:
-                raise Exception("No search queries identified in prompt")
-
-            # Print the chatbot response, citations, and document
-            print("\nChatbot:", response.text, file=self.outf)
-            result["answer"] = response.text
-            result["citations"] = response.citations
-
-            # Display citations and source documents
-            if response.citations:
-                print("\n\nCITATIONS:", file=self.outf)
-                for citation in response.citations:
-                    print(citation, file=self.outf)
-
-                print("\nDOCUMENTS:", file=self.outf)
-                for document in response.documents:
-                    print(document, file=self.outf)
-            result["err_msg"] = ""
-        except Exception as e:
-            result = {
-                "answer": "ERROR",
-                "err_msg": f"{e}",
-                "citations": [],
-                "chunks_used": [],
-                "fr_doc_tok_len": self.vectorstore.input_doc_tok_len,
-                "fr_doc_word_len": self.vectorstore.input_doc_word_len,
-            }
-
-        return result
-
-
-def llm_analysis(fr_doc_dataset, datadir):
-    results = {
-        "llm-answer": [], 
-        "llm-citations": [], 
-        "llm-chunks-used": [],
-        "llm-preamble": [],
-        "llm-prompt": [],
-        "llm-error": [],
-        "fr-doc-tok-len": [],
-        "fr-doc-word-len": []
-    }
-
-    preamble = '''
-
-    ## Task & Context
-    You have been given a Final Rule document which is a document published by a U.S. federal government agency that establishes a new regulation. In a Final Rule document, the agency issuing the Rule responds to any significant, relevant issues raised in public comments about the Rule during the rule-making process. For each public comment in the Final Rule, the agency will first describe the comment from the public and then offer the agency's response. You are being asked to look over all of the comments described in this Final Rule and determine if any of the public commenters raised concerns that the agency is not acting with authority from Congress by issuing this rule. You will only answer yes or no.
-    '''
-    print(fr_doc_dataset.head())
-    for _, fr_doc_data in fr_doc_dataset.iterrows():
-        print(fr_doc_data)
-        rule_dir = os.path.join(datadir, "final_rules", fr_doc_data["fr-docno"])
-        rule_html = os.path.join(rule_dir, "rule.html")
-        index_path = os.path.join(rule_dir, "index")
-        # TODO: change results.txt to a .json
-        results_txt = open(os.path.join(rule_dir, "results.txt"), "w")
-
-        agencies = " or ".join([f"the {a} ({abbrv})" for a, abbrv in zip(fr_doc_data["fr-doc-agencies"], fr_doc_data["fr-doc-agencies-shorthand"])])
-        pronoun = "their" if len(fr_doc_data["fr-doc-agencies"]) > 1 else "its"
-        
-        prompt = f'''
-        Did {agencies} receive any public comments questioning {pronoun} legal or statutory authority to issue this Final Rule?
-        '''
-        vectorstore = VectorStoreIndex(rule_html, index_path, outf=results_txt)
-        chatbot = Chatbot(vectorstore, outf=results_txt)
-        llm_results = chatbot.run(preamble, prompt)
-
-        results["llm-answer"].append(llm_results["answer"])
-        results["llm-citations"].append(llm_results["citations"])
-        results["llm-chunks-used"].append(llm_results["chunks_used"])
-        results["llm-preamble"].append(preamble)
-        results["llm-prompt"].append(prompt)
-        results["llm-error"].append(llm_results["err_msg"])
-        results["fr-doc-tok-len"].append(llm_results["fr_doc_tok_len"])
-        results["fr-doc-word-len"].append(llm_results["fr_doc_word_len"])
-        
-    return pd.concat([fr_doc_dataset, pd.DataFrame(results)], axis=1)
-    
-
-#################################
-# Functions for parsing the CFR #
-#################################
-
-def citation_in_doc(cita_in_cfr, rule):
-    fr_cita, fr_start, fr_stop = rule["citation"], rule["start_page"], rule["end_page"]
-    if fr_cita is None:
-        # This is rare but can happen, e.g. FR doc 94-27103
-        return False
-    
-    cita_in_cfr = cita_in_cfr.split(" ")
-    assert len(cita_in_cfr) == 3 and cita_in_cfr[1] == "FR"
-    
-    fr_cita = fr_cita.split(" ")
-    assert len(fr_cita) == 3 and fr_cita[1] == "FR"
-    
-    assert int(fr_cita[2]) == fr_start
-    same_edition = fr_cita[0] == cita_in_cfr[0]
-    in_page_range = fr_start <= int(cita_in_cfr[2]) and int(cita_in_cfr[2]) <= fr_stop
-    return same_edition and in_page_range
-
-
-def citations_of_part(titleno, partno, datadir):
-    '''
-    Fetch the full text of a CFR Part from the eCFR (XML format), cache it, then extract via regex any
-    citations of the Federal Register along with whatever division of the CFR to which the citation belongs.
-    Returns a dictionary {FR citation : [CFR Division]}, in which FR citation is a page citation string of  
-    the form "X FR Y, Month, Date, Year" and CFR division is a tuple of the form ("NAME", "DIV-TYPE")
-    '''
-    print("\t[*] Collecting FR citations... ", end="")
-    part_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", "part.xml")
-    try:
-        with open(part_path, "r") as f:
-            full_xml = ET.parse(f)
-    except FileNotFoundError:
-        full_xml = requests.get(f"https://www.ecfr.gov/api/versioner/v1/full/{ECFR_DATE}/title-{titleno}.xml?part={partno}")
-        full_xml.raise_for_status()
-        full_xml = full_xml.content
-        with open(part_path, "wb") as f:
-            f.write(full_xml)
-        full_xml = ET.fromstring(full_xml)
-
-    fr_cita_to_cfr_divs = {}
+  # Analyze 40 CFR Part 63 with a local Ollama server, so the FR documents never leave this machine
+  python backend.py --Part 40 63 --llm-provider ollama --models llama3.1 documents/
 
-    for cita_elem in full_xml.iter("CITA"):
-        div_to_sum = None
-        parent = cita_elem.getparent()
-        if parent.tag.startswith("DIV"):
-            divname, divty = parent.attrib["N"], parent.attrib["TYPE"]
-            div_to_sum = parent
-        elif parent.tag.startswith("EXTRACT"):
-            grandparent = parent.getparent()
-            if grandparent.tag.startswith("DIV"):
-                divname, divty = grandparent.attrib["N"], grandparent.attrib["TYPE"]
-                div_to_sum = grandparent
-            else:
-                divname, divty = next(f"{titleno} CFR {partno} {child.text}" for child in parent if child.tag == "HD1"), "EXTRACT"
-                div_to_sum = parent
-        
-        div_word_sz = 0
-        for text in div_to_sum.itertext():
-            splittextt = filter(lambda word : not (word.isspace() or word == ""), text.split(" "))
-            div_word_sz += len(list(splittextt))
+  # Load shell completion, e.g. in ~/.bashrc
+  source <(python backend.py --completion bash)
 
-        fr_citations = set(re.findall(citation_regex, cita_elem.text))
-        
-        for fr_cita in fr_citations:
-            if fr_cita not in fr_cita_to_cfr_divs:
-                fr_cita_to_cfr_divs[fr_cita] = set()
-            fr_cita_to_cfr_divs[fr_cita].add((divname, divty, div_word_sz))
-        
-    # This should just be accounted for in the sub-part granule citations
-    # TODO: when we get CFR data that's better for time differentials, we can update this and test this hypothesis.
-    # sources = full_xml.find("SOURCE")
-    # if sources is not None:
-    #     assert sources.find("HED").text == "Source:" and "Unexpected structure for the Source tag"
-    #     citations.extend(re.findall(citation_regex, sources.find("PSPACE").text))
-    print(f"{len(fr_cita_to_cfr_divs)} citations.")
-    return fr_cita_to_cfr_divs
-
-
-def fr_docs_for_part(titleno, partno, datadir):
-    '''
-    Search FederalRegister.gov for all Final Rule documents since 1994 that were marked as affecting the given CFR Part.
-    Cache the search results. FR.gov's search API returns a JSON object, returned from this function as a dictionary.
-    '''
-    print("\t[*] Searching for affecting FR documents... ", end="")
-    rule_search_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", "rules.json")
-    try:
-        with open(rule_search_path, "r") as f:
-            rule_search = json.load(f)
-    except FileNotFoundError:
-        rule_query = "https://www.federalregister.gov/api/v1/documents.json"
-        rule_query += "?per_page=1000&order=newest"
-        rule_query += f"&conditions[cfr][title]={titleno}"
-        # Some Parts have letters in them (e.g. 15 CFR 4a) and the FederalRegister.gov API lists documents affecting these parts under just
-        # the numerical Part, i.e. 15 CFR 4 for the aforementioned example.
-        rule_query += f"&conditions[cfr][part]={re.sub(non_alphabet_regex, '', partno)}"
-        rule_query += "&conditions[publication_date][gte]=1994-01-01"
-        rule_query += "&conditions[type][]=RULE"
-        rule_query += "&fields[]=abstract"
-        rule_query += "&fields[]=agencies"
-        rule_query += "&fields[]=agency_names"
-        rule_query += "&fields[]=body_html_url"
-        rule_query += "&fields[]=cfr_references"
-        rule_query += "&fields[]=citation"
-        rule_query += "&fields[]=document_number"
-        rule_query += "&fields[]=end_page"
-        rule_query += "&fields[]=pdf_url"
-        rule_query += "&fields[]=publication_date"
-        rule_query += "&fields[]=significant"
-        rule_query += "&fields[]=start_page"
-        rule_query += "&fields[]=title"
-        
-        rule_search = requests.get(rule_query)
-        rule_search.raise_for_status()
-        rule_search = rule_search.json()
-        
-        next_page_url = rule_search.get("next_page_url")
-        while next_page_url is not None:
-            next_page = requests.get(next_page_url)
-            next_page.raise_for_status()
-            next_page = next_page.json()
-            print(len(next_page["results"]))
-            rule_search["results"].extend(next_page["results"])
-            next_page_url = next_page.get("next_page_url")    
-            
-        with open(rule_search_path, "w") as f:
-            json.dump(rule_search, f)
-    
-    result_count = rule_search["count"]
-    results = rule_search.get("results", [])
-    try:
-        # Results are returned 1000 results per page for maximum 10 pages. TODO: fetch the remaining for those above 10,000
-        assert result_count == len(results) or result_count > 10000
-    except AssertionError as e:
-        print(f"result_count = {result_count}, len(results) = {len(results)} ")
-        raise e
-    print(f"{result_count} documents.")
-    
-    return results
-
-
-def fetch_fr_docs(final_rule_docs, datadir):
-    '''
-    Create the following portion of the database if not created already:
-    final-rules/
-        doc-no-X/
-            details.toml
-            index
-            results.{txt, toml, json?}
-            rule.html
-            rule.pdf 
-    '''
-    skipped = []
-    num_rules = len(final_rule_docs)
-    for i, docno in enumerate(final_rule_docs):
-        print(f"[*] Fetching FR documents... {i+1}/{num_rules}: {docno}", end="\r", flush=True)
-        fr_doc = final_rule_docs[docno][1]
-
-        # Skip existing Final Rule docs
-        document_dir = os.path.join(datadir, "final_rules", docno)
-        if os.path.exists(document_dir):
-            assert os.path.isdir(document_dir) and f"{document_dir} exists but isn't a directory."
-            continue
-
-        try:
-            # Get the PDF of the rule
-            pdf_res = requests.get(fr_doc["pdf_url"])
-            pdf_res.raise_for_status()
-            assert pdf_res.headers["Content-Type"].startswith("application/pdf")
-            
-            # Get the HTML and CFR Part of the rule
-            html_res = requests.get(fr_doc["body_html_url"])
-            html_res.raise_for_status()
-            assert html_res.headers["Content-Type"].startswith("text/html")
-
-            details = {}
-            details["title"] = fr_doc["title"]
-            details["agencies"] = fr_doc["agencies"]
-            details["agency_shorthand"] = fr_doc["agency_shorthand"]
-            details["abstract"] = fr_doc["abstract"]
-            details["body_html_url"] = fr_doc["body_html_url"]
-            details["citation"] = fr_doc["citation"]
-            details["cfr_references"] = fr_doc["cfr_references"]
-            details["document_number"] = docno
-            details["end_page"] = fr_doc["end_page"]
-            details["pdf_url"] = fr_doc["pdf_url"]
-            date = fr_doc["publication_date"].split("-")
-            details["publication-date"] = datetime.date(int(date[0]), int(date[1]), int(date[2]))
-            details["significant"] = fr_doc["significant"]
-            details["start_page"] = fr_doc["start_page"]
-        except Exception as e:
-            skipped.append((i, fr_doc, e))
-            continue
-
-        os.makedirs(document_dir, exist_ok=True)
-
-        details_toml = os.path.join(document_dir, "details.toml")
-        with open(details_toml, "w") as details_toml:
-            toml.dump(details, details_toml)
-
-        rule_pdf = os.path.join(document_dir, "rule.pdf")
-        with open(rule_pdf, "wb") as rule_pdf:
-            rule_pdf.write(pdf_res.content)
-
-        rule_html = os.path.join(document_dir, "rule.html")
-        with open(rule_html, "wb") as rule_html:
-            rule_html.write(html_res.content)
-    
-    print(f"[*] Fetching FR documents... {num_rules - len(skipped)}/{num_rules}, {len(skipped)} skipped.", flush=True)
-    return skipped
-
-
-def cfr_to_fr_docs(cfr_parts, datadir):
-    '''
-    Input: [(titleno, part)]
-    Create a database in the local filesystem with this structure:
-    cfr-{date}/
-        title-{titleno}/
-            part-{X}/
-                text.xml
-                rules.json
-            part-{Y}/
-                ...
-            ...
-            structure.json
-    final-rules/
-        doc-no-X/
-            details.toml
-            index # Added by llm_analysis
-            results.{txt, toml, json?} # Added by llm_analysis
-            rule.html
-            rule.pdf
-        doc-no-Y/
-            ...
-        ...
-    Return the FR doc data and how well the CFR inputs were "covered," i.e. how many FR citations we were able to attribute to documents
-    '''
-    # This is used to add agency abbreviations to the FR doc info. The field is useful to the LLM but can't be selected in the FederalRegister.gov 
-    # search API endpoint used in fr_docs_for_part, which gets all the other docinfo.
-    all_agency_info = requests.get("https://www.federalregister.gov/api/v1/agencies")
-    all_agency_info.raise_for_status()
-    all_agency_info = all_agency_info.json()
-
-    fr_docs_to_analyze = {}
-    cfr_part_cov = {}
-    
-    for (titleno, part) in cfr_parts:
-        partno = part["identifier"] # Can be non-integer
-        print(f"[*] {titleno} CFR Part {partno}")
-        os.makedirs(os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}"), exist_ok=True)
-        # Search the eCFR for all the citations of the Federal Register in the given CFR Part
-        fr_citas_to_cfr_divs = citations_of_part(titleno, partno, datadir)
-        # Search FederalRegister.gov for all documents marked as affecting the given CFR Part
-        fr_docs_affecting = fr_docs_for_part(titleno, partno, datadir)
-        
-        # Attempt to match each FR citation to its FR Final Rule document number
-        print("\t[*] Attributing FR citations to a FR document... ", end="")
-        fr_docs_attrib_for_part = set()
-        fr_citas_unattrib_for_part = set()
-        for fr_cita, cfr_divs in fr_citas_to_cfr_divs.items():
-            fr_doc_identified = False
-            for fr_doc in fr_docs_affecting:
-                if citation_in_doc(fr_cita, fr_doc):
-                    docno = fr_doc["document_number"]
-                    if docno not in fr_docs_to_analyze:
-                        # Add the short-hands for the issuing agencies
-                        agency_names = []
-                        agency_abbrvs = []
-                        for agency in fr_doc["agency_names"]:
-                            try:
-                                agency_abbrvs.append(next(agency_info["short_name"] for agency_info in all_agency_info if agency == agency_info["name"]))
-                                agency_names.append(agency)
-                            except Exception as e:
-                                continue
-                        fr_doc["agencies"] = agency_names
-                        fr_doc["agency_shorthand"] = agency_abbrvs
-                        # Add it to the set of FR docs to analyze {docno: (cfr-divs-affected, docinfo)}
-                        fr_docs_to_analyze[docno] = (set(), fr_doc)
-                    fr_docs_to_analyze[docno][0].update(cfr_divs)
-                    
-                    fr_docs_attrib_for_part.add(docno)
-                    fr_doc_identified = True
-
-            if not fr_doc_identified:
-                fr_citas_unattrib_for_part.add(fr_cita)
-    
-        num_citas = len(fr_citas_to_cfr_divs)
-        num_unattributed = len(fr_citas_unattrib_for_part)
-        attrib_count = num_citas - num_unattributed
-        print(f"{attrib_count}/{num_citas} citations attributed from {len(fr_docs_affecting)} available documents.")
-
-        cfr_part_cov[(titleno, partno)] = {
-            "fr-citations": list(fr_citas_to_cfr_divs.keys()),
-            "fr-docs-affecting": list(map(lambda fr_doc : fr_doc["document_number"], fr_docs_affecting)),
-            "fr-docs-attributed": list(fr_docs_attrib_for_part),
-            "fr-cita-unattributed": list(fr_citas_unattrib_for_part),
-        }
-    
-    # Fetch the FR docs to analyze
-    fr_docs_unfetched = fetch_fr_docs(fr_docs_to_analyze, datadir)
-    fr_docs_unfetched = list(map(lambda s : s[1]["document_number"], fr_docs_unfetched))
-
-    # Aggregate the FR doc results into a DataFrame
-    fr_doc_results = {
-        "fr-docno": [], 
-        "cfr-divs-referenced-in": [], 
-        "fr-doc-citation": [], 
-        "fr-doc-agencies": [], 
-        "fr-doc-agencies-shorthand": [], 
-        "fr-doc-title": [], 
-        "fr-doc-abstract": [], 
-        "fr-doc-publication-date": [], 
-        "fr-doc-cfr-parts-affected": []
-    }
-    
-    fr_docs_to_analyze = {docno: docval for docno, docval in fr_docs_to_analyze.items() if docno not in fr_docs_unfetched}
-    for docno, (cfr_divs, docinfo) in fr_docs_to_analyze.items():
-        fr_doc_results["fr-docno"].append(docno),
-        fr_doc_results["cfr-divs-referenced-in"].append(cfr_divs),
-        fr_doc_results["fr-doc-citation"].append(docinfo["citation"]),
-        fr_doc_results["fr-doc-agencies"].append(docinfo["agencies"]),
-        fr_doc_results["fr-doc-agencies-shorthand"].append(docinfo["agency_shorthand"]),
-        fr_doc_results["fr-doc-title"].append(docinfo["title"]),
-        fr_doc_results["fr-doc-abstract"].append(docinfo["abstract"]),
-        fr_doc_results["fr-doc-publication-date"].append(docinfo["publication_date"]),
-        fr_doc_results["fr-doc-cfr-parts-affected"].append(docinfo["cfr_references"]),
-    fr_doc_results = pd.DataFrame(fr_doc_results)
-
-    # Collect the description of what analysis was done per input CFR Part into a DataFrame
-    cfr_part_results = {
-        "cfr-title": [],
-        "cfr-part": [],
-        "fr-citations": [],
-        "fr-docs-affecting": [],
-        "fr-docs-attributed": [], # FR docnos
-        "fr-cita-unattributed": [], # FR citas
-        "fr-docs-unfetched": [], # FR docnos
-    }
-
-    for (titleno, partno), status in cfr_part_cov.items():
-        cfr_part_results["cfr-title"].append(titleno)
-        cfr_part_results["cfr-part"].append(partno)
-        cfr_part_results["fr-citations"].append(status["fr-citations"])
-        cfr_part_results["fr-docs-affecting"].append(status["fr-docs-affecting"])
-        cfr_part_results["fr-docs-attributed"].append(status["fr-docs-attributed"])
-        cfr_part_results["fr-cita-unattributed"].append(status["fr-cita-unattributed"])
-        cfr_part_results["fr-docs-unfetched"].append([docno for docno in status["fr-docs-attributed"] if docno in fr_docs_unfetched])
-    cfr_part_results = pd.DataFrame(cfr_part_results)
-
-    return fr_doc_results, cfr_part_results
-
-
-def extract_part_info(titleno, divty, divid, datadir):
-    '''
-    Fetch the structure of a CFR Title from the eCFR, cache it, and return a list of the component Parts.
-    All CFR Titles are divided into Parts, unlike some other subdivisions (Chapter, Subchapter, etc.).
-    '''
-    if titleno not in CFR_TITLES:
-        raise ValueError(f"Invalid CFR Title {titleno}")
-    if titleno == "35":
-        raise ValueError(f"Title 35 is fully reserved.")
-    
-    structure_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", "structure", f"title-{titleno}.json")    
-    try:
-        with open(structure_path, "r") as f:
-            structure = json.load(f)
-    except FileNotFoundError:
-        structure = requests.get(f"https://www.ecfr.gov/api/versioner/v1/structure/{ECFR_DATE}/title-{titleno}.json")
-        structure.raise_for_status()
-        structure = structure.json()
-        with open(structure_path, "w") as f:
-            json.dump(structure, f)
-
-    def flatten_structure(item):
-        flat_structure = [item]
-        for child in item.get("children", []):
-            flat_structure.extend(flatten_structure(child))
-        return flat_structure
-    
-    flat_structure = flatten_structure(structure)
-    div_structure = list(filter(lambda item : item["type"] == divty and item["identifier"] == divid, flat_structure))
-    
-    if len(div_structure) == 0:
-        raise ValueError(f"Unknown input: {titleno} CFR {divty} {divid}")
-    assert len(div_structure) == 1 and f"WEIRD: {titleno} CFR {divty} {divid} maps to multiple subdivisions of the CFR."
-    
-    flat_div_structure = flatten_structure(div_structure[0])
-    parts_for_div = filter(lambda item : item["type"] == "part" and not item["reserved"], flat_div_structure)
-    parts_with_title = list(map(lambda part : (titleno, part), parts_for_div))
-    assert len(parts_with_title) > 0 and f"{titleno} CFR {divty} {divid} exists but contains no Parts that aren't reserved."
-    
-    return parts_with_title
+A results directory written with --shard-by part has a manifest.json like:
+  {"shard-by": "part", "shards": [{"cfr-title": "40", "cfr-part": "50",
+    "fr_doc_analysis": "shards/title-40-part-50/fr_doc_analysis.csv",
+    "cfr_coverage": "shards/title-40-part-50/cfr_coverage.csv", "fr-doc-count": 57}]}
+'''
 
 
 if __name__ == "__main__":
     import argparse
-    parser = argparse.ArgumentParser("")
+    from completion import CompletionAction
+
+    class ArgumentParser(argparse.ArgumentParser):
+        # Bad arguments are a configuration error, not argparse's usual exit code 2, which means a partial run
+        def error(self, message):
+            self.print_usage(sys.stderr)
+            exit_with_summary(EXIT_CONFIG, message)
+
+    parser = ArgumentParser(description="Attribute CFR Parts to the Federal Register documents that produced them and analyze those documents with an LLM.", epilog=BACKEND_EXAMPLES, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--completion", action=CompletionAction, script="backend.py", help="Print the shell completion script of backend.py and exit.")
     parser.add_argument("datadir", help="The directory to store the results and analyzed data")
     parser.add_argument("--ALL", action="store_true", default=False, help="Analyze all Parts of all CFR Titles. This overrides all other options.")
     parser.add_argument("--Title", action="append", default=[], help="A CFR Title to analyze. This argument can be listed multiple times for multiple Titles.")
     parser.add_argument("--Part", nargs=2, metavar=("TITLE", "PART"), action="append", default=[], help="A CFR Title and Part to analyze (e.g., for 40 CFR Part 62, --Part 40 62). This argument can be listed multiple times for multiple Parts.")
+    parser.add_argument("--cfr-source", default="ecfr", metavar="ecfr|bulk:DIR", help="Where to read the text of CFR Parts that aren't cached in datadir from: the eCFR API (the default), or a directory of pre-downloaded CFR bulk XML, e.g. bulk:CFR-2024/ for GPO's annual edition from govinfo.gov. Parts are cached the same either way, so use a separate datadir for each source.")
+    parser.add_argument("--div-types", nargs="+", choices=cfr.CITATION_DIV_TYPES, default=None, help="Only extract the FR citations of CFR divisions of these eCFR types, e.g. --div-types SECTION APPENDIX to skip the citations of whole Parts and Subparts. By default, the citations of every division are extracted.")
+    parser.add_argument("--plugin", metavar="FILE", action="append", default=[], help="Run the hooks defined in this Python file at the stages of the run, e.g. to add the label of a custom classifier to each FR document. See plugins.py. This argument can be listed multiple times.")
+    parser.add_argument("--llm-provider", choices=LLM_PROVIDERS, default=os.getenv("LLM_PROVIDER", "cohere"), help="The provider of the LLM that answers the questions: Cohere, OpenAI or any OpenAI-compatible server at OPENAI_API_URL, Anthropic, or a local Ollama server at OLLAMA_URL, which keeps the FR documents on this machine. Defaults to LLM_PROVIDER in .env, or else Cohere. See providers.py.")
+    parser.add_argument("--embedding-provider", choices=LLM_PROVIDERS, default=os.getenv("EMBEDDING_PROVIDER"), help="The provider of the embeddings of the FR documents' chunks. Defaults to EMBEDDING_PROVIDER in .env, or else --llm-provider, or Cohere if it has no embeddings, like Anthropic. Only Cohere reranks the retrieved chunks.")
+    parser.add_argument("--embedding-model", default=None, help="The embedding model of --embedding-provider. Defaults to embed-english-v3.0 for Cohere, text-embedding-3-small for OpenAI, and nomic-embed-text for Ollama.")
+    parser.add_argument("--models", nargs="+", default=None, help=f"The models of --llm-provider that answer the questions, in order of preference. Defaults to {' '.join(llm.LLM_MODELS)} for Cohere, and is required for the other providers. When one fails repeatedly or is slower than --llm-max-seconds, the rest of the run fails over to the next. The model of each answer is in the llm-model column.")
+    parser.add_argument("--llm-max-seconds", type=float, default=None, help="Fail over to the next of --models after an LLM call slower than this.")
+    parser.add_argument("--transcripts", action="store_true", default=False, help="Keep the prompts and responses of every LLM call about each FR document, gzipped in its directory in datadir, for auditing. Print one with `python store.py transcript datadir DOCNO`.")
+    parser.add_argument("--reuse-retrieval", action="store_true", default=False, help="Answer each question from the excerpts of each FR document retrieved for it by an earlier run, kept in retrieval.json in its directory in datadir, rather than retrieving them again. E.g., to re-ask a question whose preamble or prompt has changed.")
+    parser.add_argument("--historical", action="store_true", default=False, help="Attribute the FR citations from before 1994, which FederalRegister.gov's search doesn't cover, to the scanned pages of the FR they cite, fetched from GovInfo. These are flagged in the fr-doc-historical column. Their text is extracted with pdftotext, or, for scans without a text layer, OCR'd with tesseract, if they're installed.")
+    parser.add_argument("--match-corrections", action="store_true", default=False, help="When some FR citations of a Part match no Final Rule, look up the corrections of the Part's Final Rules published around them in FederalRegister.gov's single-document API, and attribute the citations of a correction's pages to the document it corrects.")
+    parser.add_argument("--classify-unattributed", action="store_true", default=False, help="Look up the FR citations no Final Rule matched in the proposed rules, notices, and Presidential documents affecting each Part, and list those found in the fr-cita-non-rule column of cfr_coverage.csv rather than as unattributed.")
+    parser.add_argument("--presidential-docs", action="store_true", default=False, help="Also attribute CFR citations to Presidential documents, e.g. executive orders, affecting each Part. These are labeled in the fr-doc-type column of the results.")
+    parser.add_argument("--enrich", action="store_true", default=False, help="Fetch the full metadata of each FR document, e.g. its topics, President, and Regulations.gov docket, and include it in the results. Also settles the metadata that the searches of different Parts disagreed on (see the fr-doc-metadata-conflicts column), and writes topics.csv and part_topics.csv.")
+    parser.add_argument("--raw-json", action="store_true", default=False, help="Also write the raw FederalRegister.gov JSON of each FR document, as returned by the API, to fr_docs_raw.jsonl.")
+    parser.add_argument("--docs-since", type=parse_date, default=None, metavar="YYYY-MM-DD", help="Only analyze the FR documents published on or after this date with the LLM, e.g. 2017-01-20 for the rules of one administration. The other documents are still attributed and listed in the results, with empty LLM columns.")
+    parser.add_argument("--questions", metavar="FILE", default=None, help="Ask the FR documents the questions in this TOML file instead of whether their statutory authority was contested. Questions can depend on the answers to earlier ones, and are only asked if those were answered as required. See load_questions.")
+    parser.add_argument("--canary", metavar="FILE", default=None, help="Before the run, ask the questions of the FR documents with hand-verified answers in this TOML file (see canary.toml) and only continue if enough are answered correctly. Each check's accuracy is kept in canary_history.jsonl in datadir.")
+    parser.add_argument("--canary-min-accuracy", type=float, default=1.0, metavar="FRACTION", help=f"With --canary, exit with code {EXIT_CANARY_FAILED} if less than this fraction of the canaries are answered correctly.")
+    parser.add_argument("--canary-only", action="store_true", default=False, help="With --canary, exit after the check. No Title or Part needs to be given.")
+    parser.add_argument("--sample", type=int, default=None, metavar="N", help="Only analyze a random sample of N of the FR documents with the LLM, e.g. for a pilot of new questions. The other documents are still attributed and listed in the results, with empty LLM columns. The sample is chosen by --seed.")
+    parser.add_argument("--label", default=None, help="A name for the run, e.g. post-election-baseline, recorded in run.json and added to the name of its results directory, so it can be found months later. List the runs by label with `python store.py runs`.")
+    parser.add_argument("--tag", action="append", default=[], metavar="KEY=VALUE", help="A metadata tag of the run, e.g. --tag reviewer=jdoe, recorded in run.json. This argument can be listed multiple times.")
+    parser.add_argument("--seed", type=int, default=None, help="The seed of the run's random choices: the --sample of FR documents, the document indexes, and the LLM's sampling, as far as Cohere supports it. By default a random seed is chosen. Either way it's recorded in run.json in the results, so the run can be repeated exactly.")
+    parser.add_argument("--temperature", type=float, default=None, help="The LLM's sampling temperature, e.g. 0 for its most likely answers. By default, Cohere's default.")
+    parser.add_argument("--brief", action="store_true", default=False, help="Also have the LLM write a one-page markdown brief of each CFR Part from its FR documents, in the briefs directory of the results.")
+    parser.add_argument("--download-graphics", action="store_true", default=False, help="Also download the images in the analyzed CFR Parts and FR documents, which are listed in graphics.csv, so the content the LLM can't read can be reviewed by hand.")
+    parser.add_argument("--diff-text", action="store_true", default=False, help="Also diff the text each FR document gave the CFR sections attributed to it against their eCFR text, and say in text_survival.csv whether it survives, was superseded, or was removed.")
+    parser.add_argument("--court-cases", action="store_true", default=False, help="Also search CourtListener for court cases citing each CFR Part and each significant FR document, in court_cases.csv. Set COURTLISTENER_API_TOKEN in .env for higher rate limits.")
+    parser.add_argument("--pending-docs", action="store_true", default=False, help="Also list Rules on FederalRegister.gov's public inspection desk, i.e. not yet published, that may affect the analyzed Parts in pending_documents.csv.")
+    parser.add_argument("--refresh-searches", action="store_true", default=False, help="Re-run the cached FederalRegister.gov searches of each Part and list the FR documents that are new, removed, or changed since the cached searches in search_changes.csv.")
+    parser.add_argument("--keep-search-history", action="store_true", default=False, help="With --refresh-searches, keep the previous searches in the history directory of each Part instead of overwriting them.")
+    parser.add_argument("--check-changes", action="store_true", default=False, help=f"Check the eCFR for amendments and corrections to each Part made after {ECFR_DATE} and flag changed Parts as potentially stale in the coverage results.")
+    parser.add_argument("--refetch-stale", action="store_true", default=False, help="Like --check-changes, but also discard and re-fetch the cached XML of Parts flagged as potentially stale.")
+    parser.add_argument("--shard-by", choices=["part"], default=None, help="Split the results into one set of files per CFR Part plus a manifest.json, instead of one large table. Useful for Title-scale runs.")
+    parser.add_argument("--profile", choices=list(EXPORT_PROFILES), default=None, help="Only write the result tables and columns needed by one kind of downstream use. llm-input: the inputs and outputs of the LLM per FR document; econ-analysis: FR document sizes, dates, and agencies, and Part coverage; audit: the evidence for each LLM answer and the attribution and fetch status.")
+    parser.add_argument("--columns", type=lambda s: s.split(","), default=None, metavar="COL,...", help="Only write these columns of the result tables, plus the columns identifying each row (fr-docno, cfr-title, cfr-part). Tables with none of these columns aren't written. Overrides --profile.")
+    parser.add_argument("--exclude-columns", type=lambda s: s.split(","), default=[], metavar="COL,...", help="Don't write these columns of the result tables.")
+    parser.add_argument("--computed-columns", metavar="FILE", default=None, help="Add the columns defined in this TOML file to the result tables, computed from their other columns, e.g. the words per citation of each FR document. See load_computed_columns.")
+    parser.add_argument("--null-policy", choices=NULL_POLICIES, default="as-is", help="How the result tables write missing values, empty strings, and empty lists: as-is, as pandas writes them, i.e. an empty cell, an empty cell, and []; empty, all as an empty cell; null, all as NULL; or omit, all as an empty cell, leaving out the columns with no other values.")
+    parser.add_argument("--csv-separator", default=",", help="The separator of the cells of the result tables, e.g. ; or tab. analyze.py reads it from run.json.")
+    parser.add_argument("--csv-quoting", choices=list(CSV_QUOTING), default="minimal", help="Which cells of the result tables are quoted: those with separators, quotes, or newlines (minimal), all, all but numbers (nonnumeric), or none, escaping them with a backslash instead.")
+    parser.add_argument("--csv-encoding", default="utf-8", help="The encoding of the result tables, e.g. utf-8-sig to start them with the byte order mark Excel expects.")
+    parser.add_argument("--compress", choices=["gzip", "zstd"], default=None, help="Compress the result tables, e.g. fr_doc_analysis.csv.gz. zstd is quicker but needs the zstandard package. analyze.py reads either.")
+    parser.add_argument("--llm-budget", type=int, default=None, metavar="CALLS", help=f"Exit with code {EXIT_BUDGET_EXCEEDED} before the LLM analysis if it's estimated to take more than this many Cohere calls.")
+    parser.add_argument("--usage-stats", action="store_true", default=False, help="Record which options this run used and how long each of its stages took in usage_stats.jsonl in datadir, summarized by `python store.py stats`. Nothing is sent anywhere. Can also be turned on with DOGE_GUARD_USAGE_STATS=1 in .env.")
+    parser.add_argument("--lock-timeout", type=int, default=0, help="If another run is using datadir, wait up to this many seconds for it to finish instead of exiting immediately.")
+    parser.add_argument("--record-fixtures", metavar="DIR", default=None, help="Save every request to the eCFR, FederalRegister.gov, etc. and its response to DIR, so the run can be replayed with --replay-fixtures.")
+    parser.add_argument("--replay-fixtures", metavar="DIR", default=None, help="Answer every request to the eCFR, FederalRegister.gov, etc. from the fixtures in DIR instead of the network. Cohere requests are not replayed.")
+    parser.add_argument("--stream", action="store_true", default=False, help="Start the LLM analysis of each FR document as soon as it's fetched, while the rest are still downloading, rather than after all of them are. Can't be combined with --llm-budget, which needs every document to estimate the analysis before it starts.")
+    parser.add_argument("--spill-to-disk", action="store_true", default=False, help="Keep the FR documents attributed so far in an SQLite database in the results directory instead of in memory. Useful for Title-scale runs. Implied by --ALL.")
+    parser.add_argument("--strict", action="store_true", default=False, help="Fail a CFR Part when the eCFR or FederalRegister.gov data breaks an assumption of the analysis, e.g. a search returning fewer results than it counted. By default, the violation is reported in errors.json and the analysis carries on.")
+    parser.add_argument("--resume", action="store_true", default=False, help="Resume an interrupted run with the same inputs, skipping the Parts it already completed. Implies --spill-to-disk.")
     
     args = parser.parse_args()
+    if args.usage_stats or os.getenv("DOGE_GUARD_USAGE_STATS") == "1":
+        report.USAGE_STATS_PATH = os.path.join(args.datadir, "usage_stats.jsonl")
+        report.USAGE_FEATURES = sorted(name for name, value in vars(args).items() if name not in ["datadir", "usage_stats"] and value != parser.get_default(name))
+    report.STRICT = args.strict
+    cfr.DIV_TYPES = args.div_types
+    if args.cfr_source.startswith("bulk:"):
+        cfr.BULK_CFR_DIR = args.cfr_source[len("bulk:"):]
+        if not os.path.isdir(cfr.BULK_CFR_DIR):
+            exit_with_summary(EXIT_CONFIG, f"bad --cfr-source: {cfr.BULK_CFR_DIR} is not a directory")
+    elif args.cfr_source != "ecfr":
+        exit_with_summary(EXIT_CONFIG, f"bad --cfr-source: {args.cfr_source}")
+    if args.stream and args.llm_budget is not None:
+        exit_with_summary(EXIT_CONFIG, "--stream and --llm-budget can't be combined")
+    if args.stream and args.sample is not None:
+        exit_with_summary(EXIT_CONFIG, "--stream and --sample can't be combined")
+    try:
+        tags = parse_tags(args.tag)
+    except ValueError as e:
+        exit_with_summary(EXIT_CONFIG, f"bad --tag: {e}")
+    if args.label is not None and safe_path_regex.fullmatch(args.label) is None:
+        exit_with_summary(EXIT_CONFIG, f"bad --label: {args.label} may only have letters, digits, ., _, and -")
+    seed = args.seed if args.seed is not None else random.randrange(2**31)
+    llm.LLM_SEED = seed
+    llm.LLM_TEMPERATURE = args.temperature
+    llm.LLM_TRANSCRIPTS = args.transcripts
+    llm.LLM_REUSE_RETRIEVAL = args.reuse_retrieval
+    if args.models is None and args.llm_provider != "cohere":
+        exit_with_summary(EXIT_CONFIG, f"--models is required with --llm-provider {args.llm_provider}")
+    llm.LLM_MODELS = args.models or llm.LLM_MODELS
+    llm.LLM_CLIENT = llm.llm_client(args.llm_provider)
+    embedding_provider = args.embedding_provider or (args.llm_provider if llm.LLM_CLIENT.default_embedding_model is not None else "cohere")
+    llm.EMBEDDING_CLIENT = llm.LLM_CLIENT if embedding_provider == args.llm_provider else llm.llm_client(embedding_provider)
+    llm.EMBEDDING_MODEL = args.embedding_model or llm.EMBEDDING_CLIENT.default_embedding_model
+    llm.LLM_MAX_SECONDS = args.llm_max_seconds
+    try:
+        csv_format = csv_options(args.csv_separator, args.csv_quoting, args.csv_encoding)
+        "".encode(csv_format["encoding"])
+    except LookupError as e:
+        exit_with_summary(EXIT_CONFIG, f"bad --csv-encoding: {e}")
+    if len(csv_format["sep"]) != 1:
+        exit_with_summary(EXIT_CONFIG, f"bad --csv-separator: {args.csv_separator} isn't one character or tab")
+    computed_columns = []
+    if args.computed_columns is not None:
+        try:
+            computed_columns = load_computed_columns(args.computed_columns)
+        except (OSError, ValueError) as e:
+            exit_with_summary(EXIT_CONFIG, f"bad --computed-columns: {e}")
+    try:
+        plugins = load_plugins(args.plugin)
+    except Exception as e:
+        exit_with_summary(EXIT_CONFIG, f"bad --plugin: {type(e).__name__}: {e}")
+    if args.stream and len(plugins["pre_analysis"]) > 0:
+        exit_with_summary(EXIT_CONFIG, "--stream can't be combined with a pre_analysis plugin, since streamed documents are analyzed before it runs")
+    questions = DEFAULT_QUESTIONS
+    if args.questions is not None:
+        try:
+            questions = load_questions(args.questions)
+        except (OSError, ValueError) as e:
+            exit_with_summary(EXIT_CONFIG, f"bad --questions: {e}")
+    lock = DatadirLock(args.datadir, timeout=args.lock_timeout)
+    try:
+        lock.acquire()
+    except RuntimeError as e:
+        exit_with_summary(EXIT_CONFIG, f"{e}")
+    atexit.register(lock.release)
+    frapi.RECORD_FIXTURES_DIR = args.record_fixtures
+    frapi.REPLAY_FIXTURES_DIR = args.replay_fixtures
+
+    if args.canary is not None:
+        doc_questions = [q for q in questions if q["scope"] == "document"]
+        try:
+            canaries = load_canaries(args.canary, doc_questions)
+        except (OSError, ValueError, IndexError) as e:
+            exit_with_summary(EXIT_CONFIG, f"bad --canary: {e}")
+        with timed_stage("canary-check"):
+            accuracy, previous, failures = canary_check(canaries, args.datadir, doc_questions)
+        for failure in failures:
+            print(f"\t[!] {failure['docno']} {failure['question']}: expected {failure['expected']}, answered {failure['answer'].strip()[:80]!r}")
+        drift = "" if previous is None else f" ({accuracy - previous:+.0%} since the last check)"
+        print(f"[*] Canary accuracy: {accuracy:.0%}{drift}")
+        if accuracy < args.canary_min_accuracy:
+            exit_with_summary(EXIT_CANARY_FAILED, f"the canary accuracy of {accuracy:.0%} is below --canary-min-accuracy {args.canary_min_accuracy:.0%}", accuracy=accuracy, previous_accuracy=previous)
+        if args.canary_only:
+            exit_with_summary(EXIT_SUCCESS, accuracy=accuracy, previous_accuracy=previous)
 
     os.makedirs(os.path.join(args.datadir, f"cfr-{ECFR_DATE}", "structure"), exist_ok=True)
     outdir = f"cfr-{ECFR_DATE}"
     cfr_parts = []
+    whole_titles = []
+    # Fetch the structures of all the Titles at once, rather than one at a time as their Parts are listed below
+    titlenos = CFR_TITLES if args.ALL else args.Title + [titleno for titleno, _ in args.Part]
+    prefetch_structures([titleno for titleno in titlenos if titleno in CFR_TITLES and titleno != "35"], args.datadir)
     if args.ALL:
         outdir += "-ALL"
         for titleno in CFR_TITLES:
             if titleno != "35":
+                whole_titles.append(titleno)
                 cfr_parts.extend(extract_part_info(titleno, "title", titleno, args.datadir))
     else:
         for titleno in args.Title:
             outdir += f"-Title-{titleno}"
+            whole_titles.append(titleno)
             cfr_parts.extend(extract_part_info(titleno, "title", titleno, args.datadir))
         for titleno, partno in args.Part:
+            try:
+                partno = str(CfrPart.parse(partno))
+            except ValueError as e:
+                exit_with_summary(EXIT_CONFIG, f"bad --Part: {e}")
             outdir += f"-Title-{titleno}-Part-{partno}"
             cfr_parts.extend(extract_part_info(titleno, "part", partno, args.datadir))
     
     if len(cfr_parts) == 0:
-        print("ERROR: must specify at least one option of Title or Part!")
-        sys.exit(1)
+        exit_with_summary(EXIT_CONFIG, "must specify at least one option of Title or Part!")
+
+    with timed_stage("title-xml"):
+        for titleno in whole_titles:
+            cache_title_xml(titleno, [(t, part) for t, part in cfr_parts if t == titleno], args.datadir)
+
+    if args.label is not None:
+        outdir += f"-{args.label}"
+    outdir = run_dir(args.datadir, outdir, resume=args.resume)
+    # The status is "running" until the results are written, then "complete", so --resume never reopens a finished run
+    manifest = {"label": args.label, "tags": tags, "arguments": vars(args), "seed": seed, "temperature": args.temperature, "ecfr-date": ECFR_DATE, "status": "running"}
+    write_run_manifest(outdir, manifest)
+    store_path = os.path.join(outdir, "aggregation.sqlite")
+    if os.path.exists(store_path) and not args.resume:
+        os.remove(store_path)
+    if args.spill_to_disk or args.resume or args.ALL:
+        store = AttributionStore(store_path)
+    else:
+        store = AttributionStore()
+
+    # Treat SIGTERM like Ctrl-C, so both save the progress made so far
+    def raise_interrupt(signum, frame):
+        raise KeyboardInterrupt()
+    signal.signal(signal.SIGTERM, raise_interrupt)
 
-    fr_doc_data, cfr_cov = cfr_to_fr_docs(cfr_parts, args.datadir)
-    fr_doc_analysis = llm_analysis(fr_doc_data, args.datadir)
+    doc_questions = [question for question in questions if question["scope"] == "document"]
+    group_questions = [question for question in questions if question["scope"] == "division"]
+    streaming = None
+    if args.stream and len(doc_questions) > 0:
+        streaming = StreamingAnalysis(args.datadir, doc_questions, docs_since=args.docs_since)
+
+    try:
+        fr_doc_data, cfr_cov, fetch_status = cfr_to_fr_docs(cfr_parts, args.datadir, check_changes=args.check_changes, refetch_stale=args.refetch_stale, store=store, include_presidential=args.presidential_docs, enrich=args.enrich, refresh_searches=args.refresh_searches, keep_search_history=args.keep_search_history, on_fetched=streaming.submit if streaming is not None else None, classify_unattributed=args.classify_unattributed, match_corrections=args.match_corrections, historical=args.historical)
+        analyzed = streaming.finish() if streaming is not None else {}
+        fr_doc_data = post_collection(plugins, fr_doc_data, cfr_cov)
+        other_tables = {}
+        with timed_stage("other-tables"):
+            if args.refresh_searches:
+                other_tables["search_changes"] = search_changes_table(cfr_cov, args.datadir)
+            if args.enrich:
+                other_tables["topics"], other_tables["part_topics"] = topic_tables(fr_doc_data, cfr_cov)
+            if args.pending_docs:
+                other_tables["pending_documents"] = pending_fr_docs(fr_doc_data, cfr_cov)
+            if args.diff_text:
+                other_tables["text_survival"] = text_survival_table(cfr_cov, args.datadir)
+            if args.court_cases:
+                other_tables["court_cases"] = court_cases(fr_doc_data, cfr_cov, args.datadir)
+            other_tables["agencies"] = agencies_table(fr_doc_data, args.datadir)
+            other_tables["cfr_divisions"] = cfr_divisions_table(cfr_cov)
+            other_tables["division_timeline"] = division_timeline_table(fr_doc_data, cfr_cov, args.datadir)
+            other_tables["coverage_gaps"] = coverage_gaps_table(cfr_cov)
+            if len(other_tables["coverage_gaps"]) > 0:
+                print(f"[!] {len(other_tables['coverage_gaps'])} CFR Parts cite the FR but their searches found no FR documents, e.g. because their rules predate 1994. See coverage_gaps.csv.")
+            if len(cfr_cov["cfr-title"].unique()) > 1:
+                other_tables["coverage_summary"] = coverage_summary_table(cfr_cov, args.datadir)
+                headline = other_tables["coverage_summary"].iloc[0]
+                if headline["cfr-parts"] is not None:
+                    print(f"[*] Analyzed {headline['cfr-parts-analyzed']}/{headline['cfr-parts']} ({headline['cfr-parts-share-analyzed']:.1%}) of the CFR's Parts, ", end="")
+                else:
+                    print(f"[*] Analyzed {headline['cfr-parts-analyzed']} CFR Parts, ", end="")
+                print(f"with {headline['fr-citations-attributed']}/{headline['fr-citations']} of their FR citations attributed.")
+            other_tables["omb_control_numbers"] = omb_control_number_table(fr_doc_data, cfr_cov, args.datadir)
+            other_tables["graphics"] = graphics_table(fr_doc_data, cfr_cov, args.datadir, download=args.download_graphics)
+        # Only the documents published since --docs-since are analyzed by the LLM, but all of them are in the results and coverage
+        llm_doc_data = fr_doc_data
+        if args.docs_since is not None:
+            llm_doc_data = fr_doc_data[fr_doc_data["fr-doc-publication-date"] >= args.docs_since].reset_index(drop=True)
+            print(f"[*] {len(llm_doc_data)} of the {len(fr_doc_data)} FR documents were published since {args.docs_since}.")
+        if args.sample is not None and args.sample < len(llm_doc_data):
+            # Keep the sample in chronological order, like the rest of the results
+            llm_doc_data = llm_doc_data.sample(n=args.sample, random_state=seed).sort_index().reset_index(drop=True)
+            print(f"[*] Analyzing a sample of {len(llm_doc_data)} FR documents, chosen with seed {seed}.")
+            manifest["sampled-fr-docnos"] = list(llm_doc_data["fr-docno"])
+            write_run_manifest(outdir, manifest)
+        llm_doc_data = transform_rows(plugins, "pre_analysis", llm_doc_data)
+        workload = estimate_llm_workload(llm_doc_data, num_questions=len(doc_questions))
+        if len(group_questions) > 0:
+            groups = division_groups(llm_doc_data, cfr_cov)
+            workload["calls"] += estimate_group_workload(groups, len(group_questions))
+        print(f"[*] LLM analysis of {len(llm_doc_data)} FR documents: about {workload['chunks']} chunks, {workload['chars']} characters, and {workload['calls']} Cohere calls.")
+        print(f"\t[*] At {TOKEN_RATE_LIMIT} tokens/min and {API_CALL_RATE_LIMIT} calls/min, it will finish no sooner than {format_finish_time(projected_llm_time(workload))}.")
+        if args.llm_budget is not None and workload["calls"] > args.llm_budget:
+            profile = args.profile if args.columns is None else None
+            write_results(fr_doc_data, cfr_cov, fetch_status, outdir, shard_by=args.shard_by, other_tables=other_tables, profile=profile, columns=args.columns, exclude_columns=args.exclude_columns, compress=args.compress, computed_columns=computed_columns, null_policy=args.null_policy, csv_format=csv_format)
+            write_errors(outdir)
+            manifest["status"] = "complete"
+            write_run_manifest(outdir, manifest)
+            link_latest_run(outdir)
+            exit_with_summary(EXIT_BUDGET_EXCEEDED, f"the LLM analysis would take about {workload['calls']} Cohere calls, over the budget of {args.llm_budget}. The results without it are in {outdir}", outdir=outdir, **workload)
+        with timed_stage("llm-analysis"):
+            fr_doc_analysis = llm_analysis(llm_doc_data, args.datadir, doc_questions, analyzed=analyzed) if len(doc_questions) > 0 else llm_doc_data
+            if len(group_questions) > 0:
+                other_tables["group_analysis"] = group_analysis(llm_doc_data, groups, args.datadir, outdir, group_questions)
+        if len(llm_doc_data) < len(fr_doc_data):
+            # The earlier or unsampled documents have empty LLM columns
+            llm_columns = [col for col in fr_doc_analysis.columns if col not in fr_doc_data.columns]
+            fr_doc_analysis = fr_doc_data.merge(fr_doc_analysis[["fr-docno"] + llm_columns], on="fr-docno", how="left")
+        fr_doc_analysis = transform_rows(plugins, "post_analysis", fr_doc_analysis)
+        if len(doc_questions) > 0:
+            other_tables["part_answers"] = part_answers_table(fr_doc_analysis, cfr_cov, doc_questions)
+            other_tables["llm_stats"], other_tables["slow_documents"] = llm_stats_tables(fr_doc_analysis, doc_questions)
+        if args.brief:
+            with timed_stage("briefs"):
+                part_briefs(llm_doc_data, cfr_cov, outdir)
+    except KeyboardInterrupt:
+        print("\n[!] Interrupted. Saving progress...")
+        if store.db is None:
+            store = store.save(store_path)
+        else:
+            store.flush()
+        write_errors(outdir)
+        manifest["status"] = "interrupted"
+        write_run_manifest(outdir, manifest)
+        print(f"[!] The Parts completed so far are saved in {store_path}. To continue, re-run the same command with --resume.")
+        exit_with_summary(EXIT_INTERRUPTED, outdir=outdir, store=store_path)
     
-    outdir = os.path.join(args.datadir, "results", outdir)
-    os.makedirs(outdir, exist_ok=True)
-    with open(os.path.join(outdir, "fr_doc_analysis.csv"), "w") as outf:
-        fr_doc_analysis.to_csv(outf)
-    with open(os.path.join(outdir, "cfr_coverage.csv"), "w") as outf:
-        cfr_cov.to_csv(outf)
-    
\ No newline at end of file
+    profile = args.profile if args.columns is None else None
+    with timed_stage("write-results"):
+        write_results(fr_doc_analysis, cfr_cov, fetch_status, outdir, shard_by=args.shard_by, other_tables=other_tables, profile=profile, columns=args.columns, exclude_columns=args.exclude_columns, compress=args.compress, computed_columns=computed_columns, null_policy=args.null_policy, csv_format=csv_format)
+        if args.raw_json:
+            write_raw_json(fr_doc_analysis, args.datadir, outdir)
+    write_errors(outdir)
+    manifest["status"] = "complete"
+    write_run_manifest(outdir, manifest)
+    link_latest_run(outdir)
+    failed = any(error["category"] in FAILURE_CATEGORIES for error in ERRORS)
+    exit_with_summary(EXIT_PARTIAL if failed else EXIT_SUCCESS, outdir=outdir, **{"cfr-parts": len(cfr_cov), "fr-docs": len(fr_doc_analysis)})
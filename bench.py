@@ -0,0 +1,129 @@
+import contextlib
+import io
+import json
+import os
+import random
+import statistics
+import subprocess
+import time
+from cfr import CfrPart, citation_in_doc, citations_of_part
+from frapi import ECFR_DATE
+
+#############################################################
+# Benchmarks of the CFR parsing and FR citation attribution #
+#############################################################
+
+def timed(fn, repeat):
+    '''
+    Returns the median wall time of repeat calls of fn, in seconds.
+    '''
+    times = []
+    for _ in range(repeat):
+        t0 = time.perf_counter()
+        fn()
+        times.append(time.perf_counter() - t0)
+    return statistics.median(times)
+
+
+def bench_citations_of_part(datadir, parts, repeat):
+    '''
+    Time citations_of_part on the cached XML of each (titleno, partno) in parts. Parts that aren't cached are skipped rather
+    than fetched, so the network isn't measured.
+    '''
+    results = []
+    for titleno, partno in parts:
+        part_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", "part.xml")
+        if not os.path.exists(part_path):
+            print(f"[!] {titleno} CFR Part {partno} isn't cached in {datadir}. Skipping.")
+            continue
+        # citations_of_part prints its progress
+        with contextlib.redirect_stdout(io.StringIO()):
+            num_citas = len(citations_of_part(titleno, partno, datadir))
+            seconds = timed(lambda: citations_of_part(titleno, partno, datadir), repeat)
+        results.append({
+            "bench": "citations_of_part",
+            "case": f"{titleno} CFR Part {partno}",
+            "xml-bytes": os.path.getsize(part_path),
+            "citations": num_citas,
+            "seconds": seconds,
+        })
+    return results
+
+
+def synthetic_attribution(num_citas, num_docs, seed=0):
+    '''
+    Make num_citas FR citations and num_docs FR documents shaped like those of a Part, i.e. docinfo with "citation",
+    "start_page", and "end_page", spread over a few FR volumes so that most citations match one document.
+    '''
+    rng = random.Random(seed)
+    docs = []
+    for i in range(num_docs):
+        volume = 50 + i % 40
+        start = rng.randrange(1, 80000)
+        docs.append({"citation": f"{volume} FR {start}", "start_page": start, "end_page": start + rng.randrange(0, 60)})
+    citas = []
+    for _ in range(num_citas):
+        doc = rng.choice(docs)
+        page = rng.randrange(doc["start_page"], doc["end_page"] + 1)
+        citas.append(f"{doc['citation'].split(' ')[0]} FR {page}")
+    return citas, docs
+
+
+def bench_attribution(scales, num_docs, repeat):
+    '''
+    Time matching every citation against every FR document, as cfr_to_fr_docs does, at each number of citations in scales.
+    '''
+    results = []
+    for num_citas in scales:
+        citas, docs = synthetic_attribution(num_citas, num_docs)
+        def attribute():
+            for cita in citas:
+                for doc in docs:
+                    citation_in_doc(cita, doc)
+        results.append({
+            "bench": "attribution",
+            "case": f"{num_citas} citations x {num_docs} documents",
+            "citations": num_citas,
+            "seconds": timed(attribute, repeat),
+        })
+    return results
+
+
+if __name__ == "__main__":
+    import argparse
+    examples = '''
+examples:
+  # Benchmark parsing 40 CFR Parts 50 and 63, cached by a previous run in documents/, and attribution at the default scales
+  python bench.py --Part 40 50 --Part 40 63 documents/
+
+  # Record the timings of this commit, to compare with later ones
+  python bench.py --Part 40 63 --save bench.jsonl documents/
+'''
+    parser = argparse.ArgumentParser(description="Benchmark the CFR parsing and FR citation attribution of backend.py", epilog=examples, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("datadir", help="The directory holding the cached eCFR XML of a previous run")
+    parser.add_argument("--Part", nargs=2, metavar=("TITLE", "PART"), action="append", default=[], help="A cached CFR Title and Part to parse. This argument can be listed multiple times.")
+    parser.add_argument("--scales", nargs="+", type=int, default=[1000, 10000, 100000], help="The numbers of citations to attribute.")
+    parser.add_argument("--docs", type=int, default=200, help="The number of FR documents to attribute the citations to.")
+    parser.add_argument("--repeat", type=int, default=3, help="Time each case this many times and report the median.")
+    parser.add_argument("--save", metavar="FILE", default=None, help="Append the results, labeled with the current git commit, to this JSON lines file.")
+    args = parser.parse_args()
+
+    try:
+        parts = [(titleno, CfrPart.parse(partno)) for titleno, partno in args.Part]
+    except ValueError as e:
+        parser.error(f"bad --Part: {e}")
+    results = bench_citations_of_part(args.datadir, parts, args.repeat)
+    results += bench_attribution(args.scales, args.docs, args.repeat)
+
+    width = max(len(result["case"]) for result in results)
+    for result in results:
+        print(f"{result['bench']:<18} {result['case']:<{width}}  {result['seconds']:.3f} s")
+
+    if args.save is not None:
+        try:
+            commit = subprocess.run(["git", "rev-parse", "--short", "HEAD"], capture_output=True, text=True).stdout.strip()
+        except OSError:
+            commit = None
+        with open(args.save, "a") as f:
+            for result in results:
+                f.write(json.dumps({"commit": commit, "date": time.strftime("%Y-%m-%d"), **result}) + "\n")
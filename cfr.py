@@ -0,0 +1,552 @@
+import concurrent.futures
+from frapi import CFR_TITLES, ECFR_API_URL, ECFR_DATE, ECFR_SNAPSHOT_DATE, http_get, parse_date
+import html
+import io
+import json
+import lxml.etree as ET
+import os
+import re
+from report import check_assumption, report_error
+from store import write_atomic
+from typing import NamedTuple
+import unicodedata
+
+########################################
+# Global constants for parsing the CFR #
+########################################
+
+# For now, we aren't using the date. Maybe when diff-ing algo
+# fr_citation_pattern = r"([0-9]+ FR [0-9]+, (Jan.|Feb.|Mar.|Apr.|May|June|July|Aug.|Sept.|Oct.|Nov.|Dec.) [0-9]{1,2}, [0-9]{4})"
+citation_regex = re.compile(r"[0-9]+ FR [0-9]+")
+# A Part's number, letter suffix, and the number after a hyphen of e.g. 41 CFR Part 60-1
+part_regex = re.compile(r"([0-9]+)([A-Za-z]*)(?:-([0-9A-Za-z]+))?")
+# The levels of the CFR's hierarchy by the eCFR TYPE of their divisions, with the column of each in the results
+DIVISION_LEVELS = {
+    "CHAPTER": "cfr-chapter",
+    "SUBCHAP": "cfr-subchapter",
+    "PART": "cfr-part",
+    "SUBPART": "cfr-subpart",
+    "SUBJGRP": "cfr-subject-group",
+    "SECTION": "cfr-section",
+    "APPENDIX": "cfr-appendix",
+}
+# The TYPEs of the divisions whose CITAs are extracted. EXTRACT is an extract outside any division, e.g. a Part's table.
+CITATION_DIV_TYPES = ["PART", "SUBPART", "SUBJGRP", "SECTION", "APPENDIX", "EXTRACT"]
+# THIS IS MUTATED!!! If set, only the CITAs of divisions of these TYPEs are extracted, e.g. ["SECTION"] for section-level
+# granularity. See citations_of_part.
+DIV_TYPES = None
+
+
+#################################
+# Functions for parsing the CFR #
+#################################
+
+class CfrPart(NamedTuple):
+    '''
+    A CFR Part identifier, made of a number, an optional letter suffix, and an optional extension after a hyphen, e.g. 40
+    CFR Part 50, 15 CFR Part 4a, or 41 CFR Part 60-1, which are common in Title 41. Use CfrPart.parse to validate and normalize identifiers from the eCFR or the command line. str() gives the canonical
+    identifier used in eCFR URLs, cache paths, and outputs.
+    '''
+    number: int
+    suffix: str = ""
+    extension: str = ""
+
+    @classmethod
+    def parse(cls, identifier):
+        match = re.fullmatch(part_regex, str(identifier).strip())
+        if match is None:
+            raise ValueError(f"Invalid CFR Part {identifier}")
+        return cls(int(match[1]), match[2], match[3] or "")
+
+
+    def __str__(self):
+        return f"{self.number}{self.suffix}" + (f"-{self.extension}" if self.extension else "")
+
+
+def normalize_text(text):
+    '''
+    Normalize text extracted from the CFR, e.g. a CITA or a division name, so that the same text always makes the same key:
+    entities left escaped in the XML, e.g. "&amp;#167;", are unescaped, the unicode is NFC-normalized, and runs of
+    whitespace, including non-breaking spaces, are collapsed to one space. None is normalized to "".
+    '''
+    if text is None:
+        return ""
+    return " ".join(unicodedata.normalize("NFC", html.unescape(text)).split())
+
+
+def citation_match(cita_in_cfr, rule):
+    '''
+    How an FR citation from the CFR matches an FR document: "start-page" if it cites the page the document starts on,
+    "mid-range" if it cites a later page of the document, "other-range" if it cites one of the other page ranges of the
+    document in rule["page_ranges"], e.g. of a correction of it (see fr_doc_page_ranges), or None if it doesn't cite the
+    document.
+    '''
+    fr_cita, fr_start, fr_stop = rule["citation"], rule["start_page"], rule["end_page"]
+    if fr_cita is None:
+        # This is rare but can happen, e.g. FR doc 94-27103
+        return None
+    cita_in_cfr, fr_cita = normalize_text(cita_in_cfr), normalize_text(fr_cita)
+    
+    docno = rule.get("document_number")
+    cita_in_cfr = cita_in_cfr.split(" ")
+    if not check_assumption(len(cita_in_cfr) == 3 and cita_in_cfr[1] == "FR", f"Malformed FR citation in the CFR: {' '.join(cita_in_cfr)}"):
+        return None
+    
+    fr_cita = fr_cita.split(" ")
+    if not check_assumption(len(fr_cita) == 3 and fr_cita[1] == "FR", f"Malformed FR citation of {docno}: {' '.join(fr_cita)}", docno=docno):
+        return None
+    
+    # Otherwise, the page range of the API is trusted over the citation
+    check_assumption(int(fr_cita[2]) == fr_start, f"The citation {' '.join(fr_cita)} of {docno} doesn't start on its start_page {fr_start}", docno=docno)
+    page = int(cita_in_cfr[2])
+    same_edition = fr_cita[0] == cita_in_cfr[0]
+    in_page_range = fr_start <= page and page <= fr_stop
+    if same_edition and in_page_range:
+        return "start-page" if page == fr_start else "mid-range"
+    for volume, start, stop in rule.get("page_ranges", []):
+        if volume == cita_in_cfr[0] and start <= page and page <= stop:
+            return "other-range"
+    return None
+
+
+def citation_in_doc(cita_in_cfr, rule):
+    return citation_match(cita_in_cfr, rule) is not None
+
+
+reserved_regex = r"\[\s*(Reserved|Removed(\s+and\s+Reserved)?)\s*\]"
+vacated_regex = r"\b(vacated|stayed indefinitely)\b"
+suspended_regex = r"\b(suspended|stayed)\b"
+# A vacatur or suspension of only some of a division's text, e.g. "paragraph (b) of this section was vacated" or
+# "paragraph (b)(2) of this section is stayed"
+partial_vacatur_regex = r"\b(paragraphs?|tables?|entr(y|ies)|footnotes?)\b.*\b(vacated|stayed indefinitely)\b"
+partial_suspension_regex = r"\b(paragraphs?|tables?|entr(y|ies)|footnotes?)\b.*\b(suspended|stayed)\b"
+delayed_regex = r"\b(effective date|compliance date)\b.*\b(delayed|postponed)\b"
+# The eCFR's notes on a division, in which it records editorial matters, suspensions, and amendments not yet in effect
+ECFR_NOTE_TAGS = ("EDNOTE", "NOTE", "EFFDNOT")
+
+
+def division_notes(div):
+    return [normalize_text("".join(note.itertext())) for note in div if note.tag in ECFR_NOTE_TAGS]
+
+
+def division_status(div):
+    '''
+    Whether a CFR division is dead text that still carries its CITA: "reserved" if its heading marks it [Reserved] or
+    [Removed], "vacated" if its heading or a note says it was vacated or stayed by a court, "suspended" if a note says it
+    was suspended or stayed, e.g. pending reconsideration, else "". A note vacating or suspending only part of the division,
+    e.g. one of its paragraphs, doesn't make it dead. See operative_status.
+    '''
+    head = normalize_text("".join(div.find("HEAD").itertext())) if div.find("HEAD") is not None else ""
+    if re.search(reserved_regex, head, re.IGNORECASE):
+        return "reserved"
+    notes = [head] + division_notes(div)
+    if any(re.search(vacated_regex, note, re.IGNORECASE) and not re.search(partial_vacatur_regex, note, re.IGNORECASE) for note in notes):
+        return "vacated"
+    if any(re.search(suspended_regex, note, re.IGNORECASE) and not re.search(partial_suspension_regex, note, re.IGNORECASE) for note in notes):
+        return "suspended"
+    return ""
+
+
+def operative_status(div):
+    '''
+    Whether the text of a CFR division is operative at the snapshot, from its heading and notes: its division_status if
+    it's dead, "partially-vacated" if a note vacates part of it, "partially-suspended" if a note suspends or stays part of
+    it, "not-yet-effective" if an effective-date note says its effective date was delayed, "amendment-pending" if an
+    effective-date note records an amendment of it that isn't in effect yet, and "operative" otherwise.
+    '''
+    status = division_status(div)
+    if status != "":
+        return status
+    notes = division_notes(div)
+    if any(re.search(partial_vacatur_regex, note, re.IGNORECASE) for note in notes):
+        return "partially-vacated"
+    if any(re.search(partial_suspension_regex, note, re.IGNORECASE) for note in notes):
+        return "partially-suspended"
+    effective_date_notes = [normalize_text("".join(note.itertext())) for note in div if note.tag == "EFFDNOT"]
+    if any(re.search(delayed_regex, note, re.IGNORECASE) for note in effective_date_notes):
+        return "not-yet-effective"
+    if len(effective_date_notes) > 0:
+        return "amendment-pending"
+    return "operative"
+
+
+def division_path(elem):
+    '''
+    The hierarchy of the CFR divisions an element is in, itself included, by the columns of DIVISION_LEVELS, e.g.
+    {"cfr-part": "63", "cfr-subpart": "A", "cfr-section": "63.5"}. The cached XML of a Part starts at the Part, so its
+    Chapter and Subchapter come from the Title's structure instead. See extract_part_info.
+    '''
+    path = {}
+    while elem is not None:
+        if elem.tag.startswith("DIV") and elem.attrib.get("TYPE") in DIVISION_LEVELS:
+            path[DIVISION_LEVELS[elem.attrib["TYPE"]]] = normalize_text(elem.attrib.get("N"))
+        elem = elem.getparent()
+    return {level: path[level] for level in DIVISION_LEVELS.values() if level in path}
+
+
+def citations_of_part(titleno, partno, datadir, div_paths=None):
+    '''
+    Fetch the full text of a CFR Part from the eCFR (XML format), or read it from BULK_CFR_DIR, cache it, then extract via regex any
+    citations of the Federal Register along with whatever division of the CFR to which the citation belongs.
+    Returns a dictionary {FR citation : {CFR Division : count}}, in which FR citation is a page citation string of  
+    the form "X FR Y, Month, Date, Year", CFR division is a tuple of the form ("NAME", "DIV-TYPE", word size, status),
+    in which status is "reserved", "vacated", or "suspended" for dead divisions (see division_status) and "" otherwise, and count is
+    the number of times the citation appears in the division, e.g. 2 for an amendment and a correction on the same page.
+    Only the CITAs of divisions of DIV_TYPES are extracted, if it's set. If div_paths is a dictionary, the hierarchy of each division citing the FR is added to it by name,
+    with its cfr-division-operative-status. See division_path and operative_status.
+    '''
+    print("\t[*] Collecting FR citations... ", end="")
+    part_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", "part.xml")
+    try:
+        with open(part_path, "r") as f:
+            full_xml = ET.parse(f)
+    except FileNotFoundError:
+        if BULK_CFR_DIR is not None:
+            if len(cache_bulk_parts(titleno, [partno], datadir)) > 0:
+                raise FileNotFoundError(f"{titleno} CFR Part {partno} isn't in the bulk data in {BULK_CFR_DIR}")
+            return citations_of_part(titleno, partno, datadir, div_paths)
+        full_xml = http_get(f"{ECFR_API_URL}/versioner/v1/full/{ECFR_DATE}/title-{titleno}.xml?part={partno}")
+        full_xml.raise_for_status()
+        full_xml = full_xml.content
+        write_atomic(part_path, full_xml)
+        full_xml = ET.fromstring(full_xml)
+
+    fr_cita_to_cfr_divs = {}
+    # The size and status of each division are only computed once, when its first CITA is found. Large Parts have many
+    # CITAs per division, e.g. in appendices, and re-walking the text of the division for each one dominated parse time.
+    div_info = {}
+
+    for cita_elem in full_xml.iter("CITA"):
+        div_to_sum = None
+        parent = cita_elem.getparent()
+        if parent.tag.startswith("DIV"):
+            divname, divty = normalize_text(parent.attrib["N"]), parent.attrib["TYPE"]
+            div_to_sum = parent
+        elif parent.tag.startswith("EXTRACT"):
+            grandparent = parent.getparent()
+            if grandparent.tag.startswith("DIV"):
+                divname, divty = normalize_text(grandparent.attrib["N"]), grandparent.attrib["TYPE"]
+                div_to_sum = grandparent
+            else:
+                divname, divty = next(f"{titleno} CFR {partno} {normalize_text(child.text)}" for child in parent if child.tag == "HD1"), "EXTRACT"
+                div_to_sum = parent
+        if div_to_sum is None:
+            report_error("parse-warning", f"CITA in unexpected <{parent.tag}> element", cfr_title=titleno, cfr_part=str(partno), cita=normalize_text(cita_elem.text))
+            continue
+        if DIV_TYPES is not None and divty not in DIV_TYPES:
+            continue
+        
+        if div_to_sum not in div_info:
+            div_word_sz = 0
+            for text in div_to_sum.itertext():
+                splittextt = filter(lambda word : not (word.isspace() or word == ""), text.split(" "))
+                div_word_sz += len(list(splittextt))
+            div_info[div_to_sum] = (div_word_sz, division_status(div_to_sum))
+            if div_paths is not None:
+                div_paths[divname] = {**division_path(div_to_sum), "cfr-division-operative-status": operative_status(div_to_sum)}
+        div_word_sz, div_status = div_info[div_to_sum]
+
+        fr_citations = re.findall(citation_regex, normalize_text(cita_elem.text))
+        
+        for fr_cita in fr_citations:
+            if fr_cita not in fr_cita_to_cfr_divs:
+                fr_cita_to_cfr_divs[fr_cita] = {}
+            div = (divname, divty, div_word_sz, div_status)
+            fr_cita_to_cfr_divs[fr_cita][div] = fr_cita_to_cfr_divs[fr_cita].get(div, 0) + 1
+        
+    # This should just be accounted for in the sub-part granule citations
+    # TODO: when we get CFR data that's better for time differentials, we can update this and test this hypothesis.
+    # sources = full_xml.find("SOURCE")
+    # if sources is not None:
+    #     assert sources.find("HED").text == "Source:" and "Unexpected structure for the Source tag"
+    #     citations.extend(re.findall(citation_regex, sources.find("PSPACE").text))
+    print(f"{len(fr_cita_to_cfr_divs)} citations.")
+    return fr_cita_to_cfr_divs
+
+
+def cache_title_xml(titleno, cfr_parts, datadir):
+    '''
+    Fetch the full text of a whole CFR Title from the eCFR in one request and split it into the per-Part XML cache used by
+    citations_of_part. This is much quicker than fetching each Part of a large Title individually. The Title is only fetched
+    if some of the given Parts aren't cached yet.
+    '''
+    part_path = lambda partno: os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", "part.xml")
+    partnos = set()
+    for _, part in cfr_parts:
+        try:
+            partnos.add(str(CfrPart.parse(part["identifier"])))
+        except ValueError:
+            # Reported when the Part itself is processed. See cfr_to_fr_docs.
+            continue
+    uncached = [partno for partno in partnos if not os.path.exists(part_path(partno))]
+    if len(uncached) == 0:
+        return
+    if BULK_CFR_DIR is not None:
+        print(f"[*] Reading Title {titleno} from the bulk data for {len(uncached)} uncached Parts... ", end="", flush=True)
+        missing = cache_bulk_parts(titleno, uncached, datadir)
+        print(f"{len(uncached) - len(missing)} Parts cached.")
+        return
+
+    print(f"[*] Fetching the full text of Title {titleno} for {len(uncached)} uncached Parts... ", end="", flush=True)
+    full_xml = http_get(f"{ECFR_API_URL}/versioner/v1/full/{ECFR_DATE}/title-{titleno}.xml")
+    full_xml.raise_for_status()
+
+    # Parts are DIV5 elements. Stream through them so the parsed Title is never held in memory all at once.
+    num_cached = 0
+    for _, part_elem in ET.iterparse(io.BytesIO(full_xml.content), events=("end",), tag="DIV5"):
+        if part_elem.attrib.get("TYPE") == "PART":
+            try:
+                partno = str(CfrPart.parse(part_elem.attrib["N"]))
+            except ValueError:
+                partno = None
+            if partno in uncached:
+                os.makedirs(os.path.dirname(part_path(partno)), exist_ok=True)
+                # Atomically, since an existing part.xml is taken as cached, e.g. when resuming an interrupted split
+                write_atomic(part_path(partno), ET.tostring(part_elem))
+                num_cached += 1
+        part_elem.clear()
+        while part_elem.getprevious() is not None:
+            del part_elem.getparent()[0]
+    print(f"{num_cached} Parts cached.")
+
+
+# THIS IS MUTATED!!! If set, Parts that aren't cached are read from this directory of CFR bulk-data XML instead of fetched
+# from the eCFR API. See cache_bulk_parts.
+BULK_CFR_DIR = None
+# The division elements of GPO's annual edition of the CFR, with the eCFR element and TYPE each is read as
+BULK_DIVISIONS = {
+    "PART": ("DIV5", "PART"),
+    "SUBPART": ("DIV6", "SUBPART"),
+    "SUBJGRP": ("DIV7", "SUBJGRP"),
+    "SECTION": ("DIV8", "SECTION"),
+    "APPENDIX": ("DIV9", "APPENDIX"),
+}
+
+
+def bulk_title_files(bulk_dir, titleno):
+    '''
+    The XML files of a CFR Title in a directory of bulk data, e.g. GPO's CFR-2024-title40-vol1.xml, ... or the eCFR's
+    ECFR-title40.xml, in any subdirectory, e.g. title-40/.
+    '''
+    files = []
+    for root, _, fnames in os.walk(bulk_dir):
+        for fname in fnames:
+            path = os.path.join(root, fname)
+            if fname.lower().endswith(".xml") and re.search(rf"title-?{titleno}(?![0-9])", os.path.relpath(path, bulk_dir), re.IGNORECASE):
+                files.append(path)
+    return sorted(files)
+
+
+def bulk_part_to_ecfr(part_elem):
+    '''
+    Rewrite a <PART> of GPO's annual edition of the CFR in place into the eCFR's schema read by citations_of_part: each
+    division becomes a DIV element with a TYPE, an N, and a HEAD, e.g. <SECTION><SECTNO>§ 50.1</SECTNO>... becomes
+    <DIV8 TYPE="SECTION" N="50.1"><HEAD>§ 50.1 ...</HEAD>.... Returns the Part's identifier, or None if it has none.
+    '''
+    for elem in list(part_elem.iter(*BULK_DIVISIONS)):
+        tag, divty = BULK_DIVISIONS[elem.tag]
+        if elem.tag == "SECTION":
+            sectno = elem.find("SECTNO")
+            subject = elem.find("SUBJECT")
+            name = "".join(sectno.itertext()).replace("§", "").strip() if sectno is not None else ""
+            head = " ".join("".join(e.itertext()).strip() for e in (sectno, subject) if e is not None)
+        else:
+            hd = elem.find("HD")
+            head = "".join(hd.itertext()).strip() if hd is not None else ""
+            name = re.split(r"\s*[—-]\s*", head, maxsplit=1)[0]
+            match = re.match(r"(?:PART|Subpart)\s+(\S+)", name, re.IGNORECASE)
+            if match is not None:
+                name = match[1]
+            elif elem.tag == "PART" and elem.find("EAR") is not None:
+                name = "".join(elem.find("EAR").itertext()).replace("Pt.", "").strip()
+        elem.tag = tag
+        elem.attrib["TYPE"] = divty
+        elem.attrib["N"] = name
+        head_elem = ET.Element("HEAD")
+        head_elem.text = head
+        elem.insert(0, head_elem)
+    try:
+        return str(CfrPart.parse(part_elem.attrib["N"]))
+    except ValueError:
+        return None
+
+
+def cache_bulk_parts(titleno, partnos, datadir):
+    '''
+    Read the given Parts of a CFR Title from the bulk data in BULK_CFR_DIR into the per-Part XML cache used by
+    citations_of_part, like cache_title_xml does from the eCFR API. Both GPO's annual edition, split into volumes, and the
+    eCFR's bulk data are read. Returns the Parts that weren't found.
+    '''
+    part_path = lambda partno: os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", "part.xml")
+    uncached = {partno for partno in map(str, partnos) if not os.path.exists(part_path(partno))}
+    for path in bulk_title_files(BULK_CFR_DIR, titleno):
+        if len(uncached) == 0:
+            break
+        for _, part_elem in ET.iterparse(path, events=("end",), tag=("PART", "DIV5")):
+            if part_elem.tag == "PART":
+                partno = bulk_part_to_ecfr(part_elem)
+            elif part_elem.attrib.get("TYPE") == "PART":
+                try:
+                    partno = str(CfrPart.parse(part_elem.attrib["N"]))
+                except ValueError:
+                    partno = None
+            else:
+                continue
+            if partno in uncached:
+                os.makedirs(os.path.dirname(part_path(partno)), exist_ok=True)
+                write_atomic(part_path(partno), ET.tostring(part_elem))
+                uncached.remove(partno)
+            part_elem.clear()
+            while part_elem.getprevious() is not None:
+                del part_elem.getparent()[0]
+    return uncached
+
+
+def corrections_of_title(titleno):
+    '''
+    Fetch the eCFR's editorial corrections for a CFR Title. These are not cached, since the whole point is to learn about
+    corrections made after our data was fetched. Returns the list of correction objects from the eCFR admin API.
+    '''
+    corrections = http_get(f"{ECFR_API_URL}/admin/v1/corrections/title/{titleno}.json")
+    corrections.raise_for_status()
+    return corrections.json().get("ecfr_corrections", [])
+
+
+def recent_changes_of_part(titleno, partno, title_corrections):
+    '''
+    Detect whether a CFR Part has changed since ECFR_DATE, either because the eCFR published a new version of the Part
+    (i.e. it was amended after our snapshot) or because an error present in our snapshot was later corrected.
+    Returns a dictionary with the dates of the amendments and the FR citations of the corrections found.
+    '''
+    print("\t[*] Checking for changes since the snapshot date... ", end="")
+    versions = http_get(f"{ECFR_API_URL}/versioner/v1/versions/title-{titleno}.json?part={partno}&issue_date[gte]={ECFR_DATE}")
+    versions.raise_for_status()
+    versions = versions.json().get("content_versions", [])
+    amendment_dates = sorted(set(v["issue_date"] for v in versions if parse_date(v["issue_date"]) > ECFR_SNAPSHOT_DATE))
+
+    corrections = []
+    for correction in title_corrections:
+        # Only corrections of errors that were live on the snapshot date matter; the others are already reflected in, or
+        # don't yet apply to, our data.
+        if not (parse_date(correction["error_occurred"]) <= ECFR_SNAPSHOT_DATE < parse_date(correction["error_corrected"])):
+            continue
+        if any(ref["hierarchy"].get("part") == str(partno) for ref in correction["cfr_references"]):
+            corrections.append(correction.get("fr_citation"))
+
+    print(f"{len(amendment_dates)} amendments, {len(corrections)} corrections.")
+    return {
+        "ecfr-amendments-since-snapshot": amendment_dates,
+        "ecfr-corrections-since-snapshot": corrections,
+        "potentially-stale": len(amendment_dates) > 0 or len(corrections) > 0,
+    }
+
+
+def ecfr_agencies_by_division(datadir):
+    '''
+    Fetch the eCFR's list of agencies and the divisions of the CFR each administers, cache it, and return a dictionary
+    {(titleno, "chapter" or "part", identifier): [agency name]}. Sub-agencies are listed under their own names.
+    '''
+    agencies_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", "ecfr_agencies.json")
+    try:
+        with open(agencies_path, "r") as f:
+            agencies = json.load(f)
+    except (FileNotFoundError, ValueError):
+        # ValueError, if a copy written before it was written atomically was truncated
+        agencies = http_get(f"{ECFR_API_URL}/admin/v1/agencies.json")
+        agencies.raise_for_status()
+        agencies = agencies.json()
+        write_atomic(agencies_path, json.dumps(agencies))
+
+    agencies_by_div = {}
+    def add_agency(agency):
+        for ref in agency.get("cfr_references", []):
+            for divty in ["part", "chapter"]:
+                if divty in ref:
+                    agencies_by_div.setdefault((str(ref["title"]), divty, str(ref[divty])), []).append(agency["name"])
+                    break
+        for child in agency.get("children", []):
+            add_agency(child)
+
+    for agency in agencies.get("agencies", []):
+        add_agency(agency)
+    return agencies_by_div
+
+
+# The most structure requests made to the eCFR at once by prefetch_structures
+ECFR_MAX_CONCURRENT_REQUESTS = 4
+
+
+def title_structure(titleno, datadir):
+    '''
+    Fetch the structure of a CFR Title on ECFR_DATE from the eCFR and cache it in the structure directory of the snapshot.
+    '''
+    structure_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", "structure", f"title-{titleno}.json")
+    try:
+        with open(structure_path, "r") as f:
+            return json.load(f)
+    except FileNotFoundError:
+        structure = http_get(f"{ECFR_API_URL}/versioner/v1/structure/{ECFR_DATE}/title-{titleno}.json")
+        structure.raise_for_status()
+        structure = structure.json()
+        write_atomic(structure_path, json.dumps(structure))
+        return structure
+
+
+def prefetch_structures(titlenos, datadir):
+    '''
+    Fetch and cache the structures of several CFR Titles concurrently, at most ECFR_MAX_CONCURRENT_REQUESTS at a time, so runs
+    of many Titles don't wait on each Title's structure in turn. Titles that fail are left for extract_part_info to retry.
+    '''
+    uncached = sorted({titleno for titleno in titlenos if not os.path.exists(os.path.join(datadir, f"cfr-{ECFR_DATE}", "structure", f"title-{titleno}.json"))})
+    if len(uncached) < 2:
+        return
+    print(f"[*] Fetching the structure of {len(uncached)} CFR Titles... ", end="", flush=True)
+    with concurrent.futures.ThreadPoolExecutor(max_workers=ECFR_MAX_CONCURRENT_REQUESTS) as pool:
+        futures = {pool.submit(title_structure, titleno, datadir): titleno for titleno in uncached}
+        failed = []
+        for future in concurrent.futures.as_completed(futures):
+            if future.exception() is not None:
+                failed.append(futures[future])
+    print(f"{len(uncached) - len(failed)} fetched" + (f", Titles {', '.join(sorted(failed))} failed." if len(failed) > 0 else "."))
+
+
+def extract_part_info(titleno, divty, divid, datadir):
+    '''
+    Fetch the structure of a CFR Title from the eCFR, cache it, and return a list of the component Parts.
+    All CFR Titles are divided into Parts, unlike some other subdivisions (Chapter, Subchapter, etc.).
+    '''
+    if titleno not in CFR_TITLES:
+        raise ValueError(f"Invalid CFR Title {titleno}")
+    if titleno == "35":
+        raise ValueError(f"Title 35 is fully reserved.")
+    
+    structure = title_structure(titleno, datadir)
+
+    # Also label every item with the Chapter it belongs to, which determines the agency that administers it, and its Subchapter
+    def flatten_structure(item, chapter=None, subchapter=None):
+        if item.get("identifier") is not None:
+            item["identifier"] = normalize_text(item["identifier"])
+        if item["type"] == "chapter":
+            chapter, subchapter = item["identifier"], None
+        elif item["type"] == "subchapter":
+            subchapter = item["identifier"]
+        item["chapter"] = chapter
+        item["subchapter"] = subchapter
+        flat_structure = [item]
+        for child in item.get("children", []):
+            flat_structure.extend(flatten_structure(child, chapter, subchapter))
+        return flat_structure
+    
+    flat_structure = flatten_structure(structure)
+    div_structure = list(filter(lambda item : item["type"] == divty and item["identifier"] == divid, flat_structure))
+    
+    if len(div_structure) == 0:
+        raise ValueError(f"Unknown input: {titleno} CFR {divty} {divid}")
+    assert len(div_structure) == 1 and f"WEIRD: {titleno} CFR {divty} {divid} maps to multiple subdivisions of the CFR."
+    
+    flat_div_structure = flatten_structure(div_structure[0], div_structure[0]["chapter"], div_structure[0]["subchapter"])
+    parts_for_div = filter(lambda item : item["type"] == "part" and not item["reserved"], flat_div_structure)
+    parts_with_title = list(map(lambda part : (titleno, part), parts_for_div))
+    assert len(parts_with_title) > 0 and f"{titleno} CFR {divty} {divid} exists but contains no Parts that aren't reserved."
+    
+    return parts_with_title
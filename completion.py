@@ -0,0 +1,107 @@
+import argparse
+
+################################################
+# Shell completion of the command-line scripts #
+################################################
+
+def parser_options(parser):
+    '''
+    Returns the option strings of an argparse parser and its subcommands as {name: subparser}.
+    '''
+    options = []
+    subcommands = {}
+    for action in parser._actions:
+        if isinstance(action, argparse._SubParsersAction):
+            subcommands.update(action.choices)
+        else:
+            options.extend(action.option_strings)
+    return options, subcommands
+
+
+def bash_completion(parser, script):
+    '''
+    Bash (and zsh, through bashcompinit) completion of `python script ...`. Every script's completion shares the _doge_guard
+    dispatcher, so the completions of several scripts can be loaded at once.
+    '''
+    fn = "_doge_guard_" + script.replace(".", "_").replace("-", "_")
+    options, subcommands = parser_options(parser)
+    lines = [f"{fn}() {{", '    local cur="${COMP_WORDS[COMP_CWORD]}"']
+    if len(subcommands) > 0:
+        lines.append("    if [[ $COMP_CWORD -eq 2 ]]; then")
+        lines.append(f'        COMPREPLY=($(compgen -W "{" ".join(subcommands)}" -- "$cur"))')
+        lines.append("        return")
+        lines.append("    fi")
+        lines.append('    case "${COMP_WORDS[2]}" in')
+        for name, subparser in subcommands.items():
+            lines.append(f'        {name}) local opts="{" ".join(parser_options(subparser)[0])}" ;;')
+        lines.append(f'        *) local opts="{" ".join(options)}" ;;')
+        lines.append("    esac")
+    else:
+        lines.append(f'    local opts="{" ".join(options)}"')
+    lines.append('    if [[ "$cur" == -* ]]; then')
+    lines.append('        COMPREPLY=($(compgen -W "$opts" -- "$cur"))')
+    lines.append("    fi")
+    lines.append("}")
+    lines.append("_doge_guard() {")
+    lines.append('    local fn="_doge_guard_$(basename "${COMP_WORDS[1]}" | tr .- __)"')
+    lines.append('    COMPREPLY=()')
+    lines.append('    if [[ $COMP_CWORD -ge 2 ]] && declare -F "$fn" > /dev/null; then')
+    lines.append('        "$fn"')
+    lines.append("    fi")
+    lines.append("}")
+    # -o default falls back to completing file names, e.g. the datadir
+    lines.append("complete -o default -F _doge_guard python python3")
+    return "\n".join(lines) + "\n"
+
+
+def fish_completion(parser, script):
+    '''
+    Fish completion of `python script ...`.
+    '''
+    def escape(text):
+        return (text or "").replace("'", "\\'")
+
+    def option_lines(parser, condition):
+        lines = []
+        for action in parser._actions:
+            for option in action.option_strings:
+                flag = f"-l {option[2:]}" if option.startswith("--") else f"-s {option[1:]}"
+                choices = f" -x -a '{' '.join(map(str, action.choices))}'" if action.choices else ""
+                lines.append(f"complete -c python -c python3 -n '{condition}' {flag}{choices} -d '{escape(action.help)}'")
+        return lines
+
+    options, subcommands = parser_options(parser)
+    subcommand_help = {}
+    for action in parser._actions:
+        if isinstance(action, argparse._SubParsersAction):
+            subcommand_help.update((choice.dest, choice.help) for choice in action._choices_actions)
+    in_script = f"__fish_seen_subcommand_from {script}"
+    if len(subcommands) == 0:
+        return "\n".join(option_lines(parser, in_script)) + "\n"
+    lines = []
+    no_subcommand = f"{in_script}; and not __fish_seen_subcommand_from {' '.join(subcommands)}"
+    for name, subparser in subcommands.items():
+        lines.append(f"complete -c python -c python3 -n '{no_subcommand}' -a {name} -d '{escape(subcommand_help.get(name))}'")
+        lines.extend(option_lines(subparser, f"{in_script}; and __fish_seen_subcommand_from {name}"))
+    return "\n".join(lines) + "\n"
+
+
+class CompletionAction(argparse.Action):
+    '''
+    An argparse action like --version that prints the shell completion script of the parser it's added to and exits, e.g.
+    parser.add_argument("--completion", action=CompletionAction, script="backend.py").
+    '''
+    def __init__(self, option_strings, dest, script, **kwargs):
+        self.script = script
+        super().__init__(option_strings, dest, nargs=None, choices=["bash", "zsh", "fish"], default=argparse.SUPPRESS, **kwargs)
+
+
+    def __call__(self, parser, namespace, values, option_string=None):
+        if values == "fish":
+            print(fish_completion(parser, self.script), end="")
+        elif values == "zsh":
+            print("autoload -U +X bashcompinit && bashcompinit")
+            print(bash_completion(parser, self.script), end="")
+        else:
+            print(bash_completion(parser, self.script), end="")
+        parser.exit()
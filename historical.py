@@ -0,0 +1,186 @@
+import datetime
+from frapi import FR_SEARCH_FIRST_VOLUME, GOVINFO_URL, http_get, parse_date
+import hashlib
+import html
+import os
+import re
+from report import report_error
+import shutil
+from store import fr_doc_dir, write_atomic
+import subprocess
+import tempfile
+import toml
+
+##########################################################
+# Functions for resolving FR citations from before 1994 #
+##########################################################
+
+# The package of an issue of the FR on GovInfo, e.g. FR-1990-03-14, which is in the URL the link service redirects to
+govinfo_fr_package_regex = re.compile(r"/FR-(\d{4}-\d{2}-\d{2})/")
+
+
+def is_historical_citation(fr_cita):
+    '''
+    Whether an FR citation, e.g. "55 FR 1234", is of a volume from before 1994, which FederalRegister.gov's search
+    doesn't cover.
+    '''
+    volume = fr_cita.split(" ")[0]
+    return volume.isdigit() and int(volume) < FR_SEARCH_FIRST_VOLUME
+
+
+def historical_docno(fr_cita):
+    '''
+    The identifier a historical FR document is stored under, in place of a document number, e.g. historical-55-FR-1234.
+    '''
+    volume, _, page = fr_cita.split(" ")
+    return f"historical-{volume}-FR-{page}"
+
+
+def pdf_text(pdf_path):
+    '''
+    The text layer of a PDF, extracted with pdftotext, or "" if it has none, e.g. a scan that was never OCR'd, or if
+    pdftotext isn't installed.
+    '''
+    if shutil.which("pdftotext") is None:
+        return ""
+    result = subprocess.run(["pdftotext", "-layout", pdf_path, "-"], capture_output=True)
+    if result.returncode != 0:
+        raise ValueError(f"pdftotext failed: {result.stderr.decode(errors='replace').strip()}")
+    return result.stdout.decode(errors="replace")
+
+
+# The resolution the pages of a scanned PDF are rendered at for OCR. Tesseract does best with 300 DPI.
+OCR_DPI = 300
+# The mean confidence of the OCR'd words, from 0 to 1, below which the OCR is reported as poor. Scans of the FR from the
+# 1970s and 80s are often faint or skewed, and the LLM analysis of their text should be read with care.
+OCR_MIN_QUALITY = 0.6
+
+
+def ocr_available():
+    return shutil.which("pdftoppm") is not None and shutil.which("tesseract") is not None
+
+
+def ocr_pdf(pdf_path):
+    '''
+    OCR a PDF of scanned pages: render each page with pdftoppm, from poppler, and read it with tesseract. Returns (text,
+    quality), where quality is the mean confidence tesseract has in the words it read, from 0 to 1, or None if it read none.
+    '''
+    with tempfile.TemporaryDirectory() as tmpdir:
+        result = subprocess.run(["pdftoppm", "-r", str(OCR_DPI), "-png", pdf_path, os.path.join(tmpdir, "page")], capture_output=True)
+        if result.returncode != 0:
+            raise ValueError(f"pdftoppm failed: {result.stderr.decode(errors='replace').strip()}")
+        texts, confidences = [], []
+        # pdftoppm numbers the pages with as many digits as the last page needs, so they sort by name
+        for page in sorted(name for name in os.listdir(tmpdir) if name.endswith(".png")):
+            outbase = os.path.join(tmpdir, os.path.splitext(page)[0])
+            result = subprocess.run(["tesseract", os.path.join(tmpdir, page), outbase, "txt", "tsv"], capture_output=True)
+            if result.returncode != 0:
+                raise ValueError(f"tesseract failed on {page}: {result.stderr.decode(errors='replace').strip()}")
+            with open(f"{outbase}.txt", encoding="utf-8", errors="replace") as f:
+                texts.append(f.read())
+            # The columns of the TSV end with conf and text. Only the rows of words have a text and a conf other than -1.
+            with open(f"{outbase}.tsv", encoding="utf-8", errors="replace") as f:
+                for line in f.readlines()[1:]:
+                    fields = line.rstrip("\n").split("\t")
+                    if len(fields) >= 12 and fields[11].strip() != "" and float(fields[10]) >= 0:
+                        confidences.append(float(fields[10]) / 100)
+    quality = round(sum(confidences) / len(confidences), 3) if len(confidences) > 0 else None
+    return "\f".join(texts), quality
+
+
+def extract_text(fr_cita, docno, rule_pdf):
+    '''
+    The text of the PDF of a historical FR document, from its text layer if it has one, or else OCR'd, if pdftoppm and
+    tesseract are installed. Returns (text, text_source, ocr_quality), where text_source is "pdf-text", "ocr", or "" if
+    there's no text, and ocr_quality is as returned by ocr_pdf. Poor OCR is reported as an "ocr-low-quality" error.
+    '''
+    text = pdf_text(rule_pdf)
+    if text.strip() != "":
+        return text, "pdf-text", None
+    if not ocr_available():
+        return "", "", None
+    text, quality = ocr_pdf(rule_pdf)
+    if text.strip() == "":
+        return "", "", None
+    if quality is None or quality < OCR_MIN_QUALITY:
+        report_error("ocr-low-quality", f"The OCR of the scanned pages has a mean word confidence of {quality}", docno=docno, cita=fr_cita)
+    return text, "ocr", quality
+
+
+def store_text(fr_cita, docno, document_dir, details):
+    '''
+    Extract the text of the rule.pdf in document_dir and write it to rule.html. Sets the text_source, ocr_quality, and
+    html_sha256 of details, which the caller writes to details.toml.
+    '''
+    text, details["text_source"], ocr_quality = extract_text(fr_cita, docno, os.path.join(document_dir, "rule.pdf"))
+    # TOML has no null
+    if ocr_quality is not None:
+        details["ocr_quality"] = ocr_quality
+    rule_html = f"<html><body><pre>{html.escape(text)}</pre></body></html>".encode()
+    write_atomic(os.path.join(document_dir, "rule.html"), rule_html)
+    details["html_sha256"] = hashlib.sha256(rule_html).hexdigest()
+
+
+def fetch_historical_doc(fr_cita, datadir):
+    '''
+    Resolve an FR citation from before 1994 with GovInfo's link service, which redirects a volume and page of the Federal
+    Register to the PDF of the scanned pages, fetch it, and store it like an FR document, as rule.pdf and its text as
+    rule.html, so its divisions can be attributed and analyzed. Returns (docno, docinfo). The docinfo is flagged historical,
+    since all that's known of the document is its pages: it has no title, agencies, or document number, and the PDF may
+    hold its neighbors in the FR as well. The text is empty if the PDF has no text layer and can't be OCR'd. See
+    extract_text.
+    '''
+    docno = historical_docno(fr_cita)
+    volume, _, page = fr_cita.split(" ")
+    document_dir = fr_doc_dir(datadir, docno)
+    details_path = os.path.join(document_dir, "details.toml")
+    if os.path.exists(details_path):
+        details = toml.load(details_path)
+        # Fetched before OCR was available, so its text can be extracted now
+        if details.get("text_source", "") == "" and ocr_available():
+            store_text(fr_cita, docno, document_dir, details)
+            write_atomic(details_path, toml.dumps(details))
+    else:
+        pdf_url = f"{GOVINFO_URL}/link/fr/{volume}/{page}?link-type=pdf"
+        pdf_res = http_get(pdf_url)
+        pdf_res.raise_for_status()
+        if not pdf_res.headers.get("Content-Type", "").startswith("application/pdf"):
+            raise ValueError(f"GovInfo has no PDF of {fr_cita}")
+        package = re.search(govinfo_fr_package_regex, pdf_res.url or "")
+
+        os.makedirs(document_dir, exist_ok=True)
+        write_atomic(os.path.join(document_dir, "rule.pdf"), pdf_res.content)
+        details = {
+            "document_number": docno,
+            "citation": fr_cita,
+            "pdf_url": pdf_res.url or pdf_url,
+            "publication-date": datetime.date.fromisoformat(package[1]) if package is not None else "",
+            "historical": True,
+            # Used by store.py to detect corrupted documents
+            "pdf_sha256": hashlib.sha256(pdf_res.content).hexdigest(),
+        }
+        store_text(fr_cita, docno, document_dir, details)
+        # Written last, so a document directory without it is an incomplete fetch
+        write_atomic(details_path, toml.dumps(details))
+
+    publication_date = parse_date(details.get("publication-date") or None)
+    return docno, {
+        "document_number": docno,
+        "type": None,
+        "citation": fr_cita,
+        "start_page": int(page),
+        "end_page": int(page),
+        "title": None,
+        "abstract": None,
+        "agency_names": [],
+        "agencies": [],
+        "agency_shorthand": [],
+        "cfr_references": [],
+        "significant": None,
+        "publication_date": publication_date.isoformat() if publication_date is not None else None,
+        "body_html_url": None,
+        "pdf_url": details["pdf_url"],
+        "historical": True,
+        "text_source": details.get("text_source", ""),
+        "ocr_quality": details.get("ocr_quality"),
+    }
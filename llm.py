@@ -0,0 +1,988 @@
+import cohere
+import datetime
+from frapi import CA_BUNDLE, FR_API_URL, FR_DOC_FIELDS, abbreviate_agency, agency_abbreviations, fetch_fr_docs, format_fr_citation, fr_agencies, http_get, parse_date
+import gzip
+import hashlib
+import hnswlib
+import httpx
+import json
+import os
+import pandas as pd
+from pipeline import cfr_to_fr_docs
+from providers import LLM_PROVIDERS, CohereClient
+import queue
+import re
+from report import format_eta, report_error
+from store import fr_doc_dir, write_atomic
+import sys
+import threading
+import time
+import toml
+from unstructured.partition.html import partition_html
+from unstructured.chunking.title import chunk_by_title
+import uuid
+
+#####################################
+# Global constants for using Cohere #
+#####################################
+
+api_key = os.getenv("COHERE_API_KEY")
+if CA_BUNDLE:
+    # The Cohere client reads the proxy variables itself, but not a CA bundle
+    co = cohere.Client(api_key, httpx_client=httpx.Client(verify=CA_BUNDLE, timeout=300))
+else:
+    co = cohere.Client(api_key)
+
+# THESE ARE MUTATED!!! The clients of the providers that answer the questions and that embed and rerank the chunks of the
+# FR documents, and the embedding model. Cohere's, unless --llm-provider or --embedding-provider say otherwise. See
+# llm_client and providers.py.
+LLM_CLIENT = CohereClient(co)
+EMBEDDING_CLIENT = LLM_CLIENT
+EMBEDDING_MODEL = EMBEDDING_CLIENT.default_embedding_model
+
+USING_COHERE_TRIAL_KEY = False
+
+if USING_COHERE_TRIAL_KEY:
+    # Trial key rate limits
+    API_CALL_RATE_LIMIT = 10 # calls/min
+    TOKEN_RATE_LIMIT = 100000 # tokens/min
+else:
+    # Production key rate limits
+    API_CALL_RATE_LIMIT = 100000 # calls/min guess? This is supposedly 2,000 calls/min for embed, but experimentally, this limit worked...
+    TOKEN_RATE_LIMIT = 2000000 # tokens/min
+
+# THESE ARE MUTATED!!!
+TOKENS = 0
+CALLS = 0
+RATE_LIMIT_PAUSES = 0
+# THESE ARE MUTATED!!! The run's seed and LLM temperature, given to every chat call so its sampling can be reproduced (as far as
+# Cohere supports it), and the seed also to the document indexes. None leaves them to Cohere's and hnswlib's defaults.
+LLM_SEED = None
+LLM_TEMPERATURE = None
+# THESE ARE MUTATED!!! The models that answer the questions, in order of preference, and the index of the one in use. A
+# model fails over to the next after LLM_MAX_ERRORS errors in a row or a call slower than LLM_MAX_SECONDS. See chat.
+LLM_MODELS = ["command-r-plus"]
+ACTIVE_MODEL = 0
+LLM_MAX_ERRORS = 3
+LLM_MAX_SECONDS = None
+# THIS IS MUTATED!!! If set, the prompts and responses of every chat call about an FR document are kept in its directory,
+# for auditing. See Chatbot.record.
+LLM_TRANSCRIPTS = False
+# THIS IS MUTATED!!! If set, each question is answered from the excerpts of the FR document retrieved for it by an earlier
+# run, if any, rather than retrieving them again. See analyze_fr_doc.
+LLM_REUSE_RETRIEVAL = False
+
+
+##############################################
+# Functions and classes for LLM RAG analysis #
+##############################################
+
+def llm_client(provider):
+    '''
+    A new client of a provider of LLM_PROVIDERS, e.g. for LLM_CLIENT.
+    '''
+    return CohereClient(co) if provider == "cohere" else LLM_PROVIDERS[provider]()
+
+
+def index_name():
+    '''
+    The name of an FR document's index in its directory, which depends on the embeddings: "index" for Cohere's default
+    ones, and e.g. "index-ollama-nomic-embed-text" for others, so switching providers doesn't reuse an index of another's.
+    '''
+    if EMBEDDING_CLIENT.name == "cohere" and EMBEDDING_MODEL == CohereClient.default_embedding_model:
+        return "index"
+    return f"index-{EMBEDDING_CLIENT.name}-{re.sub(r'[^A-Za-z0-9._-]', '_', EMBEDDING_MODEL)}"
+
+
+def sampling_params():
+    '''
+    The keyword arguments of the chat calls for the run's seed and temperature, if set.
+    '''
+    params = {}
+    if LLM_SEED is not None:
+        params["seed"] = LLM_SEED
+    if LLM_TEMPERATURE is not None:
+        params["temperature"] = LLM_TEMPERATURE
+    return params
+
+
+def chat(**request):
+    '''
+    LLM_CLIENT's chat with the model of LLM_MODELS in use, retrying errors. After LLM_MAX_ERRORS errors in a row, or if the call took
+    longer than LLM_MAX_SECONDS, the rest of the run fails over to the next model, so a long run doesn't stall on one
+    model's outage. Returns (response, the model that produced it). Raises the last error if the last model fails too.
+    '''
+    global ACTIVE_MODEL
+    errors = 0
+    while True:
+        model = LLM_MODELS[ACTIVE_MODEL]
+        t0 = time.time()
+        try:
+            response = LLM_CLIENT.chat(model=model, **request, **sampling_params())
+        except Exception as e:
+            errors += 1
+            if errors < LLM_MAX_ERRORS:
+                continue
+            if ACTIVE_MODEL + 1 >= len(LLM_MODELS):
+                raise
+            ACTIVE_MODEL += 1
+            errors = 0
+            report_error("llm-failover", f"{model} failed {LLM_MAX_ERRORS} times in a row ({e}). Failing over to {LLM_MODELS[ACTIVE_MODEL]}.")
+            print(f"\n\t[!] {model} failed {LLM_MAX_ERRORS} times in a row. Failing over to {LLM_MODELS[ACTIVE_MODEL]}.")
+            continue
+        seconds = time.time() - t0
+        if LLM_MAX_SECONDS is not None and seconds > LLM_MAX_SECONDS and ACTIVE_MODEL + 1 < len(LLM_MODELS):
+            ACTIVE_MODEL += 1
+            report_error("llm-failover", f"{model} took {seconds:.0f} s, over {LLM_MAX_SECONDS} s. Failing over to {LLM_MODELS[ACTIVE_MODEL]}.")
+            print(f"\n\t[!] {model} took {seconds:.0f} s. Failing over to {LLM_MODELS[ACTIVE_MODEL]}.")
+        return response, model
+
+
+def billed_tokens(response):
+    '''
+    The (input, output) tokens Cohere billed for a chat response, or (0, 0) if it didn't say.
+    '''
+    billed_units = getattr(getattr(response, "meta", None), "billed_units", None)
+    return int(getattr(billed_units, "input_tokens", None) or 0), int(getattr(billed_units, "output_tokens", None) or 0)
+
+
+def rate_limit_check(additional_toks):
+    '''
+    Enforces pauses for Cohere's rate limits. Not thread-safe. Call before every Cohere request. E.g.,
+    
+    texts = [item["text"] for item in batch]   
+    rate_limit_check(sum(map(lambda x : len(x), texts)))
+    docs_embs_batch = co.embed(
+        texts=texts, model="embed-english-v3.0", input_type="search_document"
+    ).embeddings        
+    '''
+    global TOKENS
+    global CALLS
+    global RATE_LIMIT_PAUSES
+
+    # The other providers' limits are enforced by their clients
+    if LLM_CLIENT.name != "cohere" and EMBEDDING_CLIENT.name != "cohere":
+        return
+
+    print("--- Rate Limit Pause internals ---")
+    print("TOKENS:", TOKENS)
+    print("CALLS:", CALLS)
+    print("RATE_LIMIT_PAUSES:", RATE_LIMIT_PAUSES)
+    print("-----------------------------------")
+
+    CALLS += 1
+    TOKENS += additional_toks
+    if TOKENS / TOKEN_RATE_LIMIT - RATE_LIMIT_PAUSES >= 1 or CALLS / API_CALL_RATE_LIMIT >= 1:
+        print("\tPause for rate-limit...")
+        time.sleep(60)
+        CALLS = 0
+        RATE_LIMIT_PAUSES += 1
+
+
+class VectorStoreIndex:
+    '''
+    Interface for creating and calling an Hnswlib vectorstore for a single document
+    '''
+    def __init__(self, raw_doc_path, index_path, outf=sys.stdout):
+        self.raw_doc_path = raw_doc_path
+        self.docs = []
+        self.docs_embs = []
+        self.retrieve_top_k = 15
+        self.rerank_top_k = 5
+        self.idx = hnswlib.Index(space="ip", dim=EMBEDDING_CLIENT.dimension(EMBEDDING_MODEL))
+        self.input_doc_tok_len = 0
+        self.input_doc_word_len = 0
+        self.outf = outf
+        
+        self.load_and_chunk()
+        if os.path.exists(index_path):
+            self.idx.load_index(index_path)
+        else:
+            self.embed()
+            self.index(index_path)
+        print(f"Indexing complete with {self.idx.get_current_count()} document chunks.", file=self.outf)
+
+
+    def load_and_chunk(self):
+        print("Loading documents...", file=self.outf)
+
+        with open(self.raw_doc_path, "r", encoding="windows-1252") as f:
+            html_content = f.read()
+        
+        t0 = time.time()
+        print("\tPartition HTML", file=self.outf)
+        elements = partition_html(text=html_content)
+        print(f"\t\t{time.time() - t0} s", file=self.outf)
+        
+        t0 = time.time()
+        print("\tChunk by title", file=self.outf)
+        chunks = chunk_by_title(elements)
+        print(f"\t\t{time.time() - t0} s", file=self.outf)
+        
+        print(f"\tChunking {self.raw_doc_path}", end="", file=self.outf)
+        t0 = time.time()
+        for chunk in chunks:
+            chunk = str(chunk)
+            self.input_doc_tok_len += len(chunk)
+            self.input_doc_word_len += len(list(filter(lambda word : not (word.isspace() or word == ""), chunk.split(" "))))
+            self.docs.append(
+                {
+                    "title": self.raw_doc_path,
+                    "text": chunk,
+                }
+            )
+        print(f"\t\t{time.time() - t0} s", file=self.outf)
+
+    
+    def embed(self):
+        print("Embedding document chunks...", file=self.outf)
+
+        batch_size = 90
+        self.docs_len = len(self.docs)
+        for i in range(0, self.docs_len, batch_size):
+            batch = self.docs[i : min(i + batch_size, self.docs_len)]
+            texts = [item["text"] for item in batch]
+            
+            rate_limit_check(sum(map(lambda x : len(x), texts)))
+            print(f"\tSending...", file=self.outf)
+            docs_embs_batch = EMBEDDING_CLIENT.embed(texts, EMBEDDING_MODEL, "search_document")
+            self.docs_embs.extend(docs_embs_batch)
+            
+   
+    def index(self, index_path):
+        print("Indexing document chunks...", file=self.outf)
+
+        self.idx.init_index(max_elements=self.docs_len, ef_construction=512, M=64, random_seed=LLM_SEED if LLM_SEED is not None else 100)
+        self.idx.add_items(self.docs_embs, list(range(len(self.docs_embs))))
+
+        print("Saving idx to disc...", file=self.outf)
+        self.idx.save_index(index_path)
+
+    
+    def chunk(self, doc_id):
+        return {
+            "title": self.docs[doc_id]["title"],
+            "text": self.docs[doc_id]["text"],
+        }
+
+
+    def retrieve(self, query: str):
+        '''
+        Returns the ids of the rerank_top_k chunks most relevant to query, in order of relevance. See chunk.
+        '''
+        # Retrieve
+        rate_limit_check(len(query))
+        query_emb = EMBEDDING_CLIENT.embed([query], EMBEDDING_MODEL, "search_query")
+
+        doc_ids = self.idx.knn_query(query_emb, k=self.retrieve_top_k)[0][0]
+
+        # Rerank
+        docs_to_rerank = [self.docs[doc_id] for doc_id in doc_ids]
+        print("Docs to rerank:", docs_to_rerank, file=self.outf)
+
+        rate_limit_check(len(query))
+        rerank_results = EMBEDDING_CLIENT.rerank(query, docs_to_rerank, self.rerank_top_k)
+
+        doc_ids_reranked = [int(doc_ids[i]) for i in rerank_results]
+
+        print("Docs reranked:", [self.chunk(doc_id) for doc_id in doc_ids_reranked], file=self.outf)
+
+        return doc_ids_reranked
+
+
+class Chatbot:
+    def __init__(self, vectorstore: VectorStoreIndex, outf=sys.stdout):
+        self.vectorstore = vectorstore
+        self.conversation_id = str(uuid.uuid4())
+        self.outf = outf
+        # [{request, response}] of every chat call, if LLM_TRANSCRIPTS is set
+        self.transcript = []
+ 
+    
+    def record(self, request, response):
+        '''
+        Add a chat call, its keyword arguments and the response, to the transcript, if LLM_TRANSCRIPTS is set.
+        '''
+        if not LLM_TRANSCRIPTS:
+            return
+        self.transcript.append({
+            "request": request,
+            "response": {
+                "text": response.text,
+                "search-queries": [query.text for query in response.search_queries or []],
+                "citations": response.citations or [],
+                "documents": response.documents or [],
+            },
+        })
+
+
+    def save_transcript(self, path):
+        '''
+        Write the transcript to path as gzipped JSON, if LLM_TRANSCRIPTS is set. Print it with `python store.py transcript`.
+        '''
+        if LLM_TRANSCRIPTS:
+            write_atomic(path, gzip.compress(json.dumps(self.transcript, default=str).encode()))
+
+
+    def close(self):
+        '''
+        Forget the conversation, which a provider other than Cohere keeps in LLM_CLIENT for every Chatbot otherwise.
+        '''
+        LLM_CLIENT.end_conversation(self.conversation_id)
+
+
+    def run(self, preamble, prompt, retrieval=None):
+        '''
+        Answer prompt from the chunks of the document retrieved for the search queries the LLM makes of it. Unless retrieval,
+        the {"search-queries", "chunk-ids"} of an earlier run's result, is given, in which case its chunks are used instead.
+        '''
+        result = {}
+        print(f"\n{'-'*100}\n", file=self.outf)
+        t0 = time.time()
+        input_tokens, output_tokens = 0, 0
+        
+        try:
+            toks_in_query = len(preamble) + len(prompt)
+
+            if retrieval is not None:
+                search_queries = retrieval["search-queries"]
+                print("Reusing the retrieved information...", end="", file=self.outf)
+            else:
+                # Generate search queries (if any)
+                rate_limit_check(toks_in_query)
+                request = dict(preamble=preamble, message=prompt, model=LLM_CLIENT.search_queries_model or LLM_MODELS[ACTIVE_MODEL], search_queries_only=True, **sampling_params())
+                response = LLM_CLIENT.chat(**request)
+                self.record(request, response)
+                input_tokens, output_tokens = billed_tokens(response)
+                search_queries = [query.text for query in response.search_queries or []]
+
+            # If there are search queries, retrieve document chunks and respond
+            if search_queries:
+                # Retrieve document chunks for each query
+                if retrieval is None:
+                    print("Retrieving information...", end="", file=self.outf)
+                    chunk_ids = [doc_id for query in search_queries for doc_id in self.vectorstore.retrieve(query)]
+                else:
+                    chunk_ids = retrieval["chunk-ids"]
+                documents = [self.vectorstore.chunk(doc_id) for doc_id in chunk_ids]
+                result["retrieval"] = {"search-queries": search_queries, "chunk-ids": chunk_ids}
+                result["chunks_used"] = documents
+                result["fr_doc_tok_len"] = self.vectorstore.input_doc_tok_len
+                result["fr_doc_word_len"] = self.vectorstore.input_doc_word_len
+
+                # Use document chunks to respond
+                rate_limit_check(toks_in_query)
+                request = dict(preamble=preamble, message=prompt, documents=documents, conversation_id=self.conversation_id)
+                response, model = chat(**request)
+                self.record({**request, "model": model, **sampling_params()}, response)
+                result["model"] = model
+                answer_input_tokens, answer_output_tokens = billed_tokens(response)
+                input_tokens += answer_input_tokens
+                output_tokens += answer_output_tokens
+            else:
+                raise Exception("No search queries identified in prompt")
+
+            # Print the chatbot response, citations, and document
+            print("\nChatbot:", response.text, file=self.outf)
+            result["answer"] = response.text
+            result["citations"] = response.citations
+
+            # Display citations and source documents
+            if response.citations:
+                print("\n\nCITATIONS:", file=self.outf)
+                for citation in response.citations:
+                    print(citation, file=self.outf)
+
+                print("\nDOCUMENTS:", file=self.outf)
+                for document in response.documents:
+                    print(document, file=self.outf)
+            result["err_msg"] = ""
+        except Exception as e:
+            report_error("llm-error", f"{e}", docno=os.path.basename(os.path.dirname(self.vectorstore.raw_doc_path)))
+            result = {
+                "answer": "ERROR",
+                "err_msg": f"{e}",
+                "citations": [],
+                "chunks_used": [],
+                "model": None,
+                "fr_doc_tok_len": self.vectorstore.input_doc_tok_len,
+                "fr_doc_word_len": self.vectorstore.input_doc_word_len,
+            }
+        result["seconds"] = time.time() - t0
+        result["input_tokens"] = input_tokens
+        result["output_tokens"] = output_tokens
+
+        return result
+
+
+STATUTORY_AUTHORITY_PREAMBLE = '''
+
+    ## Task & Context
+    You have been given a Final Rule document which is a document published by a U.S. federal government agency that establishes a new regulation. In a Final Rule document, the agency issuing the Rule responds to any significant, relevant issues raised in public comments about the Rule during the rule-making process. For each public comment in the Final Rule, the agency will first describe the comment from the public and then offer the agency's response. You are being asked to look over all of the comments described in this Final Rule and determine if any of the public commenters raised concerns that the agency is not acting with authority from Congress by issuing this rule. You will only answer yes or no.
+    '''
+
+# The questions asked of each FR document when no --questions file is given. {agencies} and {pronoun} in a prompt are filled
+# in with the agencies that issued the document.
+DEFAULT_QUESTIONS = [
+    {
+        "id": "statutory-authority",
+        "preamble": STATUTORY_AUTHORITY_PREAMBLE,
+        "prompt": '''
+        Did {agencies} receive any public comments questioning {pronoun} legal or statutory authority to issue this Final Rule?
+        ''',
+        "answers": ["yes", "no"],
+        "scope": "document",
+    },
+]
+
+
+def load_questions(path):
+    '''
+    Load a question set from a TOML file of [[question]] tables, each with an id, a prompt, and optionally a preamble, the
+    answers it must start with, and depends_on: the answers earlier questions must have started with for it to be asked. E.g.,
+
+    [[question]]
+    id = "reporting"
+    prompt = "Does this Final Rule impose a new reporting requirement? Answer yes or no."
+    answers = ["yes", "no"]
+
+    [[question]]
+    id = "reporting-hours"
+    prompt = "Estimate the annual hours of the reporting requirements this Final Rule imposes."
+    depends_on = { reporting = "yes" }
+
+    A question with scope = "division" is asked once of each group of FR documents that amended the same CFR division instead
+    of each document (see group_analysis), and can only depend on other such questions.
+
+    Returns the questions in an order they can be asked in, i.e. each after the questions it depends on. Raises a ValueError
+    if the set isn't valid.
+    '''
+    questions = toml.load(path).get("question", [])
+    if len(questions) == 0:
+        raise ValueError(f"{path} has no [[question]] tables")
+    for question in questions:
+        if "id" not in question or "prompt" not in question:
+            raise ValueError(f"every question in {path} needs an id and a prompt")
+        question.setdefault("preamble", "")
+        question.setdefault("scope", "document")
+        if question["scope"] not in ("document", "division"):
+            raise ValueError(f"question {question['id']} has unknown scope {question['scope']}")
+    return order_questions([q for q in questions if q["scope"] == "document"]) + order_questions([q for q in questions if q["scope"] == "division"])
+
+
+def order_questions(questions):
+    '''
+    Sort questions so that each comes after the questions it depends_on, keeping the given order otherwise.
+    '''
+    by_id = {}
+    for question in questions:
+        if question["id"] in by_id:
+            raise ValueError(f"question {question['id']} is defined twice")
+        by_id[question["id"]] = question
+    for question in questions:
+        for dep in question.get("depends_on", {}):
+            if dep not in by_id:
+                raise ValueError(f"question {question['id']} depends on unknown question {dep}")
+
+    ordered = []
+    placed = set()
+    while len(ordered) < len(questions):
+        ready = [q for q in questions if q["id"] not in placed and all(dep in placed for dep in q.get("depends_on", {}))]
+        if len(ready) == 0:
+            cycle = [q["id"] for q in questions if q["id"] not in placed]
+            raise ValueError(f"the dependencies of questions {', '.join(cycle)} form a cycle")
+        ordered.append(ready[0])
+        placed.add(ready[0]["id"])
+    return ordered
+
+
+def unmet_dependency(question, answers):
+    '''
+    Returns why question shouldn't be asked given the answers to the questions before it, or None if it should be. A
+    dependency is met if the answer to it starts with the required answer, or one of them if several are listed.
+    '''
+    for dep, required in question.get("depends_on", {}).items():
+        required = [required] if isinstance(required, str) else required
+        answer = answers[dep]
+        if answer in ("ERROR", "SKIPPED"):
+            return f"{dep} was {answer.lower()}"
+        if not answer.strip().lower().startswith(tuple(r.lower() for r in required)):
+            return f"{dep} wasn't {' or '.join(required)}"
+    return None
+
+
+def estimate_llm_workload(fr_doc_data, chunk_chars=500, num_questions=1):
+    '''
+    Estimate the Cohere usage of llm_analysis over fr_doc_data from the statistics of each document, in the same units as
+    rate_limit_check: characters sent and API calls. The text is split into chunks of at most chunk_chars characters, and
+    every section heading and table starts a new chunk. Documents already indexed by a previous run, and questions skipped for
+    their dependencies, are counted too, so this is an upper bound.
+    '''
+    chunks = 0
+    chars = 0
+    calls = 0
+    for _, fr_doc in fr_doc_data.iterrows():
+        if pd.isna(fr_doc["fr-doc-char-count"]):
+            continue
+        doc_chunks = int(max(fr_doc["fr-doc-char-count"] / chunk_chars, fr_doc["fr-doc-section-count"] + fr_doc["fr-doc-table-count"])) + 1
+        chunks += doc_chunks
+        chars += int(fr_doc["fr-doc-char-count"])
+        # Chunks are embedded in batches of 90, then each question takes two chat calls
+        calls += -(-doc_chunks // 90) + 2 * num_questions
+    return {"chunks": chunks, "chars": chars, "calls": calls}
+
+
+def projected_llm_time(workload):
+    '''
+    The minimum time, in seconds, that an estimate_llm_workload takes under the Cohere rate limits, ignoring the time the
+    requests themselves take.
+    '''
+    return 60 * max(workload["chars"] / TOKEN_RATE_LIMIT, workload["calls"] / API_CALL_RATE_LIMIT)
+
+
+def analyze_fr_doc(fr_doc_data, datadir, questions=DEFAULT_QUESTIONS):
+    '''
+    Ask each question of one FR document, fr_doc_data, a row of the FR doc data or a dict with at least its fr-docno,
+    fr-doc-agencies, and fr-doc-agencies-shorthand. Returns {column: value} of the LLM columns of llm_analysis. The search
+    queries and chunk ids retrieved for each question are kept in retrieval.json, {question id: {"search-queries",
+    "chunk-ids"}}, so that an answer can be audited against its excerpts, or, with LLM_REUSE_RETRIEVAL, asked again of them
+    when only the question's preamble or prompt has changed.
+    '''
+    def column(name, question):
+        return name if len(questions) == 1 else f"{name}-{question['id']}"
+
+    results = {}
+    rule_dir = fr_doc_dir(datadir, fr_doc_data["fr-docno"])
+    rule_html = os.path.join(rule_dir, "rule.html")
+    index_path = os.path.join(rule_dir, index_name())
+    retrieval_json = os.path.join(rule_dir, "retrieval.json")
+    retrievals = {}
+    if os.path.exists(retrieval_json):
+        with open(retrieval_json) as f:
+            retrievals = json.load(f)
+    # TODO: change results.txt to a .json
+    results_txt = open(os.path.join(rule_dir, "results.txt"), "w")
+
+    agencies = " or ".join([f"the {a} ({abbrv})" for a, abbrv in zip(fr_doc_data["fr-doc-agencies"], fr_doc_data["fr-doc-agencies-shorthand"])])
+    pronoun = "their" if len(fr_doc_data["fr-doc-agencies"]) > 1 else "its"
+    
+    vectorstore = VectorStoreIndex(rule_html, index_path, outf=results_txt)
+    chatbot = Chatbot(vectorstore, outf=results_txt)
+    answers = {}
+    try:
+        for question in questions:
+            prompt = question["prompt"].format(agencies=agencies, pronoun=pronoun)
+            skip_reason = unmet_dependency(question, answers)
+            if skip_reason is not None:
+                print(f"\t[*] Skip {question['id']}: {skip_reason}")
+                llm_results = {
+                    "answer": "SKIPPED",
+                    "err_msg": skip_reason,
+                    "citations": [],
+                    "chunks_used": [],
+                    "model": None,
+                    "seconds": 0.0,
+                    "input_tokens": 0,
+                    "output_tokens": 0,
+                }
+            else:
+                retrieval = retrievals.get(question["id"]) if LLM_REUSE_RETRIEVAL else None
+                llm_results = chatbot.run(question["preamble"], prompt, retrieval)
+                if "retrieval" in llm_results:
+                    retrievals[question["id"]] = llm_results["retrieval"]
+            if "answers" in question and llm_results["answer"] not in ("ERROR", "SKIPPED") and not llm_results["answer"].strip().lower().startswith(tuple(question["answers"])):
+                report_error("llm-invalid-answer", f"The answer to {question['id']} doesn't start with {' or '.join(question['answers'])}", docno=fr_doc_data["fr-docno"], answer=llm_results["answer"])
+            answers[question["id"]] = llm_results["answer"]
+
+            results[column("llm-answer", question)] = llm_results["answer"]
+            results[column("llm-citations", question)] = llm_results["citations"]
+            results[column("llm-chunks-used", question)] = llm_results["chunks_used"]
+            results[column("llm-preamble", question)] = question["preamble"]
+            results[column("llm-prompt", question)] = prompt
+            results[column("llm-error", question)] = llm_results["err_msg"]
+            results[column("llm-model", question)] = llm_results["model"]
+            results[column("llm-seconds", question)] = llm_results["seconds"]
+            results[column("llm-input-tokens", question)] = llm_results["input_tokens"]
+            results[column("llm-output-tokens", question)] = llm_results["output_tokens"]
+        chatbot.save_transcript(os.path.join(rule_dir, "transcript.json.gz"))
+    finally:
+        chatbot.close()
+    write_atomic(retrieval_json, json.dumps(retrievals, indent=2).encode())
+    results["fr-doc-tok-len"] = vectorstore.input_doc_tok_len
+    results["fr-doc-word-len"] = vectorstore.input_doc_word_len
+    return results
+
+
+def llm_analysis(fr_doc_dataset, datadir, questions=DEFAULT_QUESTIONS, analyzed={}):
+    '''
+    Ask each question of each FR document in dependency order. A question whose dependencies weren't answered as required
+    isn't sent to the LLM, and is answered SKIPPED. The answers of a set of one question are in the llm-answer, llm-citations,
+    etc. columns; those of larger sets are in columns suffixed with the question's id, e.g. llm-answer-reporting. Documents
+    in analyzed, {docno: results of analyze_fr_doc}, e.g. by a StreamingAnalysis, aren't asked again.
+    '''
+    def column(name, question):
+        return name if len(questions) == 1 else f"{name}-{question['id']}"
+
+    results = {}
+    for question in questions:
+        for name in ("llm-answer", "llm-citations", "llm-chunks-used", "llm-preamble", "llm-prompt", "llm-error", "llm-model", "llm-seconds", "llm-input-tokens", "llm-output-tokens"):
+            results[column(name, question)] = []
+    results.update({"fr-doc-tok-len": [], "fr-doc-word-len": [], "llm-unread-images": []})
+
+    print(fr_doc_dataset.head())
+    t0 = time.time()
+    for i, (_, fr_doc_data) in enumerate(fr_doc_dataset.iterrows()):
+        if fr_doc_data["fr-docno"] in analyzed:
+            doc_results = analyzed[fr_doc_data["fr-docno"]]
+        else:
+            print(f"[*] LLM analysis {i+1}/{len(fr_doc_dataset)}: {fr_doc_data['fr-docno']} {format_eta(t0, i, len(fr_doc_dataset))}")
+            print(fr_doc_data)
+            doc_results = analyze_fr_doc(fr_doc_data, datadir, questions)
+        for name, value in doc_results.items():
+            results[name].append(value)
+        # Only the text of the document is given to the LLM, so flag any content in images that it couldn't read
+        results["llm-unread-images"].append(fr_doc_data.get("fr-doc-image-count"))
+        
+    return pd.concat([fr_doc_dataset, pd.DataFrame(results)], axis=1)
+
+
+# The FR documents fetched ahead of a StreamingAnalysis. Fetching waits when this many are waiting to be analyzed.
+STREAM_QUEUE_SIZE = 8
+
+
+class StreamingAnalysis:
+    '''
+    Asks the questions of each FR document on a background thread as soon as it's fetched, rather than after every document
+    is, so the LLM analysis of a large run starts on its first documents while the rest are still downloading. Fetched
+    documents wait in a queue of at most queue_size, and submit blocks when it's full, so the fetching never gets more than
+    that far ahead of the analysis. Documents published before docs_since aren't analyzed. finish returns the results to
+    pass to llm_analysis as analyzed. A document whose analysis fails is left for llm_analysis to retry. If on_analyzed is
+    given, it's called with (fr_doc_data, results of analyze_fr_doc) of each document as soon as it's analyzed.
+    '''
+    def __init__(self, datadir, questions, docs_since=None, queue_size=STREAM_QUEUE_SIZE, on_analyzed=None):
+        self.datadir = datadir
+        self.questions = questions
+        self.docs_since = docs_since
+        self.on_analyzed = on_analyzed
+        self.queue = queue.Queue(maxsize=queue_size)
+        self.analyzed = {}
+        self.thread = threading.Thread(target=self.run, daemon=True)
+        self.thread.start()
+
+
+    def submit(self, docno, docinfo):
+        published = parse_date(docinfo["publication_date"])
+        # The publication date of a historical document isn't always known. See fetch_historical_doc.
+        if self.docs_since is not None and (published is None or published < self.docs_since):
+            return
+        self.queue.put({
+            "fr-docno": docno,
+            "fr-doc-citation": docinfo.get("citation"),
+            "fr-doc-title": docinfo.get("title"),
+            "fr-doc-publication-date": parse_date(docinfo.get("publication_date")),
+            "fr-doc-agencies": docinfo["agencies"],
+            "fr-doc-agencies-shorthand": docinfo["agency_shorthand"],
+        })
+
+
+    def run(self):
+        while True:
+            fr_doc_data = self.queue.get()
+            if fr_doc_data is None:
+                return
+            try:
+                self.analyzed[fr_doc_data["fr-docno"]] = analyze_fr_doc(fr_doc_data, self.datadir, self.questions)
+            except Exception as e:
+                print(f"\n\t[!] Streaming LLM analysis of {fr_doc_data['fr-docno']} failed: {type(e).__name__}: {e}")
+                continue
+            if self.on_analyzed is not None:
+                self.on_analyzed(fr_doc_data, self.analyzed[fr_doc_data["fr-docno"]])
+
+
+    def finish(self):
+        '''
+        Wait for the documents submitted so far to be analyzed and return {docno: results of analyze_fr_doc}.
+        '''
+        self.queue.put(None)
+        self.thread.join()
+        return self.analyzed
+
+
+def stream_docs(cfr_parts, datadir, questions=DEFAULT_QUESTIONS, docs_since=None, **options):
+    '''
+    Attribute the CFR Parts cfr_parts, [(titleno, part info)] as for cfr_to_fr_docs, to FR documents and ask questions of
+    each document, yielding each one as soon as its analysis completes, rather than returning the tables of the whole run at
+    the end. For embedding the analysis, e.g. in an ETL job or a server sending each document to its clients as it's ready:
+
+    for fr_doc in stream_docs(cfr_parts, "documents/"):
+        load(fr_doc)
+
+    Each document is a dict of its fr-docno, fr-doc-citation, fr-doc-title, fr-doc-publication-date, fr-doc-agencies, and
+    fr-doc-agencies-shorthand, and the LLM columns of analyze_fr_doc. Documents whose analysis fails aren't yielded.
+    options are passed to cfr_to_fr_docs, and any error of it is raised once the documents analyzed before it are yielded.
+    '''
+    analyzed = queue.Queue()
+    errors = []
+    def attribute_and_analyze():
+        streaming = StreamingAnalysis(datadir, questions, docs_since=docs_since, on_analyzed=lambda fr_doc_data, results : analyzed.put({**fr_doc_data, **results}))
+        try:
+            cfr_to_fr_docs(cfr_parts, datadir, on_fetched=streaming.submit, **options)
+        except Exception as e:
+            errors.append(e)
+        finally:
+            streaming.finish()
+            analyzed.put(None)
+
+    thread = threading.Thread(target=attribute_and_analyze, daemon=True)
+    thread.start()
+    while True:
+        fr_doc = analyzed.get()
+        if fr_doc is None:
+            break
+        yield fr_doc
+    thread.join()
+    if len(errors) > 0:
+        raise errors[0]
+
+
+def division_groups(fr_doc_dataset, cfr_cov, min_docs=2):
+    '''
+    Group the FR documents in fr_doc_dataset by the CFR divisions whose citations were attributed to them, i.e. the rules that
+    created or amended each division. Returns {(titleno, partno, division name): [docno]} of the groups of at least min_docs
+    documents, with the documents in order of publication. Sections are only grouped in their own Part, since a document
+    attributed to several Parts references the divisions of all of them.
+    '''
+    dates = dict(zip(fr_doc_dataset["fr-docno"], fr_doc_dataset["fr-doc-publication-date"]))
+    divs_of_doc = dict(zip(fr_doc_dataset["fr-docno"], fr_doc_dataset["cfr-divs-referenced-in"]))
+    groups = {}
+    for _, part_cov in cfr_cov.iterrows():
+        titleno, partno = part_cov["cfr-title"], str(part_cov["cfr-part"])
+        for docno in part_cov["fr-docs-attributed"]:
+            for div in divs_of_doc.get(docno, []):
+                if div[1] == "SECTION" and not div[0].startswith(f"{partno}."):
+                    continue
+                groups.setdefault((titleno, partno, div[0]), []).append(docno)
+    return {key: sorted(set(docnos), key=lambda docno: dates[docno]) for key, docnos in groups.items() if len(set(docnos)) >= min_docs}
+
+
+def estimate_group_workload(groups, num_questions):
+    '''
+    Estimate the Cohere API calls of group_analysis: an embed and a rerank call per document of each group, then a chat call.
+    '''
+    return sum(2 * len(docnos) + 1 for docnos in groups.values()) * num_questions
+
+
+def group_analysis(fr_doc_dataset, groups, datadir, outdir, questions, max_chars=200000):
+    '''
+    Ask each question with scope = "division" of each group of division_groups, e.g. how the requirements of a section changed
+    over the rules that amended it. The chunks of each document most relevant to the question are retrieved and given to the
+    LLM together, newest document first. If they're longer than max_chars, the oldest documents are left out, and listed in
+    the fr-docs-omitted column. {titleno}, {partno}, and {division} in a prompt are filled in with the group's division.
+    Returns a table with a row per division and question.
+    '''
+    results = {
+        "cfr-title": [],
+        "cfr-part": [],
+        "cfr-div": [],
+        "question-id": [],
+        "fr-docnos": [],
+        "fr-docs-omitted": [],
+        "llm-prompt": [],
+        "llm-answer": [],
+        "llm-citations": [],
+        "llm-error": [],
+        "llm-model": [],
+    }
+    fr_docs = {fr_doc["fr-docno"]: fr_doc for _, fr_doc in fr_doc_dataset.iterrows()}
+    # Each document's index is only loaded once, though it's in the group of every division it amended
+    vectorstores = {}
+    log = open(os.path.join(outdir, "group_analysis.txt"), "w")
+    t0 = time.time()
+    for i, ((titleno, partno, divname), docnos) in enumerate(groups.items()):
+        print(f"[*] Group analysis {i+1}/{len(groups)}: {titleno} CFR {divname}, {len(docnos)} FR documents {format_eta(t0, i, len(groups))}")
+        answers = {}
+        for question in questions:
+            prompt = question["prompt"].format(titleno=titleno, partno=partno, division=divname)
+            skip_reason = unmet_dependency(question, answers)
+            documents = []
+            omitted = []
+            try:
+                if skip_reason is not None:
+                    print(f"\t[*] Skip {question['id']}: {skip_reason}")
+                    answer, citations, err_msg, model = "SKIPPED", [], skip_reason, None
+                else:
+                    context_chars = 0
+                    for docno in reversed(docnos):
+                        if context_chars >= max_chars:
+                            omitted.append(docno)
+                            continue
+                        if docno not in vectorstores:
+                            rule_dir = fr_doc_dir(datadir, docno)
+                            vectorstores[docno] = VectorStoreIndex(os.path.join(rule_dir, "rule.html"), os.path.join(rule_dir, index_name()), outf=log)
+                        fr_doc = fr_docs[docno]
+                        for chunk in map(vectorstores[docno].chunk, vectorstores[docno].retrieve(prompt)):
+                            if context_chars + len(chunk["text"]) > max_chars:
+                                break
+                            documents.append({
+                                "title": f"{fr_doc['fr-doc-title']} ({format_fr_citation(fr_doc['fr-doc-citation'], fr_doc['fr-doc-publication-date'])}): {chunk['title']}",
+                                "text": chunk["text"],
+                            })
+                            context_chars += len(chunk["text"])
+                    rate_limit_check(len(question["preamble"]) + len(prompt) + context_chars)
+                    response, model = chat(preamble=question["preamble"], message=prompt, documents=documents)
+                    print(f"\n{'-'*100}\n{titleno} CFR {divname}, {question['id']}: {response.text}", file=log)
+                    answer, citations, err_msg = response.text, response.citations, ""
+            except Exception as e:
+                report_error("llm-error", f"{e}", cfr_title=titleno, cfr_part=partno, cfr_div=divname)
+                answer, citations, err_msg, model = "ERROR", [], f"{e}", None
+            if "answers" in question and answer not in ("ERROR", "SKIPPED") and not answer.strip().lower().startswith(tuple(question["answers"])):
+                report_error("llm-invalid-answer", f"The answer to {question['id']} doesn't start with {' or '.join(question['answers'])}", cfr_title=titleno, cfr_part=partno, cfr_div=divname, answer=answer)
+            answers[question["id"]] = answer
+
+            results["cfr-title"].append(titleno)
+            results["cfr-part"].append(partno)
+            results["cfr-div"].append(divname)
+            results["question-id"].append(question["id"])
+            results["fr-docnos"].append(docnos)
+            results["fr-docs-omitted"].append(omitted)
+            results["llm-prompt"].append(prompt)
+            results["llm-answer"].append(answer)
+            results["llm-citations"].append(citations)
+            results["llm-error"].append(err_msg)
+            results["llm-model"].append(model)
+    log.close()
+    return pd.DataFrame(results)
+
+
+def part_briefs(fr_doc_dataset, cfr_cov, outdir, max_docs=100):
+    '''
+    Have the LLM write a one-page narrative summary of each CFR Part in cfr_cov from the FR documents attributed to it: its
+    purpose, its major amendments over time, and its key outstanding requirements. The summaries are written as markdown
+    to outdir/briefs/title-X-part-Y.md. Only the max_docs most recent documents of a Part are given to the LLM.
+    '''
+    preamble = '''
+
+    ## Task & Context
+    You have been given the titles, dates, and abstracts of the Final Rule documents that created and amended one Part of the U.S. Code of Federal Regulations (CFR). A Final Rule document is a document published by a U.S. federal government agency that establishes or changes a regulation. You are being asked to write a one-page brief about this Part of the CFR for policy and legal experts.
+
+    ## Style Guide
+    Write in markdown. Use three sections: "Purpose", describing what the Part regulates and why; "History", describing its major amendments over time in chronological order; and "Key Requirements", describing the requirements it currently imposes. Only use information from the documents you were given.
+    '''
+    briefs_dir = os.path.join(outdir, "briefs")
+    os.makedirs(briefs_dir, exist_ok=True)
+    for _, part_cov in cfr_cov.iterrows():
+        titleno, partno = part_cov["cfr-title"], part_cov["cfr-part"]
+        print(f"[*] Writing brief of {titleno} CFR Part {partno}... ", end="", flush=True)
+        part_docs = fr_doc_dataset[fr_doc_dataset["fr-docno"].isin(part_cov["fr-docs-attributed"])]
+        part_docs = part_docs.sort_values("fr-doc-publication-date", ascending=False).head(max_docs)
+        if len(part_docs) == 0:
+            print("no FR documents.")
+            continue
+
+        documents = [
+            {
+                "title": f"{doc['fr-doc-title']} ({format_fr_citation(doc['fr-doc-citation'], doc['fr-doc-publication-date'])})",
+                "text": doc["fr-doc-abstract"] or "",
+            }
+            for _, doc in part_docs.iterrows()
+        ]
+        prompt = f"Write a brief of {titleno} CFR Part {partno}."
+        try:
+            rate_limit_check(len(preamble) + len(prompt) + sum(len(doc["title"]) + len(doc["text"]) for doc in documents))
+            response, model = chat(preamble=preamble, message=prompt, documents=documents)
+        except Exception as e:
+            report_error("llm-error", f"{e}", cfr_title=titleno, cfr_part=partno)
+            print("failed.")
+            continue
+
+        with open(os.path.join(briefs_dir, f"title-{titleno}-part-{partno}.md"), "w") as f:
+            f.write(f"# {titleno} CFR Part {partno}\n\n")
+            f.write(response.text)
+            f.write(f"\n\n_Written by {model}, based on {len(part_docs)} of the {len(part_cov['fr-docs-attributed'])} FR documents attributed to this Part:_\n\n")
+            for _, doc in part_docs.iterrows():
+                f.write(f"- {doc['fr-doc-title']}, {format_fr_citation(doc['fr-doc-citation'], doc['fr-doc-publication-date'])}\n")
+        print("done.")
+
+
+def load_canaries(path, questions):
+    '''
+    Load the canary set: FR documents whose answers were verified by hand, from a TOML file of [[canary]] tables, each with a
+    docno, the expected answer, and the id of the question it answers (by default the first question). E.g.,
+
+    [[canary]]
+    docno = "2023-12345"
+    question = "statutory-authority"
+    expected = "yes"
+    note = "Comments in section IV.B contest the EPA's authority under CAA section 111"
+
+    Canaries of questions that aren't in questions are left out with a warning.
+    '''
+    canaries = toml.load(path).get("canary", [])
+    question_ids = [q["id"] for q in questions]
+    checked = []
+    for canary in canaries:
+        if "docno" not in canary or "expected" not in canary:
+            raise ValueError(f"every canary in {path} needs a docno and an expected answer")
+        canary.setdefault("question", question_ids[0])
+        if canary["question"] not in question_ids:
+            print(f"[!] Canary {canary['docno']} is of question {canary['question']}, which isn't asked. Skipping.")
+            continue
+        checked.append(canary)
+    if len(checked) == 0:
+        raise ValueError(f"{path} has no canaries of the questions asked")
+    return checked
+
+
+def canary_check(canaries, datadir, questions):
+    '''
+    Ask questions of the canary documents, fetching them if needed, and compare the answers to the expected ones. An answer
+    is correct if it starts with the expected answer. The accuracy is appended to canary_history.jsonl in datadir, with the
+    hash of the questions asked, so changes in accuracy can be traced to the prompt and model changes that caused them.
+    Returns (accuracy, the accuracy of the previous check, or None, [the canaries answered incorrectly, with their answers]).
+    '''
+    print(f"[*] Checking the answers to {len(canaries)} canary documents...")
+    agency_abbrvs_by_name = agency_abbreviations(fr_agencies(datadir))
+    fr_docs = {}
+    for docno in sorted({canary["docno"] for canary in canaries}):
+        fr_doc = http_get(f"{FR_API_URL}/documents/{docno}.json?" + "&".join(f"fields[]={field}" for field in FR_DOC_FIELDS))
+        fr_doc.raise_for_status()
+        fr_doc = fr_doc.json()
+        fr_doc["agencies"] = fr_doc["agency_names"] or []
+        fr_doc["agency_shorthand"] = [agency_abbrvs_by_name.get(agency) or abbreviate_agency(agency) for agency in fr_doc["agencies"]]
+        fr_docs[docno] = ([], fr_doc)
+    skipped, _ = fetch_fr_docs(fr_docs, datadir)
+    skipped = [s[1]["document_number"] for s in skipped]
+    if len(skipped) > 0:
+        raise RuntimeError(f"couldn't fetch canary documents {', '.join(skipped)}")
+
+    dataset = pd.DataFrame({
+        "fr-docno": list(fr_docs),
+        "fr-doc-agencies": [fr_doc["agencies"] for _, fr_doc in fr_docs.values()],
+        "fr-doc-agencies-shorthand": [fr_doc["agency_shorthand"] for _, fr_doc in fr_docs.values()],
+    })
+    answers = llm_analysis(dataset, datadir, questions).set_index("fr-docno")
+    failures = []
+    for canary in canaries:
+        column = "llm-answer" if len(questions) == 1 else f"llm-answer-{canary['question']}"
+        answer = answers.loc[canary["docno"], column]
+        if not answer.strip().lower().startswith(canary["expected"].lower()):
+            failures.append({**canary, "answer": answer})
+    accuracy = 1 - len(failures) / len(canaries)
+
+    history_path = os.path.join(datadir, "canary_history.jsonl")
+    previous = None
+    if os.path.exists(history_path):
+        with open(history_path, "r") as f:
+            lines = [line for line in f if line.strip() != ""]
+        if len(lines) > 0:
+            previous = json.loads(lines[-1])["accuracy"]
+    questions_hash = hashlib.sha256(json.dumps(questions, sort_keys=True).encode()).hexdigest()[:12]
+    with open(history_path, "a") as f:
+        f.write(json.dumps({
+            "date": datetime.datetime.now().isoformat(timespec="seconds"),
+            "questions-sha256": questions_hash,
+            "canaries": len(canaries),
+            "accuracy": accuracy,
+            "failures": [(failure["docno"], failure["question"]) for failure in failures],
+        }) + "\n")
+    return accuracy, previous, failures
@@ -0,0 +1,900 @@
+import concurrent.futures
+import csv
+from cfr import DIVISION_LEVELS, division_status, extract_part_info, normalize_text, prefetch_structures
+import datetime
+import difflib
+from frapi import CFR_TITLES, ECFR_DATE, ECFR_SNAPSHOT_DATE, ECFR_GRAPHICS_URL, FR_API_URL, FR_DOC_SEARCH_CACHES, FR_SEARCH_FIRST_VOLUME, download_graphic, fr_agencies, http_get_all_pages, parse_date, search_court_cases
+import json
+import lxml.etree as ET
+import os
+import pandas as pd
+from pipeline import amendatory_instructions, division_roles, preamble_section
+import re
+from report import report_error
+from store import fr_doc_dir, write_atomic
+import toml
+
+############################################
+# Functions for building the result tables #
+############################################
+
+def write_raw_json(fr_doc_data, datadir, outdir):
+    '''
+    Write the raw FederalRegister.gov JSON of each FR document in the results, i.e. its search result and, for runs with
+    --enrich, its single-document API response, to outdir/fr_docs_raw.jsonl, one document per line.
+    '''
+    with open(os.path.join(outdir, "fr_docs_raw.jsonl"), "w") as outf:
+        for docno in fr_doc_data["fr-docno"]:
+            raw = {"fr-docno": docno}
+            for key, name in [("search-result", "search_result.json"), ("document", "document.json")]:
+                try:
+                    with open(os.path.join(fr_doc_dir(datadir, docno), name), "r") as f:
+                        raw[key] = json.load(f)
+                except FileNotFoundError:
+                    raw[key] = None
+            outf.write(json.dumps(raw) + "\n")
+
+
+def write_run_manifest(outdir, manifest):
+    '''
+    Write what's needed to reproduce a run, e.g. its arguments and seed, to outdir/run.json.
+    '''
+    write_atomic(os.path.join(outdir, "run.json"), json.dumps(manifest, indent=2, default=str))
+
+
+def search_changes_table(cfr_cov, datadir):
+    '''
+    Collect the changes found by the FR searches of each Part refreshed with fr_docs_for_part(refresh=True) into one table
+    of (Part, search, FR docno, change, changed fields).
+    '''
+    rows = []
+    for _, part_cov in cfr_cov.iterrows():
+        titleno, partno = part_cov["cfr-title"], part_cov["cfr-part"]
+        for doc_type, cache_name in FR_DOC_SEARCH_CACHES.items():
+            changes_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", cache_name.replace(".json", ".changes.json"))
+            try:
+                with open(changes_path, "r") as f:
+                    changes = json.load(f)
+            except FileNotFoundError:
+                continue
+            rows.extend({"cfr-title": titleno, "cfr-part": partno, "fr-doc-type": doc_type, **change} for change in changes)
+    return pd.DataFrame(rows, columns=["cfr-title", "cfr-part", "fr-doc-type", "fr-docno", "change", "changed-fields"])
+
+
+def topic_tables(fr_doc_data, cfr_cov):
+    '''
+    Tabulate the FederalRegister.gov topics of the FR documents, which are only known for runs with --enrich. Returns a
+    table of (FR docno, topic) and a table of how many of the FR documents attributed to each CFR Part have each topic.
+    These give a free, non-LLM categorization of the rules.
+    '''
+    doc_topics = fr_doc_data[["fr-docno", "fr-doc-topics"]].explode("fr-doc-topics").dropna()
+    doc_topics = doc_topics.rename(columns={"fr-doc-topics": "topic"}).reset_index(drop=True)
+
+    part_docs = cfr_cov[["cfr-title", "cfr-part", "fr-docs-attributed"]].explode("fr-docs-attributed")
+    part_docs = part_docs.rename(columns={"fr-docs-attributed": "fr-docno"})
+    part_topics = part_docs.merge(doc_topics, on="fr-docno")
+    part_topics = part_topics.groupby(["cfr-title", "cfr-part", "topic"]).size().reset_index(name="fr-doc-count")
+    part_topics = part_topics.sort_values(["cfr-title", "cfr-part", "fr-doc-count"], ascending=[True, True, False], ignore_index=True)
+    return doc_topics, part_topics
+
+
+def agencies_table(fr_doc_data, datadir):
+    '''
+    Describe each agency issuing the FR documents from the FederalRegister.gov agencies list (see fr_agencies): its short
+    name, parent agency, FederalRegister.gov page, description, and logo, with how many of the FR documents it issued.
+    Agencies that aren't in the list only have their name and count.
+    '''
+    agency_info = {}
+    for info in fr_agencies(datadir):
+        if info.get("name") and info["name"] not in agency_info:
+            agency_info[info["name"]] = info
+    parent_names = {info.get("id"): name for name, info in agency_info.items()}
+    doc_counts = fr_doc_data["fr-doc-agencies"].explode().dropna().value_counts()
+    rows = []
+    for agency, count in doc_counts.items():
+        info = agency_info.get(agency, {})
+        rows.append({
+            "agency": agency,
+            "agency-short-name": info.get("short_name"),
+            "agency-parent": parent_names.get(info.get("parent_id")),
+            "agency-url": info.get("url"),
+            "agency-description": info.get("description"),
+            "agency-logo-url": (info.get("logo") or {}).get("medium_url"),
+            "fr-doc-count": count,
+        })
+    return pd.DataFrame(rows, columns=["agency", "agency-short-name", "agency-parent", "agency-url", "agency-description", "agency-logo-url", "fr-doc-count"])
+
+
+def part_answers_table(fr_doc_analysis, cfr_cov, questions):
+    '''
+    Tabulate the answers to each question of the FR documents attributed to each CFR Part, e.g. what share of the rules of a
+    Part impose a reporting requirement. Answers are counted by which of the question's answers they start with, if it has
+    them, or else as they are, and documents the LLM didn't analyze aren't counted. Returns a table with a row per Part,
+    question, and answer, listing the documents with that answer.
+    '''
+    part_docs = cfr_cov[["cfr-title", "cfr-part", "fr-docs-attributed"]].explode("fr-docs-attributed").rename(columns={"fr-docs-attributed": "fr-docno"})
+    rows = []
+    for question in questions:
+        answer_col = "llm-answer" if len(questions) == 1 else f"llm-answer-{question['id']}"
+        if answer_col not in fr_doc_analysis.columns:
+            continue
+        answers = fr_doc_analysis[["fr-docno", answer_col]].dropna()
+        def answer_of(answer):
+            for option in question.get("answers", []):
+                if answer.strip().lower().startswith(option):
+                    return option
+            return answer.strip()
+        answers = answers.assign(answer=answers[answer_col].map(answer_of))
+        counts = part_docs.merge(answers[["fr-docno", "answer"]], on="fr-docno")
+        for (titleno, partno), part_answers in counts.groupby(["cfr-title", "cfr-part"], sort=False):
+            for answer, docs in part_answers.groupby("answer"):
+                rows.append({
+                    "cfr-title": titleno,
+                    "cfr-part": partno,
+                    "question-id": question["id"],
+                    "answer": answer,
+                    "fr-doc-count": len(docs),
+                    "fr-doc-share": len(docs) / len(part_answers),
+                    "fr-docnos": list(docs["fr-docno"]),
+                })
+    return pd.DataFrame(rows, columns=["cfr-title", "cfr-part", "question-id", "answer", "fr-doc-count", "fr-doc-share", "fr-docnos"])
+
+
+def cfr_divisions_table(cfr_cov):
+    '''
+    Tabulate the hierarchy of each CFR division citing the FR, from its Chapter down to its section or appendix, with the
+    FR documents its citations are attributed to, so the results can be rolled up at any level of the CFR. Levels a
+    division isn't in, e.g. the Subpart of a Part without Subparts, are empty. Whether its text is operative, e.g. not
+    suspended by a note, is in cfr-division-operative-status (see operative_status), and the FR citations in it, which
+    the FR documents are attributed from, in fr-citations.
+    '''
+    rows = []
+    for _, part in cfr_cov.iterrows():
+        for name, divty, path, docnos in part["cfr-div-hierarchy"]:
+            rows.append({"cfr-title": part["cfr-title"], **path, "cfr-division": name, "cfr-division-type": divty, "fr-docnos": docnos})
+    return pd.DataFrame(rows, columns=["cfr-title", *DIVISION_LEVELS.values(), "cfr-division", "cfr-division-type", "cfr-division-operative-status", "cfr-division-word-count", "fr-citations", "fr-docnos"])
+
+
+def division_key(name, divty):
+    '''
+    The key of a CFR division in the division_roles of an FR document, e.g. ("section", "63.14") for 40 CFR 63.14, or None
+    for divisions that amendatory instructions don't name on their own, e.g. Parts and subject groups.
+    '''
+    name = normalize_text(name)
+    if divty == "SECTION":
+        return ("section", name.lstrip("§ "))
+    if divty == "SUBPART":
+        match = re.search(r"([A-Z]{1,5})$", name)
+        return ("subpart", match[1]) if match else None
+    if divty == "APPENDIX":
+        match = re.search(r"Appendix\s+([A-Z0-9]{1,3})\b", name, re.IGNORECASE)
+        return ("appendix", match[1]) if match else None
+    return None
+
+
+def division_timeline_table(fr_doc_data, cfr_cov, datadir):
+    '''
+    Tabulate the history of each CFR division citing the FR: the FR documents attributed to it in order of publication, each
+    with its role, parsed from its amendatory instructions (see division_roles): "original promulgation", "amendment",
+    "removal", or, for documents titled as corrections, "correction". A document whose instructions don't name the division,
+    e.g. one amending a section of a cited Subpart, is an "amendment", and one whose text couldn't be read has no role.
+    '''
+    docs = fr_doc_data.drop_duplicates(subset="fr-docno").set_index("fr-docno")
+    roles_by_doc = {}
+    def roles_of(docno):
+        if docno not in roles_by_doc:
+            try:
+                with open(os.path.join(fr_doc_dir(datadir, docno), "rule.html"), "rb") as f:
+                    root = ET.HTML(f.read())
+                roles_by_doc[docno] = division_roles(amendatory_instructions(root)) if root is not None else None
+            except (OSError, ValueError) as e:
+                report_error("parse-warning", f"Couldn't parse the amendatory instructions: {e}", docno=docno)
+                roles_by_doc[docno] = None
+        return roles_by_doc[docno]
+
+    rows = []
+    for _, part in cfr_cov.iterrows():
+        for name, divty, _, docnos in part["cfr-div-hierarchy"]:
+            for docno in docnos:
+                # Documents that couldn't be fetched aren't in the FR doc data
+                doc = docs.loc[docno] if docno in docs.index else None
+                roles = roles_of(docno) if doc is not None else None
+                if doc is not None and re.search(r"\bcorrect(ion|ions|ing)\b", str(doc["fr-doc-title"]), re.IGNORECASE):
+                    role = "correction"
+                elif roles is None:
+                    role = None
+                else:
+                    role = roles.get(division_key(name, divty), "amendment")
+                rows.append({
+                    "cfr-title": part["cfr-title"],
+                    "cfr-part": part["cfr-part"],
+                    "cfr-division": name,
+                    "cfr-division-type": divty,
+                    "fr-docno": docno,
+                    "fr-doc-citation": doc["fr-doc-citation"] if doc is not None else None,
+                    "fr-doc-publication-date": doc["fr-doc-publication-date"] if doc is not None else None,
+                    "cfr-division-role": role,
+                })
+    table = pd.DataFrame(rows, columns=["cfr-title", "cfr-part", "cfr-division", "cfr-division-type", "fr-docno", "fr-doc-citation", "fr-doc-publication-date", "cfr-division-role"])
+    return table.sort_values(["cfr-title", "cfr-part", "cfr-division", "fr-doc-publication-date"], kind="stable", ignore_index=True)
+
+
+GOVINFO_FR_COLLECTION_URL = "https://www.govinfo.gov/app/collection/fr"
+
+
+def coverage_gaps_table(cfr_cov):
+    '''
+    Diagnose the CFR Parts whose divisions cite the FR but whose FR searches returned no documents at all, so none of their
+    citations could be attributed. This usually means the Part's rules predate 1994, which the search doesn't cover, or
+    that FederalRegister.gov didn't index them under the Part. The likely-cause column is "pre-1994" if every citation is of
+    a volume before FR_SEARCH_FIRST_VOLUME, else "search-gap". Their documents can be looked up in GovInfo's Federal
+    Register collection instead, which --historical does for the citations from before 1994. Parts that failed aren't gaps.
+    '''
+    rows = []
+    for _, part in cfr_cov[cfr_cov["attribution-error"].fillna("") == ""].iterrows():
+        if len(part["fr-citations"]) == 0 or len(part["fr-docs-affecting"]) > 0:
+            continue
+        volumes = [int(fr_cita.split(" ")[0]) for fr_cita in part["fr-citations"] if fr_cita.split(" ")[0].isdigit()]
+        pre_1994 = len([volume for volume in volumes if volume < FR_SEARCH_FIRST_VOLUME])
+        rows.append({
+            "cfr-title": part["cfr-title"],
+            "cfr-part": part["cfr-part"],
+            "fr-citation-count": len(part["fr-citations"]),
+            "fr-citations-pre-1994": pre_1994,
+            "cfr-divisions-citing-fr": len(part["cfr-div-hierarchy"]),
+            "likely-cause": "pre-1994" if pre_1994 == len(part["fr-citations"]) else "search-gap",
+            "suggestion": "Re-run with --historical to fetch the citations from before 1994 from GovInfo" if pre_1994 > 0 else f"Look up the citations in GovInfo's Federal Register collection, {GOVINFO_FR_COLLECTION_URL}",
+        })
+    return pd.DataFrame(rows, columns=["cfr-title", "cfr-part", "fr-citation-count", "fr-citations-pre-1994", "cfr-divisions-citing-fr", "likely-cause", "suggestion"])
+
+
+def coverage_summary_table(cfr_cov, datadir):
+    '''
+    Roll up the coverage of the CFR Parts of a run of several Titles: the share of the Parts that aren't reserved that were
+    analyzed, the share of their FR citations attributed to an FR document, and the words in force of the divisions citing
+    the FR that were attributed. Returns a table with a row for the whole CFR, the headline number, then a row per Title and
+    Chapter analyzed. The structure of every Title is fetched, if it isn't cached, to count the Parts of the CFR.
+    '''
+    titlenos = [titleno for titleno in CFR_TITLES if titleno != "35"]
+    prefetch_structures(titlenos, datadir)
+    cfr_parts = []
+    for titleno in titlenos:
+        try:
+            cfr_parts.extend((titleno, part.get("chapter")) for _, part in extract_part_info(titleno, "title", titleno, datadir))
+        except Exception as e:
+            report_error("coverage-summary-failed", f"{type(e).__name__}: {e}", cfr_title=titleno)
+            cfr_parts = None
+            break
+    parts = pd.DataFrame(cfr_parts if cfr_parts is not None else [], columns=["cfr-title", "cfr-chapter"])
+    analyzed = cfr_cov[cfr_cov["attribution-error"].fillna("") == ""]
+    analyzed = analyzed.assign(**{
+        "fr-citation-count": analyzed["fr-citations"].map(len),
+        "fr-citation-attributed-count": analyzed["fr-cita-attributions"].map(lambda attributions : len({attribution[0] for attribution in attributions})),
+    })
+
+    def coverage(level, part_count, rows, **ids):
+        citation_count = rows["fr-citation-count"].sum()
+        words_citing = rows["cfr-words-citing-fr"].fillna(0).sum()
+        words_attributed = rows["cfr-words-attributed"].fillna(0).sum()
+        return {
+            "level": level,
+            "cfr-title": None,
+            "cfr-chapter": None,
+            **ids,
+            "cfr-parts": part_count,
+            "cfr-parts-analyzed": len(rows),
+            "cfr-parts-share-analyzed": len(rows) / part_count if part_count else None,
+            "fr-citations": citation_count,
+            "fr-citations-attributed": rows["fr-citation-attributed-count"].sum(),
+            "fr-citations-share-attributed": rows["fr-citation-attributed-count"].sum() / citation_count if citation_count else None,
+            "cfr-words-citing-fr": words_citing,
+            "cfr-words-attributed": words_attributed,
+            "cfr-words-share-attributed": words_attributed / words_citing if words_citing else None,
+        }
+
+    # Parts are unknown if any Title's structure couldn't be fetched
+    part_count = len(parts) if cfr_parts is not None else None
+    summary = [coverage("cfr", part_count, analyzed)]
+    for titleno, title_rows in analyzed.groupby("cfr-title", sort=False):
+        part_count = (parts["cfr-title"] == titleno).sum() if cfr_parts is not None else None
+        summary.append(coverage("title", part_count, title_rows, **{"cfr-title": titleno}))
+    for (titleno, chapter), chapter_rows in analyzed.groupby(["cfr-title", "cfr-chapter"], sort=False):
+        part_count = ((parts["cfr-title"] == titleno) & (parts["cfr-chapter"] == chapter)).sum() if cfr_parts is not None else None
+        summary.append(coverage("chapter", part_count, chapter_rows, **{"cfr-title": titleno, "cfr-chapter": chapter}))
+    return pd.DataFrame(summary)
+
+
+def llm_stats_tables(fr_doc_analysis, questions, max_docs=20):
+    '''
+    Break the LLM analysis's tokens and latency down by question, and list the max_docs slowest FR documents with the likely
+    reasons: "many tables", "many chunks", "long document", or "images", when the document is in the top tenth of the run
+    by that measure, and "errors" or "failover" from its LLM columns. These guide the tuning of prompts and chunking. Returns
+    (a table with a row per question, a table with a row per slow document).
+    '''
+    def column(name, question):
+        return name if len(questions) == 1 else f"{name}-{question['id']}"
+
+    rows = []
+    for question in questions:
+        if column("llm-seconds", question) not in fr_doc_analysis.columns:
+            continue
+        asked = fr_doc_analysis[~fr_doc_analysis[column("llm-answer", question)].isin(["SKIPPED"]) & fr_doc_analysis[column("llm-answer", question)].notna()]
+        seconds = asked[column("llm-seconds", question)]
+        rows.append({
+            "question-id": question["id"],
+            "fr-doc-count": len(asked),
+            "llm-errors": (asked[column("llm-answer", question)] == "ERROR").sum(),
+            "llm-input-tokens": asked[column("llm-input-tokens", question)].sum(),
+            "llm-output-tokens": asked[column("llm-output-tokens", question)].sum(),
+            "llm-input-tokens-mean": asked[column("llm-input-tokens", question)].mean(),
+            "llm-seconds": seconds.sum(),
+            "llm-seconds-median": seconds.median(),
+            "llm-seconds-p95": seconds.quantile(0.95),
+        })
+    question_stats = pd.DataFrame(rows, columns=[
+        "question-id", "fr-doc-count", "llm-errors", "llm-input-tokens", "llm-output-tokens", "llm-input-tokens-mean",
+        "llm-seconds", "llm-seconds-median", "llm-seconds-p95",
+    ])
+
+    asked_questions = [question for question in questions if column("llm-seconds", question) in fr_doc_analysis.columns]
+    docs = fr_doc_analysis[fr_doc_analysis[[column("llm-answer", question) for question in asked_questions]].notna().any(axis=1)] if len(asked_questions) > 0 else fr_doc_analysis.iloc[0:0]
+    docs = docs.assign(**{
+        "llm-seconds": sum(docs[column("llm-seconds", question)].fillna(0) for question in asked_questions),
+        "llm-input-tokens": sum(docs[column("llm-input-tokens", question)].fillna(0) for question in asked_questions),
+        "llm-chunk-count": sum(docs[column("llm-chunks-used", question)].map(lambda chunks : len(chunks) if isinstance(chunks, list) else 0) for question in asked_questions),
+    })
+    measures = {"many tables": "fr-doc-table-density", "many chunks": "llm-chunk-count", "long document": "fr-doc-word-len", "images": "fr-doc-image-count"}
+    top_tenths = {reason: docs[measure].quantile(0.9) for reason, measure in measures.items() if measure in docs.columns and len(docs) > 0}
+
+    def reasons(doc):
+        found = [reason for reason, top_tenth in top_tenths.items() if pd.notna(doc[measures[reason]]) and doc[measures[reason]] > 0 and doc[measures[reason]] >= top_tenth]
+        if any(doc[column("llm-answer", question)] == "ERROR" for question in asked_questions):
+            found.append("errors")
+        if len({doc[column("llm-model", question)] for question in asked_questions} - {None}) > 1:
+            found.append("failover")
+        return found
+
+    slow_docs = docs.sort_values("llm-seconds", ascending=False).head(max_docs)
+    slow_docs = slow_docs.assign(reasons=[reasons(doc) for _, doc in slow_docs.iterrows()])
+    slow_columns = ["fr-docno", "llm-seconds", "llm-input-tokens", "llm-chunk-count", *[measure for measure in measures.values() if measure in slow_docs.columns and measure != "llm-chunk-count"], "reasons"]
+    return question_stats, slow_docs[slow_columns].reset_index(drop=True)
+
+
+# eCFR elements whose content is an image, or math the text extraction can't render
+ECFR_GRAPHIC_TAGS = {"GPH": "graphic", "MATH": "math"}
+
+
+def graphics_table(fr_doc_data, cfr_cov, datadir, download=False):
+    '''
+    List the content of the analyzed CFR Parts and FR documents that is in images or math rather than text, which the LLM
+    can't read: GPH and MATH elements in the cached eCFR XML, by division, and <img> elements in the FR document HTML. With
+    download, the images are also saved under a graphics directory of each Part or FR document.
+    '''
+    rows = []
+    for _, part_cov in cfr_cov.iterrows():
+        titleno, partno = part_cov["cfr-title"], part_cov["cfr-part"]
+        part_dir = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}")
+        try:
+            full_xml = ET.parse(os.path.join(part_dir, "part.xml"))
+        except (OSError, ET.XMLSyntaxError):
+            continue
+        for elem in full_xml.iter(*ECFR_GRAPHIC_TAGS):
+            div = next((a for a in elem.iterancestors() if a.tag.startswith("DIV")), None)
+            gid = (elem.findtext("GID") or "").strip()
+            rows.append({
+                "source": "ecfr",
+                "cfr-title": titleno,
+                "cfr-part": partno,
+                "fr-docno": None,
+                "division": div.attrib.get("N") if div is not None else None,
+                "div-type": div.attrib.get("TYPE") if div is not None else None,
+                "kind": ECFR_GRAPHIC_TAGS[elem.tag],
+                "graphic-id": gid or None,
+                # The eCFR serves graphics by their lower-cased GID
+                "url": f"{ECFR_GRAPHICS_URL}/{gid.lower()}.gif" if gid else None,
+                "path": None,
+            })
+            if download and gid:
+                rows[-1]["path"] = download_graphic(rows[-1]["url"], os.path.join(part_dir, "graphics"))
+
+    for _, fr_doc in fr_doc_data.iterrows():
+        if pd.isna(fr_doc["fr-doc-image-count"]) or fr_doc["fr-doc-image-count"] == 0:
+            continue
+        document_dir = fr_doc_dir(datadir, fr_doc["fr-docno"])
+        with open(os.path.join(document_dir, "rule.html"), "rb") as f:
+            root = ET.HTML(f.read())
+        for img in root.iter("img"):
+            url = img.attrib.get("src")
+            rows.append({
+                "source": "fr",
+                "cfr-title": None,
+                "cfr-part": None,
+                "fr-docno": fr_doc["fr-docno"],
+                "division": None,
+                "div-type": None,
+                "kind": "image",
+                "graphic-id": img.attrib.get("alt"),
+                "url": url,
+                "path": None,
+            })
+            if download and url and url.startswith("http"):
+                rows[-1]["path"] = download_graphic(url, os.path.join(document_dir, "graphics"))
+
+    columns = ["source", "cfr-title", "cfr-part", "fr-docno", "division", "div-type", "kind", "graphic-id", "url", "path"]
+    return pd.DataFrame(rows, columns=columns)
+
+
+omb_control_number_regex = r"\b(\d{4}-\d{4})\b"
+omb_context_regex = r"\b(OMB|Office of Management and Budget|control numbers?|Paperwork Reduction Act)\b"
+
+
+def omb_control_numbers(text):
+    '''
+    Returns the OMB control numbers of information collections, e.g. "2060-0336", in sentences of text that mention the OMB
+    or the Paperwork Reduction Act, so that other ####-#### numbers aren't picked up.
+    '''
+    numbers = []
+    for sentence in re.split(r"(?<=[.;])\s+", text):
+        if re.search(omb_context_regex, sentence):
+            numbers.extend(num for num in re.findall(omb_control_number_regex, sentence) if num not in numbers)
+    return numbers
+
+
+def omb_control_number_table(fr_doc_data, cfr_cov, datadir):
+    '''
+    Map each analyzed CFR Part to the OMB control numbers of the information collections, i.e. reporting and recordkeeping
+    requirements under the Paperwork Reduction Act, that it hooks into. The numbers are taken from the sections of the Part
+    in the cached eCFR XML, including its OMB listing sections, and from the Paperwork Reduction Act statements of the FR
+    documents attributed to the Part. Returns one row per (Part, source, control number).
+    '''
+    rows = []
+    for _, part_cov in cfr_cov.iterrows():
+        titleno, partno = part_cov["cfr-title"], part_cov["cfr-part"]
+        part_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", "part.xml")
+        try:
+            full_xml = ET.parse(part_path)
+        except (OSError, ET.XMLSyntaxError):
+            continue
+        for div in full_xml.iter("DIV8"):
+            for number in omb_control_numbers(" ".join(" ".join(div.itertext()).split())):
+                rows.append({
+                    "cfr-title": titleno,
+                    "cfr-part": partno,
+                    "source": "ecfr",
+                    "division": div.attrib.get("N"),
+                    "fr-docno": None,
+                    "omb-control-number": number,
+                })
+
+    part_docs = cfr_cov[["cfr-title", "cfr-part", "fr-docs-attributed"]].explode("fr-docs-attributed").dropna()
+    for _, fr_doc in fr_doc_data.iterrows():
+        try:
+            with open(os.path.join(fr_doc_dir(datadir, fr_doc["fr-docno"]), "rule.html"), "rb") as f:
+                root = ET.HTML(f.read())
+        except OSError:
+            continue
+        if root is None:
+            continue
+        numbers = omb_control_numbers(preamble_section(root, r"Paperwork Reduction"))
+        for _, part in part_docs[part_docs["fr-docs-attributed"] == fr_doc["fr-docno"]].iterrows():
+            for number in numbers:
+                rows.append({
+                    "cfr-title": part["cfr-title"],
+                    "cfr-part": part["cfr-part"],
+                    "source": "fr",
+                    "division": None,
+                    "fr-docno": fr_doc["fr-docno"],
+                    "omb-control-number": number,
+                })
+
+    columns = ["cfr-title", "cfr-part", "source", "division", "fr-docno", "omb-control-number"]
+    return pd.DataFrame(rows, columns=columns)
+
+
+def court_cases(fr_doc_data, cfr_cov, datadir):
+    '''
+    Find court cases citing each analyzed CFR Part, and each significant FR document, with CourtListener. Returns a table of
+    (what was cited, the citing case). Only the most recent page of cases is listed per citation, but total-cases counts
+    them all. Litigation exposure is a key input to deregulation analysis.
+    '''
+    rows = []
+    def add_cases(cited, count, cases, **ids):
+        for case in cases:
+            rows.append({
+                "cited": cited,
+                **ids,
+                "total-cases": count,
+                "case-name": case.get("caseName"),
+                "court": case.get("court"),
+                "date-filed": case.get("dateFiled"),
+                "docket-number": case.get("docketNumber"),
+                "url": f"https://www.courtlistener.com{case['absolute_url']}" if case.get("absolute_url") else None,
+            })
+
+    for _, part_cov in cfr_cov.iterrows():
+        titleno, partno = part_cov["cfr-title"], part_cov["cfr-part"]
+        print(f"[*] Searching court cases citing {titleno} CFR Part {partno}... ", end="", flush=True)
+        part_dir = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}")
+        query = f'"{titleno} C.F.R. Part {partno}" OR "{titleno} CFR Part {partno}" OR "{titleno} C.F.R. pt. {partno}"'
+        try:
+            count, cases = search_court_cases(query, os.path.join(part_dir, "court_cases.json"))
+        except Exception as e:
+            report_error("fetch-failed", f"CourtListener search: {e}", cfr_title=titleno, cfr_part=partno)
+            print("failed.")
+            continue
+        add_cases(f"{titleno} CFR Part {partno}", count, cases, **{"cfr-title": titleno, "cfr-part": partno, "fr-docno": None})
+        print(f"{count} cases.")
+
+    significant = fr_doc_data[fr_doc_data["fr-doc-significant"] == True]
+    print(f"[*] Searching court cases citing {len(significant)} significant FR documents...")
+    for _, fr_doc in significant.iterrows():
+        # Courts cite the Federal Register as "85 Fed. Reg. 12345"
+        match = re.match(r"(\d+) FR (\d+)", fr_doc["fr-doc-citation"] or "")
+        if match is None:
+            continue
+        query = f'"{match[1]} Fed. Reg. {match[2]}" OR "{match[1]} FR {match[2]}"'
+        try:
+            count, cases = search_court_cases(query, os.path.join(fr_doc_dir(datadir, fr_doc["fr-docno"]), "court_cases.json"))
+        except Exception as e:
+            report_error("fetch-failed", f"CourtListener search: {e}", docno=fr_doc["fr-docno"])
+            continue
+        add_cases(fr_doc["fr-doc-citation"], count, cases, **{"cfr-title": None, "cfr-part": None, "fr-docno": fr_doc["fr-docno"]})
+
+    columns = ["cited", "cfr-title", "cfr-part", "fr-docno", "total-cases", "case-name", "court", "date-filed", "docket-number", "url"]
+    return pd.DataFrame(rows, columns=columns)
+
+
+def rule_section_text(root, section):
+    '''
+    The regulatory text an FR document gives for a CFR section, e.g. "63.5", from its parsed HTML: the paragraphs from the last
+    one starting with "§ 63.5", which is in the regulatory text at the end of the document, up to the next section or Part.
+    Returns "" if no paragraph starts with the section.
+    '''
+    blocks = [" ".join(" ".join(elem.itertext()).split()) for elem in root.iter("p", "h1", "h2", "h3", "h4", "h5", "h6")]
+    section_regex = re.compile(rf"§\s*{re.escape(section)}(?![\d.])")
+    starts = [i for i, block in enumerate(blocks) if section_regex.match(block)]
+    if len(starts) == 0:
+        return ""
+    texts = [blocks[starts[-1]]]
+    for block in blocks[starts[-1] + 1:]:
+        if re.match(r"(§|PART\b|\[FR Doc)", block):
+            break
+        texts.append(block)
+    return " ".join(texts)
+
+
+# The elements of an eCFR section that aren't its regulatory text
+ECFR_SECTION_NOTES = {"CITA", "EDNOTE", "SOURCE", "AUTH"}
+# The share of the words of a rule's text of a section that must still be in the section for the text to survive
+TEXT_SURVIVAL_THRESHOLD = 0.8
+
+
+def text_survival_table(cfr_cov, datadir, threshold=TEXT_SURVIVAL_THRESHOLD):
+    '''
+    For each CFR section attributed to an FR document, diff the text the document gave the section against the section's
+    eCFR text at the snapshot, to say more directly than the citation whether the rule is still operative. The status is
+    "survives" if at least threshold of the rule's words are still in the section, in order, "superseded" if fewer are,
+    "removed" if the section is gone or reserved, and "unknown" if the rule's text of the section wasn't found, e.g. because
+    it only amended other sections. Elided paragraphs, "* * *", aren't counted. Returns a table with a row per document
+    and section.
+    '''
+    rows = []
+    rule_roots = {}
+    for _, part_cov in cfr_cov.iterrows():
+        titleno, partno = part_cov["cfr-title"], part_cov["cfr-part"]
+        part_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", "part.xml")
+        try:
+            part_xml = ET.parse(part_path)
+        except (OSError, ET.XMLSyntaxError) as e:
+            report_error("text-diff-failed", f"{type(e).__name__}: {e}", cfr_title=titleno, cfr_part=partno)
+            continue
+        sections = {normalize_text(div.attrib.get("N")): div for div in part_xml.iter("DIV8") if div.attrib.get("TYPE") == "SECTION"}
+        for name, divty, _, docnos in part_cov["cfr-div-hierarchy"]:
+            if divty != "SECTION":
+                continue
+            div = sections.get(name)
+            ecfr_words = []
+            if div is not None and division_status(div) == "":
+                ecfr_words = " ".join(" ".join(elem.itertext()) for elem in div if elem.tag not in ECFR_SECTION_NOTES).split()
+            for docno in docnos:
+                if docno not in rule_roots:
+                    try:
+                        with open(os.path.join(fr_doc_dir(datadir, docno), "rule.html"), "rb") as f:
+                            rule_roots[docno] = ET.HTML(f.read())
+                    except OSError:
+                        rule_roots[docno] = None
+                root = rule_roots[docno]
+                rule_words = [word for word in (rule_section_text(root, name.replace("§", "").strip()) if root is not None else "").split() if word != "*"]
+                surviving = None
+                if len(rule_words) == 0:
+                    status = "unknown"
+                elif len(ecfr_words) == 0:
+                    status = "removed"
+                else:
+                    matcher = difflib.SequenceMatcher(None, rule_words, ecfr_words, autojunk=False)
+                    surviving = sum(block.size for block in matcher.get_matching_blocks()) / len(rule_words)
+                    status = "survives" if surviving >= threshold else "superseded"
+                rows.append({
+                    "fr-docno": docno,
+                    "cfr-title": titleno,
+                    "cfr-part": partno,
+                    "cfr-section": name,
+                    "rule-words": len(rule_words),
+                    "ecfr-words": len(ecfr_words),
+                    "surviving-share": surviving,
+                    "text-status": status,
+                })
+    return pd.DataFrame(rows, columns=["fr-docno", "cfr-title", "cfr-part", "cfr-section", "rule-words", "ecfr-words", "surviving-share", "text-status"])
+
+
+def pending_fr_docs(fr_doc_data, cfr_cov):
+    '''
+    Query FederalRegister.gov's public inspection desk for Rules that are filed but not yet published. Public inspection
+    documents don't list the CFR Parts they affect, so a pending Rule is matched to the analyzed Parts whose attributed
+    FR documents were issued by one of its agencies. Returns a DataFrame of the matching pending Rules.
+    '''
+    print("[*] Searching the public inspection desk for pending Rules... ", end="")
+    pi_docs = http_get_all_pages(f"{FR_API_URL}/public-inspection-documents/current.json").get("results", [])
+
+    agencies_of_docs = dict(zip(fr_doc_data["fr-docno"], fr_doc_data["fr-doc-agencies"]))
+    parts_by_agency = {}
+    for _, part_cov in cfr_cov.iterrows():
+        for docno in part_cov["fr-docs-attributed"]:
+            for agency in agencies_of_docs.get(docno, []):
+                parts_by_agency.setdefault(agency, set()).add(f"{part_cov['cfr-title']} CFR {part_cov['cfr-part']}")
+
+    pending = {
+        "fr-docno": [],
+        "fr-doc-title": [],
+        "fr-doc-agencies": [],
+        "fr-doc-filed-at": [],
+        "fr-doc-publication-date": [],
+        "fr-doc-pdf-url": [],
+        "cfr-parts-possibly-affected": [],
+    }
+    for pi_doc in pi_docs:
+        if pi_doc.get("type") != "Rule":
+            continue
+        agencies = [agency.get("name") for agency in pi_doc.get("agencies", [])]
+        parts = set()
+        for agency in agencies:
+            parts.update(parts_by_agency.get(agency, set()))
+        if len(parts) == 0:
+            continue
+        pending["fr-docno"].append(pi_doc["document_number"])
+        pending["fr-doc-title"].append(pi_doc.get("title"))
+        pending["fr-doc-agencies"].append(agencies)
+        pending["fr-doc-filed-at"].append(pi_doc.get("filed_at"))
+        pending["fr-doc-publication-date"].append(parse_date(pi_doc.get("publication_date")))
+        pending["fr-doc-pdf-url"].append(pi_doc.get("pdf_url"))
+        pending["cfr-parts-possibly-affected"].append(sorted(parts))
+    print(f"{len(pending['fr-docno'])} pending Rules.")
+    return pd.DataFrame(pending)
+
+
+#####################################
+# Functions for writing the results #
+#####################################
+
+# The tables, and their columns, written by each export profile. A table not in the profile isn't written, and None means all
+# of its columns.
+EXPORT_PROFILES = {
+    "llm-input": {
+        "fr_doc_analysis": [
+            "fr-docno", "fr-doc-citation", "fr-doc-agencies", "fr-doc-agencies-shorthand", "fr-doc-title", "fr-doc-abstract",
+            "fr-doc-publication-date", "llm-preamble", "llm-prompt", "llm-answer", "llm-citations", "llm-chunks-used",
+        ],
+        "group_analysis": None,
+        "part_answers": None,
+    },
+    "econ-analysis": {
+        "fr_doc_analysis": [
+            "fr-docno", "fr-doc-type", "fr-doc-agencies-shorthand", "fr-doc-publication-date", "fr-doc-effective-date", "cfr-divs-referenced-in", "cfr-words-in-force", "cfr-citation-count",
+            "fr-doc-page-count", "fr-doc-word-count", "fr-doc-table-density", "fr-doc-word-len", "fr-doc-tok-len",
+            "fr-doc-rfa-certified", "fr-doc-rfa-analysis", "fr-doc-rfa-small-entities", "llm-answer",
+        ],
+        "cfr_coverage": ["cfr-title", "cfr-part", "cfr-chapter", "cfr-part-agencies", "fr-docs-attributed"],
+        "part_topics": None,
+        "agencies": None,
+        "coverage_summary": None,
+        "cfr_divisions": None,
+        "division_timeline": None,
+    },
+    "audit": {
+        "fr_doc_analysis": [
+            "fr-docno", "fr-doc-citation", "fr-doc-title", "cfr-divs-referenced-in", "fr-attribution-confidence", "llm-answer", "llm-citations", "llm-error", "llm-model",
+        ],
+        "cfr_coverage": None,
+        "fetch_status": None,
+        "group_analysis": None,
+        "coverage_gaps": None,
+    },
+}
+# Columns that --columns always keeps, so the rows of each table can still be identified
+KEY_COLUMNS = ["fr-docno", "cfr-title", "cfr-part"]
+
+
+def select_columns(name, table, profile=None, columns=None, exclude_columns=[]):
+    '''
+    Select the columns of the result table called name to write, by an EXPORT_PROFILES profile name or a list of columns
+    (plus the KEY_COLUMNS), less exclude_columns. Returns None if the table shouldn't be written at all: it isn't in the
+    profile, or it has none of the columns asked for.
+    '''
+    if profile is not None:
+        if name not in EXPORT_PROFILES[profile]:
+            return None
+        columns = EXPORT_PROFILES[profile][name]
+        if columns is not None:
+            table = table[[col for col in columns if col in table.columns]]
+    elif columns is not None:
+        if not any(col in table.columns for col in columns if col not in KEY_COLUMNS):
+            return None
+        table = table[[col for col in KEY_COLUMNS + [col for col in columns if col not in KEY_COLUMNS] if col in table.columns]]
+    return table.drop(columns=[col for col in exclude_columns if col in table.columns])
+
+
+# Names usable with @ in the expressions of computed columns, e.g. rule age in years: (@snapshot_date - `fr-doc-publication-date`) / @year
+COMPUTED_COLUMN_NAMES = {"snapshot_date": ECFR_SNAPSHOT_DATE, "year": datetime.timedelta(days=365.25)}
+
+
+def load_computed_columns(path):
+    '''
+    Load user-defined computed columns from a TOML file of [[column]] tables, each with a name, a pandas expression of the
+    other columns of the result table (see DataFrame.eval), and optionally the table, by default fr_doc_analysis. Column
+    names with dashes are quoted with backticks. E.g.,
+
+    [[column]]
+    name = "words-per-citation"
+    expr = "`cfr-words-in-force` / `cfr-citation-count`"
+
+    [[column]]
+    name = "rule-age-years"
+    expr = "(@snapshot_date - `fr-doc-publication-date`) / @year"
+
+    Returns the columns in the order they're computed, so a column can use earlier ones. Raises a ValueError if the file
+    isn't valid.
+    '''
+    columns = toml.load(path).get("column", [])
+    if len(columns) == 0:
+        raise ValueError(f"{path} has no [[column]] tables")
+    for column in columns:
+        if "name" not in column or "expr" not in column:
+            raise ValueError(f"every column in {path} needs a name and an expr")
+        column.setdefault("table", "fr_doc_analysis")
+    return columns
+
+
+def add_computed_columns(name, table, computed_columns):
+    '''
+    Add the computed columns of the result table called name to a copy of it. A column whose expression fails, e.g. because
+    a column it uses isn't in this run's results, is reported and left out.
+    '''
+    computed_columns = [column for column in computed_columns if column["table"] == name]
+    if len(computed_columns) == 0:
+        return table
+    table = table.copy()
+    for column in computed_columns:
+        try:
+            table[column["name"]] = table.eval(column["expr"], engine="python", local_dict=COMPUTED_COLUMN_NAMES)
+        except Exception as e:
+            report_error("computed-column-failed", f"{column['name']} = {column['expr']}: {type(e).__name__}: {e}", table=name)
+    return table
+
+
+# How the result tables write a missing value, an empty string, and an empty list, set, or dict, with each --null-policy:
+# as pandas does (an empty cell, "", and e.g. "[]"), all as an empty cell, all as NULL, or all as an empty cell with the
+# columns that have no other values left out
+NULL_POLICIES = ["as-is", "empty", "null", "omit"]
+
+
+def is_empty_value(value):
+    if isinstance(value, (str, list, tuple, set, dict)):
+        return len(value) == 0
+    try:
+        return bool(pd.isna(value))
+    except (TypeError, ValueError):
+        return False
+
+
+def apply_null_policy(table, null_policy):
+    '''
+    Returns a copy of table with its empty values, see is_empty_value, replaced by None according to null_policy, one of
+    NULL_POLICIES, and the string to write them as.
+    '''
+    if null_policy == "as-is":
+        return table, ""
+    if null_policy not in NULL_POLICIES:
+        raise ValueError(f"Unknown null policy {null_policy}")
+    empty = table.map(is_empty_value)
+    table = table.astype(object).mask(empty, None)
+    if null_policy == "omit":
+        table = table.loc[:, ~empty.all(axis=0) | (len(table) == 0)]
+    return table, "NULL" if null_policy == "null" else ""
+
+
+# The quoting of the cells of the result tables with each --csv-quoting option
+CSV_QUOTING = {"minimal": csv.QUOTE_MINIMAL, "all": csv.QUOTE_ALL, "nonnumeric": csv.QUOTE_NONNUMERIC, "none": csv.QUOTE_NONE}
+
+
+def csv_options(separator=",", quoting="minimal", encoding="utf-8"):
+    '''
+    The keyword arguments of DataFrame.to_csv, and read_csv, for the result tables' separator, e.g. "," or "tab", quoting,
+    one of CSV_QUOTING, and encoding, e.g. "utf-8-sig" to start the files with the byte order mark Excel expects. Unquoted
+    cells escape separators, quotes, and newlines with a backslash.
+    '''
+    options = {"sep": "\t" if separator == "tab" else separator, "quoting": CSV_QUOTING[quoting], "encoding": encoding}
+    if quoting == "none":
+        options["escapechar"] = "\\"
+    return options
+
+
+# The file extension of the result tables written with each --compress option
+RESULT_COMPRESSION_EXTENSIONS = {None: ".csv", "gzip": ".csv.gz", "zstd": ".csv.zst"}
+# The result tables are serialized, and compressed, on this many threads at once
+RESULT_WRITERS = 4
+
+
+def write_results(fr_doc_analysis, cfr_cov, fetch_status, outdir, shard_by=None, other_tables={}, profile=None, columns=None, exclude_columns=[], compress=None, computed_columns=[], null_policy="as-is", csv_format={}):
+    '''
+    Write the results of a run to outdir. By default, this is one fr_doc_analysis.csv and one cfr_coverage.csv, plus the
+    fetch_status.csv of the FR documents and any other_tables, {name: DataFrame}, which are never sharded. With shard_by="part",
+    the results are instead split into one directory per CFR Part holding the rows for that Part, plus a manifest.json listing the
+    shards. An FR document attributed to several Parts is written to each of their shards. See merge_shards in analyze.py.
+    Which tables and columns are written is controlled by profile, columns, and exclude_columns. See select_columns. With
+    compress, "gzip" or "zstd", the tables are compressed, e.g. fr_doc_analysis.csv.gz. The tables are written concurrently.
+    computed_columns are added to their tables before the columns are selected. See load_computed_columns. Empty values are
+    written according to null_policy. See apply_null_policy. csv_format is the keyword arguments of to_csv for the separator,
+    quoting, and encoding of the tables. See csv_options.
+    '''
+    all_columns = set(fr_doc_analysis.columns) | set(cfr_cov.columns) | set(fetch_status.columns) | {column["name"] for column in computed_columns}
+    for table in other_tables.values():
+        all_columns |= set(table.columns)
+    for col in (columns or []) + exclude_columns:
+        if col not in all_columns:
+            print(f"[!] WARNING: no result table has a column {col}")
+
+    ext = RESULT_COMPRESSION_EXTENSIONS[compress]
+
+    def write_table(name, table, path):
+        table = add_computed_columns(name, table, computed_columns)
+        table = select_columns(name, table, profile, columns, exclude_columns)
+        if table is None:
+            return False
+        table, na_rep = apply_null_policy(table, null_policy)
+        table.to_csv(path, compression=compress, na_rep=na_rep, **csv_format)
+        return True
+
+    os.makedirs(outdir, exist_ok=True)
+    with concurrent.futures.ThreadPoolExecutor(max_workers=RESULT_WRITERS) as pool:
+        written = [pool.submit(write_table, "fetch_status", fetch_status, os.path.join(outdir, f"fetch_status{ext}"))]
+        for name, table in other_tables.items():
+            written.append(pool.submit(write_table, name, table, os.path.join(outdir, f"{name}{ext}")))
+        if shard_by is None:
+            written.append(pool.submit(write_table, "fr_doc_analysis", fr_doc_analysis, os.path.join(outdir, f"fr_doc_analysis{ext}")))
+            written.append(pool.submit(write_table, "cfr_coverage", cfr_cov, os.path.join(outdir, f"cfr_coverage{ext}")))
+        else:
+            if shard_by != "part":
+                raise ValueError(f"Unknown shard type {shard_by}")
+            manifest = {"shard-by": shard_by, "shards": []}
+            shards_written = []
+            for _, part_cov in cfr_cov.iterrows():
+                shard_name = f"title-{part_cov['cfr-title']}-part-{part_cov['cfr-part']}"
+                shard_dir = os.path.join(outdir, "shards", shard_name)
+                os.makedirs(shard_dir, exist_ok=True)
+                part_docs = fr_doc_analysis[fr_doc_analysis["fr-docno"].isin(part_cov["fr-docs-attributed"])]
+                shard = {"cfr-title": part_cov["cfr-title"], "cfr-part": part_cov["cfr-part"]}
+                part_cov = cfr_cov[(cfr_cov["cfr-title"] == part_cov["cfr-title"]) & (cfr_cov["cfr-part"] == part_cov["cfr-part"])]
+                for name, table in [("fr_doc_analysis", part_docs), ("cfr_coverage", part_cov)]:
+                    shard_written = pool.submit(write_table, name, table, os.path.join(shard_dir, f"{name}{ext}"))
+                    shards_written.append((shard, name, os.path.join("shards", shard_name, f"{name}{ext}"), shard_written))
+                shard["fr-doc-count"] = len(part_docs)
+                manifest["shards"].append(shard)
+            # Only list the tables of each shard that were written
+            for shard, name, path, shard_written in shards_written:
+                if shard_written.result():
+                    shard[name] = path
+            with open(os.path.join(outdir, "manifest.json"), "w") as outf:
+                json.dump(manifest, outf, indent=2)
+        # Raise any error of the writers
+        for table_written in written:
+            table_written.result()
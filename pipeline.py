@@ -0,0 +1,748 @@
+from cfr import CfrPart, citation_match, citations_of_part, corrections_of_title, ecfr_agencies_by_division, recent_changes_of_part
+from frapi import ECFR_DATE, FR_DOC_FIELDS, NON_RULE_DOC_TYPES, abbreviate_agency, agency_abbreviations, enrich_fr_doc, fetch_fr_docs, fr_agencies, fr_doc_page_ranges, fr_docs_for_part, parse_date
+from historical import fetch_historical_doc, is_historical_citation
+import json
+import lxml.etree as ET
+import os
+import pandas as pd
+import re
+from report import ERRORS, STAGE_SECONDS, format_eta, report_error, timed_stage
+import sqlite3
+from store import fr_doc_dir, write_atomic
+import time
+
+#########################################
+# Functions for describing FR documents #
+#########################################
+
+def fr_doc_stats(docno, docinfo, datadir):
+    '''
+    Compute statistics of the text of a fetched FR document, for budgeting the LLM analysis, and cache them in the document's
+    directory. Table-heavy documents are chunked into many more pieces than their page range suggests, so the share of the
+    text in tables is counted as well as its size.
+    '''
+    stats_path = os.path.join(fr_doc_dir(datadir, docno), "stats.json")
+    try:
+        with open(stats_path, "r") as f:
+            stats = json.load(f)
+        # Statistics cached before images were counted are recomputed
+        if "image-count" in stats:
+            return stats
+    except (FileNotFoundError, ValueError):
+        # ValueError, if a copy written before it was written atomically was truncated
+        pass
+
+    with open(os.path.join(fr_doc_dir(datadir, docno), "rule.html"), "rb") as f:
+        root = ET.HTML(f.read())
+    text = " ".join(root.itertext()) if root is not None else ""
+    tables = root.findall(".//table") if root is not None else []
+    table_chars = sum(len(" ".join(table.itertext())) for table in tables)
+    stats = {
+        "page-count": docinfo["end_page"] - docinfo["start_page"] + 1 if docinfo.get("end_page") and docinfo.get("start_page") else None,
+        "char-count": len(text),
+        "word-count": len(text.split()),
+        "section-count": len(root.xpath("//h1|//h2|//h3|//h4|//h5|//h6")) if root is not None else 0,
+        "table-count": len(tables),
+        "table-density": table_chars / len(text) if len(text) > 0 else 0.0,
+        "image-count": len(root.findall(".//img")) if root is not None else 0,
+    }
+    write_atomic(stats_path, json.dumps(stats))
+    return stats
+
+
+def preamble_section(root, heading_regex):
+    '''
+    Returns the text of the sections of the parsed HTML of an FR document whose heading matches heading_regex, up to the next
+    heading of the same or a higher level, or "" if there are none.
+    '''
+    texts = []
+    level = None
+    for elem in root.iter():
+        if not isinstance(elem.tag, str):
+            continue
+        if re.fullmatch(r"h[1-6]", elem.tag):
+            heading = " ".join(elem.itertext())
+            if level is not None and int(elem.tag[1]) <= level:
+                level = None
+            if level is None and re.search(heading_regex, heading, re.IGNORECASE):
+                level = int(elem.tag[1])
+            continue
+        # Only take the text directly in each element, since its children are visited too
+        if level is not None:
+            texts.extend(text for text in [elem.text, elem.tail] if text)
+    return " ".join(" ".join(texts).split())
+
+
+# The numbered amendatory instructions at the end of an FR document, e.g. "2. Amend § 63.14 by revising paragraph (b)(1)"
+# or "3. Section 63.7 is amended by adding paragraph (c)". FederalRegister.gov's HTML starts each with a ■.
+amendatory_instruction_regex = r"\d+[a-z]?\.\s+(Amend|Revise|Add|Remove|Redesignate|Reserve|Correct|Section|Sections|Subpart|Subparts|Appendix|In|§)"
+division_ref_regex = r"(?P<section>\b\d+[a-z]?\.\d+[a-z0-9-]*)|[Ss]ubparts?\s+(?P<subpart>[A-Z]{1,5})\b|[Aa]ppendix\s+(?P<appendix>[A-Z0-9]{1,3})\b"
+# A reference to a division following, e.g., "Remove and reserve §", or followed by, e.g., "is removed", is to the whole
+# division rather than to a paragraph of it
+removal_before_regex = r"\bremov(e|ing)\b(\s+and\s+reserv(e|ing))?\s*(§§?\s*|sections?\s+|subparts?\s+|appendix\s+)?$"
+addition_before_regex = r"\badd(ing)?\b(\s+(a\s+)?new)?\s*(§§?\s*|sections?\s+|subparts?\s+|appendix\s+)?$"
+list_separator_regex = r"[\s,;]*(and|or|through)?[\s,]*(§§?\s*)?"
+
+
+def amendatory_instructions(root):
+    '''
+    Returns the text of each amendatory instruction of the parsed HTML of an FR document, in order.
+    '''
+    instructions = []
+    for elem in root.iter("p"):
+        text = " ".join(" ".join(elem.itertext()).split()).lstrip("■ ")
+        if re.match(amendatory_instruction_regex, text):
+            instructions.append(text)
+    return instructions
+
+
+def division_roles(instructions):
+    '''
+    Classify each CFR division named by the amendatory instructions of an FR document by what the document did to it:
+    "original promulgation" if it added the division, "removal" if it removed (and possibly reserved) it, and "amendment"
+    otherwise, e.g. if it revised a paragraph of it. Returns {(kind, identifier): role}, in which kind is "section",
+    "subpart", or "appendix", e.g. ("section", "63.14"). A division named by several instructions keeps the role of the
+    first that added or removed it, if any.
+    '''
+    roles = {}
+    for instruction in instructions:
+        refs = []
+        for match in re.finditer(division_ref_regex, instruction):
+            kind = match.lastgroup
+            refs.append({"key": (kind, match.group(kind)), "start": match.start(), "end": match.end()})
+        for i, ref in enumerate(refs):
+            before = instruction[refs[i - 1]["end"] if i > 0 else 0:ref["start"]]
+            after = instruction[ref["end"]:]
+            ref["listed"] = i > 0 and re.fullmatch(list_separator_regex, before) is not None
+            if re.search(removal_before_regex, before, re.IGNORECASE) or re.match(r"\s+(is|are)\s+removed\b", after):
+                ref["role"] = "removal"
+            elif re.search(addition_before_regex, before, re.IGNORECASE) or re.match(r"\s+(is|are)\s+added\b", after):
+                ref["role"] = "original promulgation"
+            else:
+                ref["role"] = "amendment"
+        # The divisions of a list, e.g. "Remove and reserve §§ 63.1 and 63.2" or "§§ 63.1 and 63.2 are removed", share a role
+        start = 0
+        for i in range(1, len(refs) + 1):
+            if i == len(refs) or not refs[i]["listed"]:
+                group_roles = [ref["role"] for ref in refs[start:i] if ref["role"] != "amendment"]
+                for ref in refs[start:i]:
+                    ref["role"] = group_roles[0] if len(group_roles) > 0 else ref["role"]
+                start = i
+        for ref in refs:
+            if roles.get(ref["key"], "amendment") == "amendment":
+                roles[ref["key"]] = ref["role"]
+    return roles
+
+
+rfa_certified_regex = r"certif(y|ies|ied)[^.]*not have a significant economic impact on a substantial number of small entities"
+rfa_analysis_regex = r"(final|initial) regulatory flexibility analysis"
+rfa_entities_regex = r"([\d,]+)\s+small\s+(entities|businesses|governmental jurisdictions|organizations)"
+
+
+def rfa_of_fr_doc(docno, datadir):
+    '''
+    Extract the Regulatory Flexibility Act statement of a fetched FR document's preamble: whether the agency certified that
+    the rule has no significant economic impact on a substantial number of small entities, whether it did a regulatory
+    flexibility analysis instead, and the largest number of small entities it says are affected, if any.
+    '''
+    with open(os.path.join(fr_doc_dir(datadir, docno), "rule.html"), "rb") as f:
+        root = ET.HTML(f.read())
+    section = preamble_section(root, r"Regulatory Flexibility") if root is not None else ""
+    entities = [int(num.replace(",", "")) for num, _ in re.findall(rfa_entities_regex, section) if num.replace(",", "")]
+    return {
+        "rfa-statement-found": section != "",
+        "rfa-certified": re.search(rfa_certified_regex, section, re.IGNORECASE) is not None,
+        "rfa-analysis": re.search(rfa_analysis_regex, section, re.IGNORECASE) is not None,
+        "rfa-small-entities": max(entities) if len(entities) > 0 else None,
+    }
+
+
+######################################################
+# Classes for accumulating the attributions of a run #
+######################################################
+
+class AttributionStore:
+    '''
+    Accumulates the FR documents attributed to CFR divisions, i.e. {docno: (cfr-divs-affected, docinfo)}, and the coverage of
+    each completed CFR Part. The divisions are counted per FR citation, so re-attributing a citation, e.g. when a Part is
+    resumed, doesn't count it twice. By default this is in memory. Given a db_path, the state is instead spilled to an SQLite database,
+    committed each time a Part is completed, so the attribution of a Title-scale run doesn't grow in memory and an interrupted
+    run can be resumed from the last completed Part. The final tables of cfr_to_fr_docs are still built in memory.
+    '''
+    def __init__(self, db_path=None):
+        self.mem = {}
+        self.mem_parts = {}
+        self.db = None
+        if db_path is not None:
+            self.db = sqlite3.connect(db_path)
+            self.db.execute("CREATE TABLE IF NOT EXISTS docs (docno TEXT PRIMARY KEY, docinfo TEXT)")
+            self.db.execute("CREATE TABLE IF NOT EXISTS divs (docno TEXT, name TEXT, type TEXT, word_sz INTEGER, status TEXT DEFAULT '', cita TEXT DEFAULT '', count INTEGER DEFAULT 1, confidence REAL DEFAULT 1.0, UNIQUE(docno, name, type, word_sz, status, cita))")
+            # Databases of runs from before division statuses were tracked
+            if "status" not in [col[1] for col in self.db.execute("PRAGMA table_info(divs)")]:
+                self.db.execute("ALTER TABLE divs ADD COLUMN status TEXT DEFAULT ''")
+            # Databases of runs from before citations were counted, whose divisions were unique per document
+            if "cita" not in [col[1] for col in self.db.execute("PRAGMA table_info(divs)")]:
+                self.db.execute("ALTER TABLE divs RENAME TO divs_uncounted")
+                self.db.execute("CREATE TABLE divs (docno TEXT, name TEXT, type TEXT, word_sz INTEGER, status TEXT DEFAULT '', cita TEXT DEFAULT '', count INTEGER DEFAULT 1, confidence REAL DEFAULT 1.0, UNIQUE(docno, name, type, word_sz, status, cita))")
+                self.db.execute("INSERT INTO divs (docno, name, type, word_sz, status) SELECT docno, name, type, word_sz, status FROM divs_uncounted")
+                self.db.execute("DROP TABLE divs_uncounted")
+            # Databases of runs from before attributions had a confidence
+            if "confidence" not in [col[1] for col in self.db.execute("PRAGMA table_info(divs)")]:
+                self.db.execute("ALTER TABLE divs ADD COLUMN confidence REAL DEFAULT 1.0")
+            self.db.execute("CREATE TABLE IF NOT EXISTS parts (title TEXT, part TEXT, coverage TEXT, PRIMARY KEY(title, part))")
+            self.db.commit()
+
+
+    def __contains__(self, docno):
+        if self.db is None:
+            return docno in self.mem
+        return self.db.execute("SELECT 1 FROM docs WHERE docno = ?", (docno,)).fetchone() is not None
+
+
+    def __len__(self):
+        if self.db is None:
+            return len(self.mem)
+        return self.db.execute("SELECT COUNT(*) FROM docs").fetchone()[0]
+
+
+    def add_doc(self, docno, docinfo):
+        if self.db is None:
+            self.mem[docno] = ({}, docinfo)
+        else:
+            self.db.execute("INSERT OR REPLACE INTO docs VALUES (?, ?)", (docno, json.dumps(docinfo)))
+
+
+    def doc(self, docno):
+        if self.db is None:
+            return self.mem[docno][1]
+        return json.loads(self.db.execute("SELECT docinfo FROM docs WHERE docno = ?", (docno,)).fetchone()[0])
+
+
+    def update_doc(self, docno, docinfo):
+        '''
+        Replace the docinfo of an FR document already added, keeping the divisions attributed to it.
+        '''
+        if self.db is None:
+            self.mem[docno] = (self.mem[docno][0], docinfo)
+        else:
+            self.db.execute("UPDATE docs SET docinfo = ? WHERE docno = ?", (json.dumps(docinfo), docno))
+
+
+    def add_divs(self, docno, fr_cita, cfr_divs, confidence=1.0):
+        '''
+        Attribute the CFR divisions citing fr_cita, {CFR division: number of times it cites fr_cita} as returned by
+        citations_of_part, to the FR document docno with the given confidence. See ATTRIBUTION_CONFIDENCE.
+        '''
+        if self.db is None:
+            self.mem[docno][0].update(((div, fr_cita), (count, confidence)) for div, count in cfr_divs.items())
+        else:
+            self.db.executemany("INSERT OR REPLACE INTO divs VALUES (?, ?, ?, ?, ?, ?, ?, ?)", [(docno, *div, fr_cita, count, confidence) for div, count in cfr_divs.items()])
+
+
+    def has_part(self, titleno, partno):
+        if self.db is None:
+            return (titleno, partno) in self.mem_parts
+        return self.db.execute("SELECT 1 FROM parts WHERE title = ? AND part = ?", (titleno, str(partno))).fetchone() is not None
+
+
+    def add_part_coverage(self, titleno, partno, coverage):
+        if self.db is None:
+            self.mem_parts[(titleno, partno)] = coverage
+        else:
+            self.db.execute("INSERT OR REPLACE INTO parts VALUES (?, ?, ?)", (titleno, str(partno), json.dumps(coverage)))
+
+
+    def part_coverages(self):
+        '''
+        Yields ((titleno, partno), coverage) for each completed Part, in the order they were completed.
+        '''
+        if self.db is None:
+            yield from self.mem_parts.items()
+            return
+        for titleno, partno, coverage in self.db.execute("SELECT title, part, coverage FROM parts ORDER BY rowid"):
+            yield (titleno, CfrPart.parse(partno)), json.loads(coverage)
+
+
+    def flush(self):
+        if self.db is not None:
+            self.db.commit()
+
+
+    def save(self, db_path):
+        '''
+        Copy the state of this store into an SQLite database at db_path and return the on-disk store. Used to keep the
+        state of an in-memory store when a run is interrupted, so it can be resumed.
+        '''
+        saved = AttributionStore(db_path)
+        for docno, (div_citas, docinfo) in self.mem.items():
+            saved.add_doc(docno, docinfo)
+            for (div, fr_cita), (count, confidence) in div_citas.items():
+                saved.add_divs(docno, fr_cita, {div: count}, confidence)
+        for (titleno, partno), coverage in self.part_coverages():
+            saved.add_part_coverage(titleno, partno, coverage)
+        saved.flush()
+        return saved
+
+
+    def items(self):
+        '''
+        Yields (docno, (cfr-divs-affected, docinfo)) one document at a time, in which each CFR division affected is a tuple
+        ("NAME", "DIV-TYPE", word size, status, citation count, confidence), citation count is the number of times the
+        division cites the document, and confidence is that of the division's most confident citation of it.
+        '''
+        if self.db is None:
+            for docno, (div_citas, docinfo) in self.mem.items():
+                attribs = {}
+                for (div, _), (count, confidence) in div_citas.items():
+                    total, best = attribs.get(div, (0, 0.0))
+                    attribs[div] = (total + count, max(best, confidence))
+                yield docno, ({(*div, count, confidence) for div, (count, confidence) in attribs.items()}, docinfo)
+            return
+        for docno, docinfo in self.db.execute("SELECT docno, docinfo FROM docs ORDER BY rowid"):
+            cfr_divs = set(self.db.execute("SELECT name, type, word_sz, status, SUM(count), MAX(confidence) FROM divs WHERE docno = ? GROUP BY name, type, word_sz, status", (docno,)))
+            yield docno, (cfr_divs, json.loads(docinfo))
+
+
+#######################################################
+# Functions for attributing CFR Parts to FR documents #
+#######################################################
+
+# The confidence of attributing a CFR division to an FR document by how its FR citation matched the document: it cites the
+# page the document starts on, a later page of the document, a page of one of its corrections, the scanned pages of the FR
+# from before 1994 it's on, or a page of several documents
+ATTRIBUTION_CONFIDENCE = {
+    "start-page": 1.0,
+    "mid-range": 0.8,
+    "other-range": 0.6,
+    "historical": 0.5,
+    "ambiguous": 0.4,
+}
+
+
+def issuing_agencies(docno, fr_doc, agency_abbrvs_by_name):
+    '''
+    The names and short names of the agencies that issued an FR document, from its search result. Every agency is kept,
+    even ones unknown to the agencies list.
+    '''
+    agency_names = fr_doc["agency_names"] or []
+    agency_abbrvs = []
+    for agency in agency_names:
+        if agency not in agency_abbrvs_by_name:
+            report_error("unknown-agency-abbreviation", f"No short name for {agency}", docno=docno)
+        agency_abbrvs.append(agency_abbrvs_by_name.get(agency) or abbreviate_agency(agency))
+    return agency_names, agency_abbrvs
+
+
+def merge_docinfo(docno, docinfo, other):
+    '''
+    Merge other, a search result of the FR document docno, into docinfo, the search result it was first attributed from.
+    The searches of different CFR Parts can return the same document with different metadata, since which fields are
+    available varies. The fields of FR_DOC_FIELDS empty in docinfo are filled in from other. Fields both have but that
+    differ are kept as in docinfo, reported as a metadata-conflict, and listed in its metadata_conflicts, which
+    enrich_fr_doc resolves from the single-document API. Returns the merged docinfo, or None if other changes nothing.
+    '''
+    merged = dict(docinfo)
+    conflicts = list(docinfo.get("metadata_conflicts", []))
+    # The agencies of docinfo were replaced with their names. See issuing_agencies.
+    for field in [field for field in FR_DOC_FIELDS if field != "agencies"]:
+        if other.get(field) in (None, "", []):
+            continue
+        if docinfo.get(field) in (None, "", []):
+            merged[field] = other[field]
+        elif docinfo[field] != other[field] and field not in conflicts:
+            report_error("metadata-conflict", f"Searches of different CFR Parts returned different {field}", docno=docno, kept=docinfo[field], other=other[field])
+            conflicts.append(field)
+    if len(conflicts) > 0:
+        merged["metadata_conflicts"] = conflicts
+    return merged if merged != docinfo else None
+
+
+def cfr_to_fr_docs(cfr_parts, datadir, check_changes=False, refetch_stale=False, store=None, include_presidential=False, enrich=False, refresh_searches=False, keep_search_history=False, on_fetched=None, classify_unattributed=False, match_corrections=False, historical=False):
+    '''
+    Input: [(titleno, part)]
+    Create a database in the local filesystem with this structure:
+    cfr-{date}/
+        title-{titleno}/
+            part-{X}/
+                text.xml
+                rules.json
+            part-{Y}/
+                ...
+            ...
+            structure.json
+    final-rules/
+        doc-no-X/
+            details.toml
+            index # Added by llm_analysis
+            results.{txt, toml, json?} # Added by llm_analysis
+            rule.html
+            rule.pdf
+        doc-no-Y/
+            ...
+        ...
+    Return the FR doc data, how well the CFR inputs were "covered," i.e. how many FR citations we were able to attribute to documents,
+    and the fetch status of each attributed FR doc.
+    If check_changes is set, each Part is also checked for amendments and corrections made after ECFR_DATE and flagged in the coverage
+    data as potentially stale. If refetch_stale is also set, the cached XML of stale Parts is discarded and fetched again.
+    The attributed FR documents are accumulated in store, an AttributionStore, which defaults to an in-memory one.
+    If include_presidential is set, Presidential documents affecting each Part are attributed along with its Final Rules.
+    If classify_unattributed is set, the citations no Final Rule matched are looked up in the Part's proposed rules, notices,
+    and Presidential documents, and those found are listed by the document's type rather than as unattributed.
+    If match_corrections is set and some citations of a Part match no page range of its Final Rules, the other page ranges
+    of the Final Rules published in the same or neighboring volumes, e.g. of their corrections, are looked up and matched
+    as well. See fr_doc_page_ranges.
+    If historical is set, the citations left unattributed that are of the FR from before 1994, which FederalRegister.gov's
+    search doesn't cover, are attributed to the scanned pages of the FR they cite, fetched from GovInfo and flagged as
+    historical. See fetch_historical_doc.
+    If enrich is set, the metadata of each fetched FR doc is completed from the single-document API. See enrich_fr_doc.
+    on_fetched is called with each FR doc as soon as it's fetched, e.g. to start analyzing it. See fetch_fr_docs.
+    '''
+    # This is used to add agency abbreviations to the FR doc info. The field is useful to the LLM but can't be selected in the FederalRegister.gov 
+    # search API endpoint used in fr_docs_for_part, which gets all the other docinfo.
+    agency_abbrvs_by_name = agency_abbreviations(fr_agencies(datadir))
+    # This is used to label each CFR Part with the agencies that administer it, even if no FR docs are attributed to it
+    ecfr_agencies = ecfr_agencies_by_division(datadir)
+
+    fr_docs_to_analyze = store if store is not None else AttributionStore()
+    corrections_by_title = {}
+    failed_parts = []
+    # For the ETA, which only counts the Parts done by this run
+    t0 = time.time()
+    num_processed = 0
+    
+    for i, (titleno, part) in enumerate(cfr_parts):
+        # An identifier that isn't a valid Part fails only its Part, like any other failure below
+        try:
+            partno = CfrPart.parse(part["identifier"]) # Can be non-integer
+        except ValueError as e:
+            report_error("part-failed", f"{type(e).__name__}: {e}", cfr_title=titleno, cfr_part=str(part["identifier"]))
+            print(f"[!] {titleno} CFR Part {part['identifier']} failed: {type(e).__name__}: {e}")
+            failed_parts.append((titleno, part["identifier"], part))
+            continue
+        if fr_docs_to_analyze.has_part(titleno, partno):
+            print(f"[*] {titleno} CFR Part {partno} (already done)")
+            continue
+        print(f"[*] {titleno} CFR Part {partno} ({i+1}/{len(cfr_parts)}) {format_eta(t0, num_processed, num_processed + len(cfr_parts) - i)}")
+        num_processed += 1
+        # A failure in one Part, e.g. malformed XML or an API error, is reported and the run goes on with the next. The
+        # Part isn't marked as done, so --resume retries it.
+        try:
+            os.makedirs(os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}"), exist_ok=True)
+            # Check whether the eCFR has changed the Part since our snapshot
+            changes = {
+                "ecfr-amendments-since-snapshot": [],
+                "ecfr-corrections-since-snapshot": [],
+                "potentially-stale": False,
+            }
+            if check_changes or refetch_stale:
+                if titleno not in corrections_by_title:
+                    corrections_by_title[titleno] = corrections_of_title(titleno)
+                changes = recent_changes_of_part(titleno, partno, corrections_by_title[titleno])
+                part_path = os.path.join(datadir, f"cfr-{ECFR_DATE}", f"title-{titleno}", f"part-{partno}", "part.xml")
+                if refetch_stale and changes["potentially-stale"] and os.path.exists(part_path):
+                    print("\t[*] Discarding stale cached XML.")
+                    os.remove(part_path)
+            # Search the eCFR for all the citations of the Federal Register in the given CFR Part
+            div_paths = {}
+            fr_citas_to_cfr_divs = citations_of_part(titleno, partno, datadir, div_paths=div_paths)
+            # Search FederalRegister.gov for all documents marked as affecting the given CFR Part
+            fr_docs_affecting = fr_docs_for_part(titleno, partno, datadir, refresh=refresh_searches, keep_history=keep_search_history)
+            if include_presidential:
+                # Executive orders, etc. are often the proximate cause of rule changes
+                fr_docs_affecting += fr_docs_for_part(titleno, partno, datadir, doc_type="PRESDOCU", refresh=refresh_searches, keep_history=keep_search_history)
+
+            # Some citations are of a correction of a document, or of a page range not in its search result
+            if match_corrections:
+                unmatched = [fr_cita for fr_cita in fr_citas_to_cfr_divs if not any(citation_match(fr_cita, fr_doc) is not None for fr_doc in fr_docs_affecting)]
+                volumes = {int(volume) + offset for volume in {fr_cita.split(" ")[0] for fr_cita in unmatched} if volume.isdigit() for offset in (-1, 0, 1)}
+                candidates = [fr_doc for fr_doc in fr_docs_affecting if fr_doc["citation"] and fr_doc["citation"].split(" ")[0].isdigit() and int(fr_doc["citation"].split(" ")[0]) in volumes]
+                if len(candidates) > 0:
+                    print(f"\t[*] Looking up the other page ranges of {len(candidates)} FR documents for {len(unmatched)} unmatched citations... ", end="")
+                    for fr_doc in candidates:
+                        try:
+                            fr_doc["page_ranges"] = fr_doc_page_ranges(fr_doc["document_number"], datadir)
+                        except Exception as e:
+                            report_error("fetch-failed", f"{type(e).__name__}: {e}", docno=fr_doc["document_number"])
+                    print(f"{sum(len(fr_doc.get('page_ranges', [])) > 1 for fr_doc in candidates)} have corrections.")
+        
+            # Attempt to match each FR citation to its FR Final Rule document number
+            print("\t[*] Attributing FR citations to a FR document... ", end="")
+            fr_docs_attrib_for_part = set()
+            fr_citas_unattrib_for_part = set()
+            fr_cita_attributions = []
+            for fr_cita, cfr_divs in fr_citas_to_cfr_divs.items():
+                docnos_matched = []
+                matches = []
+                for fr_doc in fr_docs_affecting:
+                    match = citation_match(fr_cita, fr_doc)
+                    if match is not None:
+                        matches.append((fr_doc, match))
+                for fr_doc, match in matches:
+                    docno = fr_doc["document_number"]
+                    if docno not in fr_docs_to_analyze:
+                        # Keep the search result as returned by the API, before it's normalized below. See fetch_fr_docs.
+                        fr_doc["raw"] = json.loads(json.dumps(fr_doc))
+                        # Add the short-hands for the issuing agencies
+                        fr_doc["agencies"], fr_doc["agency_shorthand"] = issuing_agencies(docno, fr_doc, agency_abbrvs_by_name)
+                        # Add it to the set of FR docs to analyze {docno: (cfr-divs-affected, docinfo)}
+                        fr_docs_to_analyze.add_doc(docno, fr_doc)
+                    elif fr_doc.get("raw") is None:
+                        # The document was attributed from the search of another Part, which may have had other metadata
+                        docinfo = fr_docs_to_analyze.doc(docno)
+                        merged = merge_docinfo(docno, docinfo, fr_doc)
+                        if merged is not None:
+                            if merged["agency_names"] != docinfo["agency_names"]:
+                                merged["agencies"], merged["agency_shorthand"] = issuing_agencies(docno, merged, agency_abbrvs_by_name)
+                            fr_docs_to_analyze.update_doc(docno, merged)
+                    # A citation of several documents doesn't say which of them made the division
+                    match_type = "ambiguous" if len(matches) > 1 else match
+                    fr_docs_to_analyze.add_divs(docno, fr_cita, cfr_divs, ATTRIBUTION_CONFIDENCE[match_type])
+                    fr_cita_attributions.append((fr_cita, docno, match_type, ATTRIBUTION_CONFIDENCE[match_type]))
+                
+                    fr_docs_attrib_for_part.add(docno)
+                    docnos_matched.append(docno)
+
+                if len(docnos_matched) == 0:
+                    fr_citas_unattrib_for_part.add(fr_cita)
+                elif len(docnos_matched) > 1:
+                    report_error("ambiguous-attribution", "Citation matches several FR documents", cfr_title=titleno, cfr_part=str(partno), cita=fr_cita, docnos=docnos_matched)
+    
+            num_citas = len(fr_citas_to_cfr_divs)
+            num_unattributed = len(fr_citas_unattrib_for_part)
+            attrib_count = num_citas - num_unattributed
+            print(f"{attrib_count}/{num_citas} citations attributed from {len(fr_docs_affecting)} available documents.")
+
+            # Citations of documents that aren't Final Rules, e.g. notices, aren't failures of attribution
+            fr_cita_non_rule = []
+            if classify_unattributed and len(fr_citas_unattrib_for_part) > 0:
+                non_rule_docs = []
+                for doc_type in NON_RULE_DOC_TYPES:
+                    if doc_type != "PRESDOCU" or not include_presidential:
+                        non_rule_docs += fr_docs_for_part(titleno, partno, datadir, doc_type=doc_type, refresh=refresh_searches, keep_history=keep_search_history)
+                for fr_cita in sorted(fr_citas_unattrib_for_part):
+                    for fr_doc in non_rule_docs:
+                        if citation_match(fr_cita, fr_doc) is not None:
+                            fr_cita_non_rule.append((fr_cita, fr_doc["document_number"], fr_doc["type"]))
+                            report_error("non-rule-citation", f"Citation of a {fr_doc['type']}", cfr_title=titleno, cfr_part=str(partno), cita=fr_cita, docno=fr_doc["document_number"])
+                fr_citas_unattrib_for_part -= {fr_cita for fr_cita, _, _ in fr_cita_non_rule}
+                print(f"\t[*] {len({fr_cita for fr_cita, _, _ in fr_cita_non_rule})}/{num_unattributed} unattributed citations are of proposed rules, notices, or Presidential documents.")
+            # Citations from before 1994 are of documents that FederalRegister.gov's search doesn't cover
+            fr_citas_historical = [fr_cita for fr_cita in sorted(fr_citas_unattrib_for_part) if is_historical_citation(fr_cita)]
+            if historical and len(fr_citas_historical) > 0:
+                print(f"\t[*] Fetching the scanned pages of {len(fr_citas_historical)} citations from before 1994 from GovInfo... ", end="")
+                for fr_cita in fr_citas_historical:
+                    try:
+                        docno, fr_doc = fetch_historical_doc(fr_cita, datadir)
+                    except Exception as e:
+                        report_error("fetch-failed", f"{type(e).__name__}: {e}", cfr_title=titleno, cfr_part=str(partno), cita=fr_cita)
+                        continue
+                    if docno not in fr_docs_to_analyze:
+                        fr_docs_to_analyze.add_doc(docno, fr_doc)
+                    fr_docs_to_analyze.add_divs(docno, fr_cita, fr_citas_to_cfr_divs[fr_cita], ATTRIBUTION_CONFIDENCE["historical"])
+                    fr_cita_attributions.append((fr_cita, docno, "historical", ATTRIBUTION_CONFIDENCE["historical"]))
+                    fr_docs_attrib_for_part.add(docno)
+                    fr_citas_unattrib_for_part.discard(fr_cita)
+                print(f"{len([fr_cita for fr_cita in fr_citas_historical if fr_cita not in fr_citas_unattrib_for_part])} fetched.")
+            for fr_cita in sorted(fr_citas_unattrib_for_part):
+                report_error("unattributed-citation", "No FR document found for citation", cfr_title=titleno, cfr_part=str(partno), cita=fr_cita)
+
+            # The words in force of the divisions citing the FR, and of those with a citation attributed to an FR document
+            divs_in_force = {div for divs in fr_citas_to_cfr_divs.values() for div in divs if not div[3]}
+            fr_citas_attributed = {fr_cita for fr_cita, _, _, _ in fr_cita_attributions}
+            divs_attributed = {div for fr_cita in fr_citas_attributed for div in fr_citas_to_cfr_divs[fr_cita] if not div[3]}
+            # The hierarchy of each division citing the FR, with its word count, its citations, and the FR documents they're
+            # attributed to
+            div_docnos = {(div[0], div[1]): set() for divs in fr_citas_to_cfr_divs.values() for div in divs}
+            div_info = {(div[0], div[1]): {"cfr-division-word-count": div[2], "fr-citations": []} for divs in fr_citas_to_cfr_divs.values() for div in divs}
+            for fr_cita, divs in fr_citas_to_cfr_divs.items():
+                for div in divs:
+                    div_info[(div[0], div[1])]["fr-citations"].append(fr_cita)
+            for fr_cita, docno, _, _ in fr_cita_attributions:
+                for div in fr_citas_to_cfr_divs[fr_cita]:
+                    div_docnos[(div[0], div[1])].add(docno)
+            part_path = {"cfr-chapter": part.get("chapter"), "cfr-subchapter": part.get("subchapter"), "cfr-part": str(partno)}
+            div_hierarchy = [(name, divty, {**part_path, **div_paths.get(name, {}), **div_info[(name, divty)]}, sorted(docnos)) for (name, divty), docnos in sorted(div_docnos.items())]
+
+            fr_docs_to_analyze.add_part_coverage(titleno, partno, {
+                "cfr-chapter": part.get("chapter"),
+                "cfr-part-agencies": ecfr_agencies.get((titleno, "part", str(partno)), ecfr_agencies.get((titleno, "chapter", part.get("chapter")), [])),
+                "fr-citations": list(fr_citas_to_cfr_divs.keys()),
+                "fr-docs-affecting": list(map(lambda fr_doc : fr_doc["document_number"], fr_docs_affecting)),
+                "fr-docs-attributed": list(fr_docs_attrib_for_part),
+                "fr-cita-unattributed": list(fr_citas_unattrib_for_part),
+                "fr-cita-attributions": fr_cita_attributions,
+                "fr-cita-non-rule": fr_cita_non_rule,
+                "cfr-divs-dead": sorted({(div[0], div[3]) for divs in fr_citas_to_cfr_divs.values() for div in divs if div[3]}),
+                "cfr-words-citing-fr": sum(div[2] for div in divs_in_force),
+                "cfr-words-attributed": sum(div[2] for div in divs_attributed),
+                "cfr-div-hierarchy": div_hierarchy,
+                **changes,
+            })
+            fr_docs_to_analyze.flush()
+        except Exception as e:
+            report_error("part-failed", f"{type(e).__name__}: {e}", cfr_title=titleno, cfr_part=str(partno))
+            print(f"\n\t[!] {titleno} CFR Part {partno} failed: {type(e).__name__}: {e}")
+            failed_parts.append((titleno, partno, part))
+    
+    # Fetch the FR docs to analyze
+    STAGE_SECONDS["attribution"] = STAGE_SECONDS.get("attribution", 0) + time.time() - t0
+    with timed_stage("fetch"):
+        fr_docs_skipped, fetch_status = fetch_fr_docs(fr_docs_to_analyze, datadir, on_fetched=on_fetched)
+    # Sets, since they're looked up for every document and Part
+    fr_docs_unfetched = {s[1]["document_number"] for s in fr_docs_skipped}
+    fr_docs_no_full_text = {s[1]["document_number"] for s in fr_docs_skipped if s[2] == "no-full-text"}
+
+    # Aggregate the FR doc results into a DataFrame
+    fr_doc_results = {
+        "fr-docno": [], 
+        "fr-doc-type": [], 
+        "cfr-divs-referenced-in": [], 
+        "cfr-words-in-force": [], # Words of the referencing divisions that aren't reserved, vacated, or suspended
+        "cfr-citation-count": [], # Times the referencing divisions cite the document, e.g. 2 for an amendment and its correction
+        "fr-attribution-confidence": [], # Of the document's most confident attribution. See ATTRIBUTION_CONFIDENCE.
+        "fr-doc-citation": [], 
+        "fr-doc-volume": [], # The volume and start page of the citation as numbers, for sorting
+        "fr-doc-start-page": [],
+        "fr-doc-agencies": [], 
+        "fr-doc-agencies-shorthand": [], 
+        "fr-doc-title": [], 
+        "fr-doc-abstract": [], 
+        "fr-doc-publication-date": [], 
+        "fr-doc-effective-date": [], # Only with enrich
+        "fr-doc-significant": [],
+        "fr-doc-cfr-parts-affected": [],
+        "fr-doc-topics": [],
+        "fr-doc-president": [],
+        "fr-doc-regulations-dot-gov-info": [],
+        "fr-doc-full-text-xml-url": [],
+        "fr-doc-metadata-conflicts": [], # The fields the searches of different Parts disagreed on. See merge_docinfo.
+        "fr-doc-historical": [], # Scanned pages of the FR from before 1994 rather than a document. See fetch_historical_doc.
+        "fr-doc-ocr-quality": [], # The mean word confidence of the OCR of a historical document's scan, from 0 to 1. See ocr_pdf.
+        "fr-doc-page-count": [],
+        "fr-doc-char-count": [],
+        "fr-doc-word-count": [],
+        "fr-doc-section-count": [],
+        "fr-doc-table-count": [],
+        "fr-doc-table-density": [],
+        "fr-doc-image-count": [],
+        "fr-doc-rfa-statement-found": [],
+        "fr-doc-rfa-certified": [],
+        "fr-doc-rfa-analysis": [],
+        "fr-doc-rfa-small-entities": [],
+    }
+    
+    if enrich:
+        print("[*] Enriching FR document metadata...")
+
+    for docno, (cfr_divs, docinfo) in fr_docs_to_analyze.items():
+        if docno in fr_docs_unfetched:
+            continue
+        # Historical documents aren't in FederalRegister.gov's API
+        if enrich and not docinfo.get("historical"):
+            try:
+                enriched = enrich_fr_doc(docno, docinfo, datadir)
+                if "agency_names" in docinfo.get("metadata_conflicts", []) and enriched["agency_names"] != docinfo["agency_names"]:
+                    enriched["agencies"], enriched["agency_shorthand"] = issuing_agencies(docno, enriched, agency_abbrvs_by_name)
+                docinfo = enriched
+            except Exception as e:
+                report_error("enrichment-failed", f"{e}", docno=docno)
+        fr_doc_results["fr-docno"].append(docno),
+        # Searches cached before the type was fetched were all for Final Rules
+        fr_doc_results["fr-doc-type"].append(docinfo.get("type", "Rule")),
+        fr_doc_results["cfr-divs-referenced-in"].append(cfr_divs),
+        fr_doc_results["cfr-words-in-force"].append(sum(div[2] for div in cfr_divs if len(div) < 4 or not div[3])),
+        fr_doc_results["cfr-citation-count"].append(sum(div[4] for div in cfr_divs)),
+        fr_doc_results["fr-attribution-confidence"].append(max(div[5] for div in cfr_divs)),
+        fr_doc_results["fr-doc-citation"].append(docinfo["citation"]),
+        fr_doc_results["fr-doc-volume"].append(int(docinfo["citation"].split(" ")[0]) if docinfo["citation"] else None),
+        fr_doc_results["fr-doc-start-page"].append(docinfo.get("start_page")),
+        fr_doc_results["fr-doc-agencies"].append(docinfo["agencies"]),
+        fr_doc_results["fr-doc-agencies-shorthand"].append(docinfo["agency_shorthand"]),
+        fr_doc_results["fr-doc-title"].append(docinfo["title"]),
+        fr_doc_results["fr-doc-abstract"].append(docinfo["abstract"]),
+        fr_doc_results["fr-doc-publication-date"].append(parse_date(docinfo["publication_date"])),
+        fr_doc_results["fr-doc-effective-date"].append(parse_date(docinfo.get("effective_on"))),
+        fr_doc_results["fr-doc-significant"].append(docinfo.get("significant")),
+        fr_doc_results["fr-doc-cfr-parts-affected"].append(docinfo["cfr_references"]),
+        fr_doc_results["fr-doc-topics"].append(docinfo.get("topics")),
+        fr_doc_results["fr-doc-president"].append((docinfo.get("president") or {}).get("name")),
+        fr_doc_results["fr-doc-regulations-dot-gov-info"].append(docinfo.get("regulations_dot_gov_info")),
+        fr_doc_results["fr-doc-full-text-xml-url"].append(docinfo.get("full_text_xml_url")),
+        fr_doc_results["fr-doc-metadata-conflicts"].append(docinfo.get("metadata_conflicts", [])),
+        fr_doc_results["fr-doc-historical"].append(docinfo.get("historical", False)),
+        fr_doc_results["fr-doc-ocr-quality"].append(docinfo.get("ocr_quality")),
+        try:
+            stats = fr_doc_stats(docno, docinfo, datadir)
+        except Exception as e:
+            report_error("parse-warning", f"Couldn't compute document statistics: {e}", docno=docno)
+            stats = {}
+        for stat in ["page-count", "char-count", "word-count", "section-count", "table-count", "table-density", "image-count"]:
+            fr_doc_results[f"fr-doc-{stat}"].append(stats.get(stat))
+        try:
+            rfa = rfa_of_fr_doc(docno, datadir)
+        except Exception as e:
+            report_error("parse-warning", f"Couldn't extract the Regulatory Flexibility Act statement: {e}", docno=docno)
+            rfa = {}
+        for field in ["rfa-statement-found", "rfa-certified", "rfa-analysis", "rfa-small-entities"]:
+            fr_doc_results[f"fr-doc-{field}"].append(rfa.get(field))
+    # In chronological order, like the amendments to the CFR they made
+    fr_doc_results = pd.DataFrame(fr_doc_results).sort_values(["fr-doc-publication-date", "fr-docno"], kind="stable", ignore_index=True)
+
+    # Collect the description of what analysis was done per input CFR Part into a DataFrame
+    cfr_part_results = {
+        "cfr-title": [],
+        "cfr-part": [],
+        "cfr-chapter": [],
+        "cfr-part-agencies": [],
+        "fr-citations": [],
+        "fr-docs-affecting": [],
+        "fr-docs-attributed": [], # FR docnos
+        "fr-cita-unattributed": [], # FR citas
+        "fr-cita-attributions": [], # (FR cita, FR docno, match type, confidence). See ATTRIBUTION_CONFIDENCE.
+        "fr-cita-non-rule": [], # (FR cita, FR docno, FR doc type) of the citations of documents that aren't Final Rules
+        "cfr-divs-dead": [], # (division name, "reserved", "vacated", or "suspended")
+        "cfr-words-citing-fr": [], # Words in force of the divisions citing the FR
+        "cfr-words-attributed": [], # Of those, the words of the divisions with a citation attributed to an FR document
+        "cfr-div-hierarchy": [], # (division name, type, {level column: identifier, and its operative status, word count, and FR citations}, FR docnos). See DIVISION_LEVELS.
+        "fr-docs-unfetched": [], # FR docnos
+        "fr-docs-no-full-text": [], # FR docnos
+        "ecfr-amendments-since-snapshot": [], # Dates
+        "ecfr-corrections-since-snapshot": [], # FR citas of the corrections
+        "potentially-stale": [],
+        "attribution-error": [],
+    }
+
+    for (titleno, partno), status in fr_docs_to_analyze.part_coverages():
+        cfr_part_results["cfr-title"].append(titleno)
+        cfr_part_results["cfr-part"].append(str(partno))
+        cfr_part_results["cfr-chapter"].append(status["cfr-chapter"])
+        cfr_part_results["cfr-part-agencies"].append(status["cfr-part-agencies"])
+        cfr_part_results["fr-citations"].append(status["fr-citations"])
+        cfr_part_results["fr-docs-affecting"].append(status["fr-docs-affecting"])
+        cfr_part_results["fr-docs-attributed"].append(status["fr-docs-attributed"])
+        cfr_part_results["fr-cita-unattributed"].append(status["fr-cita-unattributed"])
+        cfr_part_results["fr-cita-attributions"].append(status.get("fr-cita-attributions", []))
+        cfr_part_results["fr-cita-non-rule"].append(status.get("fr-cita-non-rule", []))
+        cfr_part_results["cfr-divs-dead"].append(status.get("cfr-divs-dead", []))
+        cfr_part_results["cfr-words-citing-fr"].append(status.get("cfr-words-citing-fr"))
+        cfr_part_results["cfr-words-attributed"].append(status.get("cfr-words-attributed"))
+        cfr_part_results["cfr-div-hierarchy"].append(status.get("cfr-div-hierarchy", []))
+        cfr_part_results["fr-docs-unfetched"].append([docno for docno in status["fr-docs-attributed"] if docno in fr_docs_unfetched and docno not in fr_docs_no_full_text])
+        cfr_part_results["fr-docs-no-full-text"].append([docno for docno in status["fr-docs-attributed"] if docno in fr_docs_no_full_text])
+        cfr_part_results["ecfr-amendments-since-snapshot"].append([parse_date(date) for date in status["ecfr-amendments-since-snapshot"]])
+        cfr_part_results["ecfr-corrections-since-snapshot"].append(status["ecfr-corrections-since-snapshot"])
+        cfr_part_results["potentially-stale"].append(status["potentially-stale"])
+        cfr_part_results["attribution-error"].append("")
+    # Failed Parts are listed too, so they aren't mistaken for Parts with no FR documents
+    failed_errors = {(error["cfr_title"], error["cfr_part"]): error["message"] for error in ERRORS if error["category"] == "part-failed"}
+    for titleno, partno, part in failed_parts:
+        for column, values in cfr_part_results.items():
+            values.append([] if column.startswith(("fr-", "ecfr-", "cfr-div")) else None)
+        cfr_part_results["cfr-title"][-1] = titleno
+        cfr_part_results["cfr-part"][-1] = str(partno)
+        cfr_part_results["cfr-chapter"][-1] = part.get("chapter")
+        cfr_part_results["potentially-stale"][-1] = False
+        cfr_part_results["attribution-error"][-1] = failed_errors.get((titleno, str(partno)), "")
+    cfr_part_results = pd.DataFrame(cfr_part_results)
+
+    return fr_doc_results, cfr_part_results, fetch_status
@@ -0,0 +1,64 @@
+import importlib.util
+import os
+import pandas as pd
+
+#############################################################
+# Hooks for running custom code at the stages of backend.py #
+#############################################################
+
+# The functions a plugin can define, and what each is called with. Each returns its first argument, changed or not.
+PLUGIN_HOOKS = {
+    # The FR doc data and the CFR coverage, after the attribution. E.g., filter the documents or add columns to them.
+    "post_collection": "fr_doc_data, cfr_cov",
+    # A dict of each FR document's row of the FR doc data, before its LLM analysis
+    "pre_analysis": "fr_doc",
+    # A dict of each row of fr_doc_analysis, after the LLM analysis. E.g., add the label of a proprietary classifier.
+    "post_analysis": "fr_doc",
+}
+
+
+def load_plugins(paths):
+    '''
+    Load each Python file in paths as a plugin defining any of the functions of PLUGIN_HOOKS. E.g., a plugin with
+
+    def post_analysis(fr_doc):
+        fr_doc["my-label"] = my_classifier(fr_doc["fr-doc-abstract"])
+        return fr_doc
+
+    adds the my-label column to the results. Returns {hook: [function]}, with the functions in the order of paths. Raises a
+    ValueError if a plugin defines none of the hooks.
+    '''
+    plugins = {hook: [] for hook in PLUGIN_HOOKS}
+    for path in paths:
+        spec = importlib.util.spec_from_file_location(os.path.splitext(os.path.basename(path))[0], path)
+        if spec is None:
+            raise ValueError(f"{path} isn't a Python file")
+        module = importlib.util.module_from_spec(spec)
+        spec.loader.exec_module(module)
+        hooks = [hook for hook in PLUGIN_HOOKS if callable(getattr(module, hook, None))]
+        if len(hooks) == 0:
+            raise ValueError(f"{path} defines none of {', '.join(PLUGIN_HOOKS)}")
+        for hook in hooks:
+            plugins[hook].append(getattr(module, hook))
+    return plugins
+
+
+def post_collection(plugins, fr_doc_data, cfr_cov):
+    for fn in plugins["post_collection"]:
+        fr_doc_data = fn(fr_doc_data, cfr_cov)
+    return fr_doc_data
+
+
+def transform_rows(plugins, hook, table):
+    '''
+    Pass each row of table, as a dict, through the functions of hook in turn. Returns the table of the rows they return.
+    '''
+    if len(plugins[hook]) == 0:
+        return table
+    rows = []
+    for _, row in table.iterrows():
+        row = row.to_dict()
+        for fn in plugins[hook]:
+            row = fn(row)
+        rows.append(row)
+    return pd.DataFrame(rows, columns=None if len(rows) > 0 else table.columns)
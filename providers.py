@@ -0,0 +1,272 @@
+from frapi import HTTP_SESSION
+import os
+import re
+import requests
+import threading
+from types import SimpleNamespace
+import time
+
+##########################################
+# Global constants for the LLM providers #
+##########################################
+
+# The base URLs and keys of the providers, which can be set in .env. OPENAI_API_URL can point at any OpenAI-compatible
+# server, e.g. vLLM or a company's gateway.
+OPENAI_API_URL = os.getenv("OPENAI_API_URL", "https://api.openai.com/v1").rstrip("/")
+OPENAI_API_KEY = os.getenv("OPENAI_API_KEY")
+ANTHROPIC_API_URL = os.getenv("ANTHROPIC_API_URL", "https://api.anthropic.com").rstrip("/")
+ANTHROPIC_API_KEY = os.getenv("ANTHROPIC_API_KEY")
+ANTHROPIC_VERSION = "2023-06-01"
+# A local Ollama server, which keeps the FR documents on the machine
+OLLAMA_URL = os.getenv("OLLAMA_URL", "http://localhost:11434").rstrip("/")
+# The HTTP statuses of a request that's worth retrying: rate limits, overload, and server errors
+RETRY_STATUSES = {408, 409, 429, 500, 502, 503, 504, 529}
+# THIS IS MUTATED!!! How many times a failed request to a provider is retried, waiting twice as long each time, before the
+# error is raised to chat, which may then fail over to the next model. See LlmClient.post.
+LLM_MAX_RETRIES = 4
+
+
+#################################
+# Classes for the LLM providers #
+#################################
+
+def chat_response(text, input_tokens, output_tokens, search_queries=None, documents=None):
+    '''
+    A chat response in the shape of the Cohere client's, which is what llm.py reads, e.g. response.text and
+    response.meta.billed_units.input_tokens. The other providers don't cite the documents they're given.
+    '''
+    return SimpleNamespace(
+        text=text,
+        search_queries=search_queries,
+        citations=[],
+        documents=documents or [],
+        meta=SimpleNamespace(billed_units=SimpleNamespace(input_tokens=input_tokens, output_tokens=output_tokens)),
+    )
+
+
+class LlmClient:
+    '''
+    The interface llm.py uses for a provider's chat, embeddings, and reranking, with the keyword arguments of Cohere's
+    client. A subclass implements complete, and embed if the provider has embeddings. The requests of a client are limited
+    to calls_per_minute, which can be overridden with the provider's <NAME>_CALLS_PER_MINUTE in .env, and retried by post.
+    '''
+    name = None
+    calls_per_minute = None
+    # The model that writes the search queries of a question, or None for the model in use. See Chatbot.run.
+    search_queries_model = None
+    default_embedding_model = None
+
+    def __init__(self):
+        self.calls_per_minute = int(os.getenv(f"{self.name.upper()}_CALLS_PER_MINUTE", 0)) or self.calls_per_minute
+        # The times of the calls of the last minute, shared by the threads of a streaming run
+        self.call_times = []
+        self.lock = threading.Lock()
+        # {conversation_id: [message]} of the chats so far, since only Cohere keeps them itself
+        self.conversations = {}
+        self.dimensions = {}
+
+
+    def throttle(self):
+        '''
+        Wait until a call would be within calls_per_minute.
+        '''
+        if self.calls_per_minute is None:
+            return
+        with self.lock:
+            while True:
+                now = time.time()
+                self.call_times = [t for t in self.call_times if now - t < 60]
+                if len(self.call_times) < self.calls_per_minute:
+                    self.call_times.append(now)
+                    return
+                time.sleep(60 - (now - self.call_times[0]))
+
+
+    def post(self, url, body, headers={}):
+        '''
+        POST body as JSON to url and return the JSON response, retrying the errors in RETRY_STATUSES, failed connections, and
+        timeouts up to LLM_MAX_RETRIES times. The wait doubles each time from 2 s, unless the provider asks for a longer one with a
+        Retry-After header.
+        '''
+        for attempt in range(LLM_MAX_RETRIES + 1):
+            self.throttle()
+            try:
+                res = HTTP_SESSION.post(url, json=body, headers=headers, timeout=300)
+            except (requests.ConnectionError, requests.Timeout):
+                if attempt == LLM_MAX_RETRIES:
+                    raise
+                time.sleep(2 ** (attempt + 1))
+                continue
+            if res.status_code in RETRY_STATUSES and attempt < LLM_MAX_RETRIES:
+                retry_after = res.headers.get("Retry-After", "")
+                time.sleep(max(2 ** (attempt + 1), float(retry_after) if retry_after.isdigit() else 0))
+                continue
+            if not res.ok:
+                raise ValueError(f"{self.name} returned {res.status_code}: {res.text[:500]}")
+            return res.json()
+
+
+    def chat(self, model, message, preamble="", documents=None, search_queries_only=False, conversation_id=None, seed=None, temperature=None, **request):
+        '''
+        Cohere's chat, for a provider without grounded generation: the documents are put in the system prompt, and search
+        queries are asked for as a list, one per line.
+        '''
+        system = preamble
+        if search_queries_only:
+            system += "\n\n## Instructions\nDon't answer the user's message. Write the search queries, one per line, that would find the passages of the document needed to answer it. Write nothing else."
+        elif documents:
+            system += "\n\n## Documents\n" + "\n\n".join(f"Document {i}: {document['title']}\n{document['text']}" for i, document in enumerate(documents))
+        history = self.conversations.get(conversation_id, []) if conversation_id is not None else []
+        messages = history + [{"role": "user", "content": message}]
+        text, input_tokens, output_tokens = self.complete(model, system, messages, seed=seed, temperature=temperature)
+        if search_queries_only:
+            queries = [re.sub(r"^\s*(?:[-*]|\d+[.)])\s*", "", line).strip() for line in text.splitlines()]
+            return chat_response("", input_tokens, output_tokens, search_queries=[SimpleNamespace(text=query) for query in queries if query != ""])
+        if conversation_id is not None:
+            self.conversations[conversation_id] = messages + [{"role": "assistant", "content": text}]
+        return chat_response(text, input_tokens, output_tokens, documents=documents)
+
+
+    def end_conversation(self, conversation_id):
+        '''
+        Drop the messages of a finished conversation.
+        '''
+        self.conversations.pop(conversation_id, None)
+
+
+    def complete(self, model, system, messages, seed=None, temperature=None):
+        '''
+        Returns (text, input tokens, output tokens) of the reply of model to messages, [{"role", "content"}].
+        '''
+        raise NotImplementedError
+
+
+    def embed(self, texts, model, input_type):
+        raise ValueError(f"{self.name} has no embeddings. Pass another --embedding-provider.")
+
+
+    def dimension(self, model):
+        '''
+        The number of dimensions of model's embeddings, found by embedding a word once.
+        '''
+        if model not in self.dimensions:
+            self.dimensions[model] = len(self.embed(["dimension"], model, "search_query")[0])
+        return self.dimensions[model]
+
+
+    def rerank(self, query, documents, top_n):
+        '''
+        The indexes of the top_n of documents most relevant to query, in order. Only Cohere reranks, so the others keep the
+        order of the nearest-neighbor search.
+        '''
+        return list(range(min(top_n, len(documents))))
+
+
+class CohereClient(LlmClient):
+    '''
+    The Cohere client co, whose rate limits are enforced by llm.rate_limit_check and whose retries are its own.
+    '''
+    name = "cohere"
+    search_queries_model = "command-r"
+    default_embedding_model = "embed-english-v3.0"
+
+    def __init__(self, co):
+        super().__init__()
+        self.co = co
+
+
+    def chat(self, **request):
+        return self.co.chat(**request)
+
+
+    def embed(self, texts, model, input_type):
+        return self.co.embed(texts=texts, model=model, input_type=input_type).embeddings
+
+
+    def dimension(self, model):
+        # Cohere's v3 embeddings
+        return 1024
+
+
+    def rerank(self, query, documents, top_n):
+        results = self.co.rerank(query=query, documents=documents, top_n=top_n, model="rerank-english-v3.0", rank_fields=["title", "text"])
+        return [result.index for result in results.results]
+
+
+class OpenAIClient(LlmClient):
+    '''
+    OpenAI's API, or any server compatible with its chat completions and embeddings, at OPENAI_API_URL.
+    '''
+    name = "openai"
+    calls_per_minute = 500
+    default_embedding_model = "text-embedding-3-small"
+
+    def headers(self):
+        return {"Authorization": f"Bearer {OPENAI_API_KEY}"} if OPENAI_API_KEY else {}
+
+
+    def complete(self, model, system, messages, seed=None, temperature=None):
+        body = {"model": model, "messages": [{"role": "system", "content": system}] + messages}
+        if seed is not None:
+            body["seed"] = seed
+        if temperature is not None:
+            body["temperature"] = temperature
+        response = self.post(f"{OPENAI_API_URL}/chat/completions", body, self.headers())
+        usage = response.get("usage") or {}
+        return response["choices"][0]["message"]["content"] or "", usage.get("prompt_tokens", 0), usage.get("completion_tokens", 0)
+
+
+    def embed(self, texts, model, input_type):
+        response = self.post(f"{OPENAI_API_URL}/embeddings", {"model": model, "input": texts}, self.headers())
+        return [item["embedding"] for item in sorted(response["data"], key=lambda item : item["index"])]
+
+
+class AnthropicClient(LlmClient):
+    '''
+    Anthropic's Messages API, which has no embeddings and no seed.
+    '''
+    name = "anthropic"
+    calls_per_minute = 50
+    max_tokens = 4096
+
+    def complete(self, model, system, messages, seed=None, temperature=None):
+        body = {"model": model, "max_tokens": self.max_tokens, "system": system, "messages": messages}
+        if temperature is not None:
+            body["temperature"] = temperature
+        headers = {"x-api-key": ANTHROPIC_API_KEY or "", "anthropic-version": ANTHROPIC_VERSION}
+        response = self.post(f"{ANTHROPIC_API_URL}/v1/messages", body, headers)
+        usage = response.get("usage") or {}
+        text = "".join(block.get("text", "") for block in response["content"] if block["type"] == "text")
+        return text, usage.get("input_tokens", 0), usage.get("output_tokens", 0)
+
+
+class OllamaClient(LlmClient):
+    '''
+    A local Ollama server at OLLAMA_URL, with the models pulled with e.g. `ollama pull llama3.1`.
+    '''
+    name = "ollama"
+    default_embedding_model = "nomic-embed-text"
+
+    def complete(self, model, system, messages, seed=None, temperature=None):
+        options = {}
+        if seed is not None:
+            options["seed"] = seed
+        if temperature is not None:
+            options["temperature"] = temperature
+        body = {"model": model, "messages": [{"role": "system", "content": system}] + messages, "stream": False, "options": options}
+        response = self.post(f"{OLLAMA_URL}/api/chat", body)
+        return response["message"]["content"], response.get("prompt_eval_count", 0), response.get("eval_count", 0)
+
+
+    def embed(self, texts, model, input_type):
+        return self.post(f"{OLLAMA_URL}/api/embed", {"model": model, "input": texts})["embeddings"]
+
+
+# The providers that can be chosen with --llm-provider and --embedding-provider, or LLM_PROVIDER and EMBEDDING_PROVIDER in
+# .env. Cohere's client is made by llm.llm_client.
+LLM_PROVIDERS = {
+    "cohere": CohereClient,
+    "openai": OpenAIClient,
+    "anthropic": AnthropicClient,
+    "ollama": OllamaClient,
+}
@@ -0,0 +1,163 @@
+import contextlib
+import datetime
+import json
+import os
+import sys
+import time
+
+###########################################################
+# Functions for reporting errors and the outcome of a run #
+###########################################################
+
+# THIS IS MUTATED!!! A list of every non-fatal error of the run. See report_error.
+ERRORS = []
+
+
+def report_error(category, message, **context):
+    '''
+    Record a non-fatal error, e.g. a document that couldn't be fetched, so it can be reported at the end of the run.
+    category is a short machine-readable label like "fetch-failed", and context is any identifying data, e.g. docno="...".
+    '''
+    ERRORS.append({"category": category, "message": message, **context})
+
+
+# THIS IS MUTATED!!! If set, a violated assumption about the data of the eCFR and FederalRegister.gov APIs is fatal to the
+# CFR Part it's in rather than reported. See check_assumption.
+STRICT = False
+# THIS IS MUTATED!!! The messages of the violated assumptions reported so far, so each is only reported once
+ASSUMPTIONS_VIOLATED = set()
+
+
+def check_assumption(ok, message, **context):
+    '''
+    Check an assumption about the data of the eCFR or FederalRegister.gov APIs, e.g. that a search returned as many results
+    as it counted. If it's violated, raise an AssertionError with --strict, or else report it as an "assumption-violated"
+    error, once per message, and let the caller carry on. Returns ok.
+    '''
+    if ok:
+        return True
+    if STRICT:
+        raise AssertionError(message)
+    if message not in ASSUMPTIONS_VIOLATED:
+        ASSUMPTIONS_VIOLATED.add(message)
+        report_error("assumption-violated", message, **context)
+    return False
+
+
+def write_errors(outdir):
+    '''
+    Write every error recorded with report_error to outdir/errors.json, along with the count of each category, and print
+    the counts as a table.
+    '''
+    counts = {}
+    for error in ERRORS:
+        counts[error["category"]] = counts.get(error["category"], 0) + 1
+    with open(os.path.join(outdir, "errors.json"), "w") as f:
+        json.dump({"counts": counts, "errors": ERRORS}, f, indent=2, default=str)
+
+    print(f"[*] {len(ERRORS)} non-fatal errors. See {os.path.join(outdir, 'errors.json')}")
+    if len(counts) > 0:
+        width = max(map(len, counts))
+        for category, count in sorted(counts.items(), key=lambda item : -item[1]):
+            print(f"\t{category:<{width}}  {count}")
+
+
+def format_eta(t0, done, total):
+    '''
+    Project when a loop over total items that started at time.time() t0 will finish, from the throughput so far, e.g.
+    "ETA 14:05 (in 1h 12m)". Returns "" until an item is done.
+    '''
+    if done == 0:
+        return ""
+    remaining = (time.time() - t0) / done * (total - done)
+    return f"ETA {format_finish_time(remaining)}"
+
+
+def format_finish_time(seconds):
+    finish = datetime.datetime.now() + datetime.timedelta(seconds=seconds)
+    hours, minutes = int(seconds // 3600), int(seconds % 3600 // 60)
+    duration = f"{hours}h {minutes}m" if hours > 0 else f"{minutes}m {int(seconds % 60)}s"
+    return f"{finish.strftime('%Y-%m-%d %H:%M')} (in {duration})"
+
+
+# Exit codes of backend.py, for scripts orchestrating runs
+EXIT_SUCCESS = 0
+EXIT_PARTIAL = 2 # Completed, but some documents failed. See errors.json.
+EXIT_CONFIG = 3 # Bad arguments or environment. Nothing was analyzed.
+EXIT_BUDGET_EXCEEDED = 4 # The estimated LLM workload is over --llm-budget. Nothing was sent to the LLM.
+EXIT_CANARY_FAILED = 5 # The answers to the --canary documents were less accurate than --canary-min-accuracy. Nothing was analyzed.
+EXIT_INTERRUPTED = 130
+EXIT_STATUSES = {
+    EXIT_SUCCESS: "success",
+    EXIT_PARTIAL: "partial",
+    EXIT_CONFIG: "config-error",
+    EXIT_BUDGET_EXCEEDED: "budget-exceeded",
+    EXIT_CANARY_FAILED: "canary-failed",
+    EXIT_INTERRUPTED: "interrupted",
+}
+# Error categories meaning that part of the requested analysis is missing from the results, rather than just a warning
+FAILURE_CATEGORIES = {"part-failed", "fetch-failed", "no-full-text", "enrichment-failed", "llm-error"}
+
+
+def exit_with_summary(code, message=None, **summary):
+    '''
+    Exit with code after printing a one-line JSON summary of the run, which is always the last line of output, e.g.
+    {"exit-code": 2, "status": "partial", "failures": 3, ...}. summary is any other data to include, e.g. outdir="...".
+    '''
+    if message is not None:
+        print(f"ERROR: {message}")
+    if USAGE_STATS_PATH is not None:
+        record_usage(code)
+    counts = {}
+    for error in ERRORS:
+        counts[error["category"]] = counts.get(error["category"], 0) + 1
+    print(json.dumps({
+        "exit-code": code,
+        "status": EXIT_STATUSES[code],
+        "message": message,
+        "failures": sum(count for category, count in counts.items() if category in FAILURE_CATEGORIES),
+        "error-counts": counts,
+        **summary,
+    }, default=str))
+    sys.exit(code)
+
+
+#####################################################
+# Functions for recording local, opt-in usage stats #
+#####################################################
+
+# If set, a line of usage stats is appended to this file at the end of each run. See record_usage.
+USAGE_STATS_PATH = None
+# THESE ARE MUTATED!!! The features used by the run, and how long each stage of it took, in seconds.
+USAGE_FEATURES = []
+STAGE_SECONDS = {}
+
+
+@contextlib.contextmanager
+def timed_stage(name):
+    '''
+    Time the enclosed code as the stage called name of the run, for the usage stats. E.g.,
+
+    with timed_stage("llm-analysis"):
+        fr_doc_analysis = llm_analysis(fr_doc_data, args.datadir)
+    '''
+    t0 = time.time()
+    try:
+        yield
+    finally:
+        STAGE_SECONDS[name] = STAGE_SECONDS.get(name, 0) + time.time() - t0
+
+
+def record_usage(code):
+    '''
+    Append the features used by the run, the duration of each of its stages, and its exit code to USAGE_STATS_PATH as one
+    JSON line. Only the names of the options used are recorded, never their values, and the file is never sent anywhere.
+    Summarize it with `python store.py stats`.
+    '''
+    with open(USAGE_STATS_PATH, "a") as f:
+        f.write(json.dumps({
+            "date": datetime.datetime.now().isoformat(timespec="seconds"),
+            "features": USAGE_FEATURES,
+            "stage-seconds": STAGE_SECONDS,
+            "exit-code": code,
+        }) + "\n")
@@ -0,0 +1,321 @@
+import ast
+import atexit
+import base64
+import contextlib
+import json
+import os
+import random
+import runpy
+import shutil
+import sys
+import tempfile
+from types import SimpleNamespace
+import urllib.parse
+
+# The LLM client is made when llm is imported, and is replaced by SelftestCohere below before it's used
+os.environ.setdefault("COHERE_API_KEY", "selftest")
+import frapi
+from frapi import ECFR_DATE, FR_API_URL, agency_abbreviations, fixture_path, fixture_response, fr_agencies, http_get_all_pages
+import llm
+import pandas as pd
+import report
+from report import EXIT_SUCCESS, FAILURE_CATEGORIES
+from store import verify_store
+
+#############################################################################
+# A tiny fixture CFR Part, its FR documents, and a mock LLM for a self-test #
+#############################################################################
+
+# A made-up Part of Title 40 with three FR citations: one of the page a Final Rule starts on, one of a later page of
+# another, and one of no Final Rule. A third Final Rule affects the Part but isn't cited.
+SELFTEST_TITLE = "40"
+SELFTEST_PART = "9999"
+SELFTEST_AGENCY = {"id": 145, "name": "Environmental Protection Agency", "short_name": "EPA", "parent_id": None, "url": "https://www.federalregister.gov/agencies/environmental-protection-agency"}
+# The agencies list replayed to check the short names, with an agency FederalRegister.gov has no short name for
+SELFTEST_AGENCIES = [SELFTEST_AGENCY, {"name": "Office of the Special Inspector General for Afghanistan Reconstruction", "short_name": None, "parent_id": None}]
+SELFTEST_AGENCY_SHORT_NAMES = {SELFTEST_AGENCY["name"]: "EPA", "Office of the Special Inspector General for Afghanistan Reconstruction": "OSIGAR"}
+SELFTEST_DOCS = [
+    {"document_number": "2020-00001", "citation": "85 FR 100", "start_page": 100, "end_page": 110, "publication_date": "2020-01-02", "title": "Selftest Fixture: Purpose"},
+    {"document_number": "2021-00002", "citation": "86 FR 200", "start_page": 200, "end_page": 230, "publication_date": "2021-02-01", "title": "Selftest Fixture: Definitions"},
+    {"document_number": "2022-00003", "citation": "87 FR 300", "start_page": 300, "end_page": 301, "publication_date": "2022-03-01", "title": "Selftest Fixture: Technical Amendment"},
+]
+SELFTEST_PART_XML = f'''<DIV5 N="{SELFTEST_PART}" TYPE="PART">
+<HEAD>PART {SELFTEST_PART}—SELFTEST FIXTURE</HEAD>
+<DIV8 N="{SELFTEST_PART}.1" TYPE="SECTION"><HEAD>§ {SELFTEST_PART}.1 Purpose.</HEAD><P>This part exists only to test Doge Guard.</P><CITA>[85 FR 100, Jan. 2, 2020]</CITA></DIV8>
+<DIV8 N="{SELFTEST_PART}.2" TYPE="SECTION"><HEAD>§ {SELFTEST_PART}.2 Definitions.</HEAD><P>Fixture means a document made up for a test.</P><CITA>[86 FR 215, Feb. 1, 2021]</CITA></DIV8>
+<DIV8 N="{SELFTEST_PART}.3" TYPE="SECTION"><HEAD>§ {SELFTEST_PART}.3 Records.</HEAD><P>Keep records of each test.</P><CITA>[84 FR 50, Jan. 3, 2019]</CITA></DIV8>
+</DIV5>
+'''
+# What the run should find
+SELFTEST_ATTRIBUTED = {"2020-00001", "2021-00002"}
+SELFTEST_UNATTRIBUTED = {"84 FR 50"}
+
+
+def selftest_html(fr_doc):
+    '''
+    The full text of a fixture FR document, with enough sections to be chunked into more pieces than the LLM retrieves.
+    '''
+    sections = "".join(f"<h2>{i}. Comment and Response {i}</h2><p>{'The agency received a comment about the fixture and responded to it. ' * 10}</p>" for i in range(1, 21))
+    return f"<html><head><title>{fr_doc['title']}</title></head><body><h1>{fr_doc['title']}</h1>{sections}</body></html>".encode()
+
+
+def selftest_search_result(fr_doc):
+    docno = fr_doc["document_number"]
+    return {
+        **fr_doc,
+        "abstract": f"A made-up Final Rule amending {SELFTEST_TITLE} CFR Part {SELFTEST_PART}, for testing.",
+        "agencies": [{"name": SELFTEST_AGENCY["name"], "id": SELFTEST_AGENCY["id"]}],
+        "agency_names": [SELFTEST_AGENCY["name"]],
+        "body_html_url": f"https://www.federalregister.gov/documents/full_text/html/{docno}.html",
+        "cfr_references": [{"title": int(SELFTEST_TITLE), "part": int(SELFTEST_PART)}],
+        "pdf_url": f"https://www.govinfo.gov/content/pkg/FR-{fr_doc['publication_date']}/pdf/{docno}.pdf",
+        "significant": False,
+        "type": "Rule",
+    }
+
+
+class SelftestSession:
+    '''
+    Answers the requests of http_get from the fixture instead of the network. With offline set, every request fails
+    instead, to check that a second run is answered from the caches of the first.
+    '''
+    def __init__(self):
+        self.offline = False
+
+
+    def get(self, url, headers={}, params=None):
+        if self.offline:
+            raise ConnectionError(f"The self-test is offline, but {url} was requested")
+        parsed = urllib.parse.urlparse(url)
+        path, query = parsed.path, urllib.parse.parse_qs(parsed.query)
+        content_type = "application/json"
+        if "/versioner/v1/structure/" in path:
+            part = {"type": "part", "identifier": SELFTEST_PART, "label_description": "Selftest Fixture", "reserved": False, "children": []}
+            chapter = {"type": "chapter", "identifier": "I", "reserved": False, "children": [part]}
+            content = json.dumps({"type": "title", "identifier": SELFTEST_TITLE, "reserved": False, "children": [chapter]}).encode()
+        elif "/versioner/v1/full/" in path:
+            content, content_type = SELFTEST_PART_XML.encode(), "application/xml"
+        elif path.endswith("/admin/v1/agencies.json"):
+            content = json.dumps({"agencies": [{"name": SELFTEST_AGENCY["name"], "cfr_references": [{"title": int(SELFTEST_TITLE), "chapter": "I"}]}]}).encode()
+        elif path.endswith("/agencies"):
+            content = json.dumps([SELFTEST_AGENCY]).encode()
+        elif path.endswith("/documents.json"):
+            results = [selftest_search_result(fr_doc) for fr_doc in SELFTEST_DOCS] if query.get("conditions[type][]") == ["RULE"] else []
+            content = json.dumps({"count": len(results), "results": results}).encode()
+        elif path.endswith(".pdf"):
+            content, content_type = b"%PDF-1.4\n% Selftest fixture\n%%EOF\n", "application/pdf"
+        elif "/full_text/html/" in path:
+            docno = os.path.basename(path).removesuffix(".html")
+            content, content_type = selftest_html(next(fr_doc for fr_doc in SELFTEST_DOCS if fr_doc["document_number"] == docno)), "text/html; charset=utf-8"
+        else:
+            return fixture_response({"url": url, "status_code": 404, "headers": {"Content-Type": "text/plain"}, "content": base64.b64encode(b"Not Found").decode()})
+        return fixture_response({"url": url, "status_code": 200, "headers": {"Content-Type": content_type}, "content": base64.b64encode(content).decode()})
+
+
+class SelftestCohere:
+    '''
+    Stands in for the Cohere client: embeddings are pseudo-random but repeatable, reranking keeps the retrieved order, and
+    every question is answered "No."
+    '''
+    def embed(self, texts, model=None, input_type=None):
+        embeddings = []
+        for text in texts:
+            rng = random.Random(text)
+            embeddings.append([rng.uniform(-1, 1) for _ in range(1024)])
+        return SimpleNamespace(embeddings=embeddings)
+
+
+    def rerank(self, query, documents, top_n, model=None, rank_fields=None):
+        return SimpleNamespace(results=[SimpleNamespace(index=i) for i in range(min(top_n, len(documents)))])
+
+
+    def chat(self, message, search_queries_only=False, documents=None, **request):
+        meta = SimpleNamespace(billed_units=SimpleNamespace(input_tokens=len(message) // 4, output_tokens=1))
+        if search_queries_only:
+            return SimpleNamespace(text="", search_queries=[SimpleNamespace(text=message.strip()[:200])], citations=None, documents=None, meta=meta)
+        return SimpleNamespace(text="No.", search_queries=None, citations=[], documents=documents or [], meta=meta)
+
+
+def run_backend(datadir, log, *args):
+    '''
+    Run backend.py on the fixture Part in datadir, as if from the command line with any more args, with its output written to
+    log. Returns its exit code and the errors it reported.
+    '''
+    report.ERRORS.clear()
+    argv = sys.argv
+    sys.argv = ["backend.py", "--Part", SELFTEST_TITLE, SELFTEST_PART, "--llm-provider", "cohere", "--embedding-provider", "cohere", *args, datadir]
+    code = None
+    try:
+        with contextlib.redirect_stdout(log):
+            runpy.run_path(os.path.join(os.path.dirname(os.path.abspath(__file__)), "backend.py"), run_name="__main__")
+    except SystemExit as e:
+        code = e.code
+    finally:
+        sys.argv = argv
+    return code, list(report.ERRORS)
+
+
+@contextlib.contextmanager
+def replaying(fixtures_dir, responses):
+    '''
+    Answer http_get from fixtures of responses, {url: JSON}, written to fixtures_dir as if recorded with --record-fixtures.
+    '''
+    os.makedirs(fixtures_dir, exist_ok=True)
+    for url, content in responses.items():
+        with open(fixture_path(fixtures_dir, url), "w") as f:
+            json.dump({"url": url, "status_code": 200, "headers": {"Content-Type": "application/json"}, "content": base64.b64encode(json.dumps(content).encode()).decode()}, f)
+    frapi.REPLAY_FIXTURES_DIR = fixtures_dir
+    try:
+        yield
+    finally:
+        frapi.REPLAY_FIXTURES_DIR = None
+
+
+def check_agencies(datadir, check):
+    '''
+    Check the short names of an agencies list replayed from a fixture: an agency with one keeps it, and one without keeps
+    its name and gets an abbreviation made from it.
+    '''
+    frapi.FR_AGENCIES = None
+    try:
+        with replaying(os.path.join(datadir, "agencies"), {f"{FR_API_URL}/agencies": SELFTEST_AGENCIES}):
+            abbrvs = agency_abbreviations(fr_agencies(datadir))
+    finally:
+        frapi.FR_AGENCIES = None
+    check("agency short names", abbrvs == SELFTEST_AGENCY_SHORT_NAMES, f"{abbrvs}" if abbrvs != SELFTEST_AGENCY_SHORT_NAMES else "")
+
+
+def check_pages(fixtures_dir, check):
+    '''
+    Check http_get_all_pages against replayed fixtures: the results of every page are merged, a response that isn't paged is
+    returned as it is, a search of more than max_pages is cut off with an error, and one counting more than max_count
+    returns its first page alone. A page that shouldn't be requested has no fixture, so requesting it fails.
+    '''
+    urls = [f"{FR_API_URL}/documents.json?selftest=paging&page={page}" for page in range(1, 4)]
+    pages = {url: {"count": 3, "results": [{"page": page}]} for page, url in enumerate(urls, 1)}
+    for url, next_url in zip(urls, urls[1:]):
+        pages[url]["next_page_url"] = next_url
+    with replaying(fixtures_dir, {**pages, f"{FR_API_URL}/agencies": SELFTEST_AGENCIES}):
+        report.ERRORS.clear()
+        merged = http_get_all_pages(urls[0])["results"]
+        passed = merged == [{"page": 1}, {"page": 2}, {"page": 3}] and len(report.ERRORS) == 0
+        check("paging: merged pages", passed, f"{merged}" if not passed else "")
+        unpaged = http_get_all_pages(f"{FR_API_URL}/agencies")
+        check("paging: unpaged response", unpaged == SELFTEST_AGENCIES, f"{unpaged}" if unpaged != SELFTEST_AGENCIES else "")
+        truncated = http_get_all_pages(urls[0], max_pages=2)["results"]
+        errors = [error["category"] for error in report.ERRORS]
+        passed = truncated == [{"page": 1}, {"page": 2}] and errors == ["truncated-results"]
+        check("paging: max_pages cutoff", passed, f"{truncated}, errors {errors}" if not passed else "")
+    report.ERRORS.clear()
+    with replaying(fixtures_dir + "-first-page", {urls[0]: pages[urls[0]]}):
+        try:
+            first = http_get_all_pages(urls[0], max_count=2)["results"]
+            check("paging: max_count", first == [{"page": 1}], f"{first}" if first != [{"page": 1}] else "")
+        except FileNotFoundError as e:
+            check("paging: max_count", False, f"{e}")
+    report.ERRORS.clear()
+
+
+def selftest(datadir):
+    '''
+    Run the whole pipeline on the fixture Part in datadir, then again from its caches without the network, and again in a new
+    datadir from the fixtures the first run recorded, and check the attribution, the fetched documents, the LLM analysis,
+    the written results, and the document store. Then check the agencies' short names and the paging of searches against
+    replayed fixtures. Returns a list of (check, passed, detail).
+    '''
+    session, cohere = SelftestSession(), SelftestCohere()
+    frapi.HTTP_SESSION = session
+    frapi.CACHE_SERVER_URL = None
+    frapi.REPLAY_FIXTURES_DIR = None
+    llm.co = cohere
+    checks = []
+    def check(name, passed, detail=""):
+        checks.append((name, passed, detail))
+        print(f"\t[{'*' if passed else '!'}] {name}: {'ok' if passed else 'FAILED'}{' (' + detail + ')' if detail else ''}")
+
+    log = open(os.path.join(datadir, "selftest.log"), "w")
+    fixtures_dir = datadir.rstrip(os.sep) + "-fixtures"
+    atexit.register(shutil.rmtree, fixtures_dir, ignore_errors=True)
+    print(f"[*] Running backend.py on {SELFTEST_TITLE} CFR Part {SELFTEST_PART}, a fixture, in {datadir}...")
+    code, errors = run_backend(datadir, log, "--record-fixtures", fixtures_dir)
+    failures = [error for error in errors if error["category"] in FAILURE_CATEGORIES]
+    check("run", code == EXIT_SUCCESS, f"exit code {code}" if code != EXIT_SUCCESS else "")
+    check("no failures", len(failures) == 0, "; ".join(f"{error['category']}: {error['message']}" for error in failures))
+
+    outdir = os.path.join(datadir, "runs", "latest")
+    try:
+        fr_doc_analysis = pd.read_csv(os.path.join(outdir, "fr_doc_analysis.csv"))
+        cfr_coverage = pd.read_csv(os.path.join(outdir, "cfr_coverage.csv"))
+    except OSError as e:
+        check("results", False, f"{e}")
+        log.close()
+        return checks
+    check("results", True)
+    docnos = set(fr_doc_analysis["fr-docno"])
+    check("attribution", docnos == SELFTEST_ATTRIBUTED, f"attributed {', '.join(sorted(docnos))}" if docnos != SELFTEST_ATTRIBUTED else "")
+    unattributed = set(ast.literal_eval(cfr_coverage["fr-cita-unattributed"].iloc[0])) if len(cfr_coverage) == 1 else set()
+    check("unattributed citations", unattributed == SELFTEST_UNATTRIBUTED, f"unattributed {', '.join(sorted(unattributed))}" if unattributed != SELFTEST_UNATTRIBUTED else "")
+    answers = list(fr_doc_analysis.get("llm-answer", pd.Series(dtype=str)).astype(str))
+    check("LLM analysis", len(answers) == len(SELFTEST_ATTRIBUTED) and all(answer.startswith("No") for answer in answers), f"answers {answers}")
+    bad_docs = verify_store(datadir, quarantine=False)
+    check("document store", len(bad_docs) == 0, "; ".join(f"{docno}: {', '.join(problems)}" for docno, problems in bad_docs.items()))
+
+    # The same run, from a copy of the caches, must not need the network
+    cached_datadir = datadir.rstrip(os.sep) + "-cached"
+    shutil.copytree(datadir, cached_datadir, ignore=shutil.ignore_patterns(".lock", "runs"))
+    atexit.register(shutil.rmtree, cached_datadir, ignore_errors=True)
+    session.offline = True
+    print(f"[*] Running backend.py again from the caches, without the network...")
+    code, errors = run_backend(cached_datadir, log)
+    check("cached run", code == EXIT_SUCCESS and not any(error["category"] in FAILURE_CATEGORIES for error in errors), f"exit code {code}" if code != EXIT_SUCCESS else "")
+
+    # And with nothing cached, every request must be answered from the fixtures the first run recorded
+    replay_datadir = datadir.rstrip(os.sep) + "-replay"
+    os.makedirs(replay_datadir)
+    atexit.register(shutil.rmtree, replay_datadir, ignore_errors=True)
+    print("[*] Running backend.py in a new datadir, replaying the fixtures recorded by the first run...")
+    code, errors = run_backend(replay_datadir, log, "--replay-fixtures", fixtures_dir)
+    log.close()
+    failures = [error for error in errors if error["category"] in FAILURE_CATEGORIES]
+    check("replayed run", code == EXIT_SUCCESS and len(failures) == 0, f"exit code {code}; " + "; ".join(f"{error['category']}: {error['message']}" for error in failures) if code != EXIT_SUCCESS or len(failures) > 0 else "")
+    try:
+        replayed = set(pd.read_csv(os.path.join(replay_datadir, "runs", "latest", "fr_doc_analysis.csv"))["fr-docno"])
+    except OSError:
+        replayed = set()
+    check("replayed attribution", replayed == SELFTEST_ATTRIBUTED, f"attributed {', '.join(sorted(replayed))}" if replayed != SELFTEST_ATTRIBUTED else "")
+
+    frapi_dir = datadir.rstrip(os.sep) + "-frapi"
+    atexit.register(shutil.rmtree, frapi_dir, ignore_errors=True)
+    print("[*] Checking the FederalRegister.gov agencies list against replayed fixtures...")
+    check_agencies(frapi_dir, check)
+    print("[*] Checking the paging of FederalRegister.gov searches against replayed fixtures...")
+    check_pages(os.path.join(frapi_dir, "pages"), check)
+    return checks
+
+
+if __name__ == "__main__":
+    import argparse
+    examples = '''
+examples:
+  # Check an install or upgrade of Doge Guard, without the network or a Cohere API key
+  python selftest.py
+
+  # Keep the fixture's datadir and the output of the runs to inspect a failure
+  python selftest.py --keep
+'''
+    parser = argparse.ArgumentParser(description="Run the whole pipeline of backend.py on a tiny fixture CFR Part, with a mock LLM, and check every stage", epilog=examples, formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("--keep", action="store_true", default=False, help="Keep the datadir of the fixture, with a selftest.log of the runs' output, rather than deleting it.")
+    args = parser.parse_args()
+
+    datadir = tempfile.mkdtemp(prefix="doge-guard-selftest-")
+    # Registered first, so it runs after backend.py releases its locks on the datadirs
+    if not args.keep:
+        atexit.register(shutil.rmtree, datadir, ignore_errors=True)
+    checks = selftest(datadir)
+    failed = [name for name, passed, _ in checks if not passed]
+    if args.keep:
+        print(f"[*] The fixture's datadir is {datadir}")
+    if len(failed) > 0:
+        print(f"[!] Self-test FAILED: {', '.join(failed)}. Re-run with --keep to inspect the datadir.")
+        exit(1)
+    print(f"[*] Self-test passed ({len(checks)} checks, {ECFR_DATE} snapshot).")
@@ -0,0 +1,628 @@
+import glob
+import gzip
+import hashlib
+import json
+import os
+import re
+import shutil
+import socket
+import threading
+import time
+import toml
+import urllib.parse
+
+#############################################################
+# Functions for maintaining the local database of documents #
+#############################################################
+
+# Fragments of the error and interstitial pages that have been saved as rule.html in the past
+ERROR_PAGE_MARKERS = [
+    b"Attention Required! | Cloudflare",
+    b"cf-error-details",
+    b"Just a moment...",
+    b"Request Rejected",
+    b"You have exceeded the rate limit",
+]
+# Page titles that mean we got an error page rather than a document. FR documents' titles are the titles of the rules.
+error_title_regex = re.compile(rb"<title>[^<]*(page not found|not found|error|too many requests|access denied|service unavailable|rate limit)[^<]*</title>", re.IGNORECASE)
+
+
+# Characters that are safe in file names on every platform and in object storage keys. Anything else is percent-encoded.
+safe_path_regex = re.compile(r"[A-Za-z0-9_-][A-Za-z0-9._-]*")
+unsafe_path_char_regex = re.compile(r"[^A-Za-z0-9._-]")
+WINDOWS_RESERVED_NAMES = {"CON", "PRN", "AUX", "NUL", *(f"COM{i}" for i in range(1, 10)), *(f"LPT{i}" for i in range(1, 10))}
+# Serializes the updates of path_index.json, which are made from the threads of prefetch_structures and StreamingAnalysis
+PATH_INDEX_LOCK = threading.Lock()
+
+
+def safe_path_component(identifier, datadir):
+    '''
+    Encode an identifier from an external API, e.g. an FR document number, as a file name that is safe on Windows and in
+    object storage. Identifiers that are already safe, which is nearly all of them, are returned unchanged. Others are
+    percent-encoded and recorded in datadir/path_index.json, so original_identifier can map them back.
+    '''
+    identifier = str(identifier)
+    if re.fullmatch(safe_path_regex, identifier) and not identifier.endswith(".") and identifier.split(".")[0].upper() not in WINDOWS_RESERVED_NAMES:
+        return identifier
+
+    component = re.sub(unsafe_path_char_regex, lambda m : "".join(f"%{b:02X}" for b in m[0].encode()), identifier)
+    if component.startswith("."):
+        component = "%2E" + component[1:]
+    if component.endswith("."):
+        component = component[:-1] + "%2E"
+    if component.split(".")[0].upper() in WINDOWS_RESERVED_NAMES:
+        component = f"%{ord(component[0]):02X}" + component[1:]
+
+    with PATH_INDEX_LOCK:
+        index = load_path_index(datadir)
+        if index.get(component) != identifier:
+            index[component] = identifier
+            os.makedirs(datadir, exist_ok=True)
+            write_atomic(os.path.join(datadir, "path_index.json"), json.dumps(index, indent=2))
+    return component
+
+
+def load_path_index(datadir):
+    '''
+    The {component: identifier} index of safe_path_component. If path_index.json is corrupt, e.g. truncated by a crash
+    before it was written atomically, the index is rebuilt from the names of the FR document directories, since
+    percent-encoding can be undone.
+    '''
+    try:
+        with open(os.path.join(datadir, "path_index.json"), "r") as f:
+            return json.load(f)
+    except FileNotFoundError:
+        return {}
+    except ValueError:
+        docs_dir = os.path.join(datadir, "final_rules")
+        if not os.path.isdir(docs_dir):
+            return {}
+        return {component: urllib.parse.unquote(component) for component in os.listdir(docs_dir) if "%" in component}
+
+
+def original_identifier(component, datadir, index=None):
+    '''
+    The inverse of safe_path_component. Pass an index from load_path_index when decoding many components.
+    '''
+    index = load_path_index(datadir) if index is None else index
+    return index.get(component, component)
+
+
+def fr_doc_dir(datadir, docno):
+    '''
+    The directory of the local database holding everything about the FR document with the given document number.
+    '''
+    return os.path.join(datadir, "final_rules", safe_path_component(docno, datadir))
+
+
+def write_atomic(path, content):
+    '''
+    Write content, bytes or str, to path so that a crash or interruption never leaves a partial file at path: it is written
+    to a temporary file next to path, which is renamed over path when complete and deleted if writing fails.
+    '''
+    tmp_path = f"{path}.tmp-{os.getpid()}-{threading.get_ident()}"
+    try:
+        with open(tmp_path, "wb" if isinstance(content, bytes) else "w") as f:
+            f.write(content)
+        os.replace(tmp_path, path)
+    except BaseException:
+        if os.path.exists(tmp_path):
+            os.remove(tmp_path)
+        raise
+
+
+class DatadirLock:
+    '''
+    An advisory lock on a datadir, so that two runs of backend.py (or store.py) can't corrupt each other's caches and
+    outputs. The lock is a datadir/.lock file naming the process holding it. If the lock is held, acquire waits up to
+    timeout seconds for it, then raises an error explaining who holds it. Locks left behind by dead processes on this
+    host are cleaned up automatically. There's no lock per CFR Part's cache entry, since this one covers them: runs of
+    different Parts still share FR documents, agencies.json, and path_index.json, so they can't safely run at once anyway.
+    '''
+    def __init__(self, datadir, timeout=0):
+        self.path = os.path.join(datadir, ".lock")
+        self.timeout = timeout
+        self.held = False
+
+
+    def holder(self):
+        try:
+            with open(self.path, "r") as f:
+                return json.load(f)
+        except (FileNotFoundError, json.JSONDecodeError):
+            return None
+
+
+    def holder_is_dead(self, holder):
+        if holder is None or holder.get("host") != socket.gethostname():
+            return False
+        try:
+            os.kill(holder["pid"], 0)
+        except ProcessLookupError:
+            return True
+        except PermissionError:
+            pass
+        return False
+
+
+    def acquire(self):
+        os.makedirs(os.path.dirname(self.path), exist_ok=True)
+        deadline = time.time() + self.timeout
+        while True:
+            try:
+                fd = os.open(self.path, os.O_CREAT | os.O_EXCL | os.O_WRONLY)
+                with os.fdopen(fd, "w") as f:
+                    json.dump({"pid": os.getpid(), "host": socket.gethostname(), "since": time.strftime("%Y-%m-%d %H:%M:%S")}, f)
+                self.held = True
+                return
+            except FileExistsError:
+                holder = self.holder()
+                if self.holder_is_dead(holder):
+                    print(f"[*] Removing stale lock of dead process {holder['pid']}")
+                    os.remove(self.path)
+                    continue
+                if time.time() >= deadline:
+                    holder = holder or {}
+                    raise RuntimeError(f"{os.path.dirname(self.path)} is in use by process {holder.get('pid', '?')} on {holder.get('host', '?')} since {holder.get('since', '?')}. Wait for it to finish, or if it isn't running, delete {self.path}.")
+                time.sleep(1)
+
+
+    def release(self):
+        if self.held:
+            os.remove(self.path)
+            self.held = False
+
+
+    def __enter__(self):
+        self.acquire()
+        return self
+
+
+    def __exit__(self, *args):
+        self.release()
+
+
+def run_complete(path):
+    '''
+    Whether the run whose directory is path finished writing its results, by the status in its run.json. Runs from before
+    the status was recorded are complete if they wrote their cfr_coverage table.
+    '''
+    try:
+        with open(os.path.join(path, "run.json"), "r") as f:
+            status = json.load(f).get("status")
+    except (FileNotFoundError, ValueError):
+        status = None
+    if status is not None:
+        return status == "complete"
+    return any(name.startswith("cfr_coverage") for name in os.listdir(path))
+
+
+def run_dir(datadir, label, resume=False):
+    '''
+    Returns a new directory for the outputs of a run, datadir/runs/<timestamp>-<label>, so that no run overwrites the
+    results of another. With resume, returns the latest run with the same label that kept its progress in an
+    aggregation.sqlite but didn't finish, e.g. an interrupted one, if there is one. --ALL and --spill-to-disk runs keep
+    their aggregation.sqlite when they finish too, so a run is only resumed if its run.json doesn't say it's complete.
+    '''
+    runs_dir = os.path.join(datadir, "runs")
+    if resume and os.path.isdir(runs_dir):
+        # Run directories are named <15-character timestamp>-<label>
+        previous = sorted(name for name in os.listdir(runs_dir) if name[16:] == label and os.path.exists(os.path.join(runs_dir, name, "aggregation.sqlite")) and not run_complete(os.path.join(runs_dir, name)))
+        if len(previous) > 0:
+            return os.path.join(runs_dir, previous[-1])
+    path = os.path.join(runs_dir, f"{time.strftime('%Y%m%dT%H%M%S')}-{label}")
+    os.makedirs(path, exist_ok=True)
+    return path
+
+
+def link_latest_run(outdir):
+    '''
+    Point the datadir/runs/latest symlink at a run's directory. The link is replaced atomically, so it always names a
+    complete run. Platforms without symlinks keep the name of the latest run in datadir/runs/latest.txt instead.
+    '''
+    runs_dir = os.path.dirname(outdir)
+    tmp_path = os.path.join(runs_dir, f"latest.tmp-{os.getpid()}")
+    try:
+        os.symlink(os.path.basename(outdir), tmp_path, target_is_directory=True)
+        os.replace(tmp_path, os.path.join(runs_dir, "latest"))
+    except OSError:
+        if os.path.lexists(tmp_path):
+            os.remove(tmp_path)
+        write_atomic(os.path.join(runs_dir, "latest.txt"), os.path.basename(outdir) + "\n")
+
+
+def parse_tags(tags):
+    '''
+    Parse the KEY=VALUE metadata tags of a run, e.g. ["reviewer=jdoe", "purpose=baseline"], into {key: value}. Raises a
+    ValueError for a tag without a key.
+    '''
+    parsed = {}
+    for tag in tags:
+        key, sep, value = tag.partition("=")
+        if sep == "" or key.strip() == "":
+            raise ValueError(f"{tag} isn't KEY=VALUE")
+        parsed[key.strip()] = value.strip()
+    return parsed
+
+
+def run_manifests(datadir):
+    '''
+    Returns [(run directory name, run.json)] of the runs in datadir/runs, oldest first. Runs that didn't get as far as
+    writing their run.json are left out.
+    '''
+    runs_dir = os.path.join(datadir, "runs")
+    manifests = []
+    for name in sorted(os.listdir(runs_dir)) if os.path.isdir(runs_dir) else []:
+        if name == "latest" or not os.path.isdir(os.path.join(runs_dir, name)):
+            continue
+        try:
+            with open(os.path.join(runs_dir, name, "run.json"), "r") as f:
+                manifests.append((name, json.load(f)))
+        except (FileNotFoundError, json.JSONDecodeError):
+            continue
+    return manifests
+
+
+def list_runs(datadir, label=None, tags={}):
+    '''
+    Print the runs in datadir/runs with their --label and --tag metadata, oldest first. Only the runs with the given label
+    and all the given tags, {key: value}, are listed.
+    '''
+    runs = [(name, manifest) for name, manifest in run_manifests(datadir) if (label is None or manifest.get("label") == label) and all(manifest.get("tags", {}).get(key) == value for key, value in tags.items())]
+    if len(runs) == 0:
+        print(f"[*] No matching runs in {os.path.join(datadir, 'runs')}.")
+        return
+    width = max(len(name) for name, _ in runs)
+    for name, manifest in runs:
+        metadata = [f"label={manifest['label']}"] if manifest.get("label") else []
+        metadata += [f"{key}={value}" for key, value in sorted(manifest.get("tags", {}).items())]
+        print(f"{name:<{width}}  {' '.join(metadata)}".rstrip())
+
+
+def html_problems(content):
+    '''
+    Returns a list of reasons the given bytes don't look like the full HTML text of an FR document, e.g. because they're
+    a truncated download or an error page. An empty list means no problems were found.
+    '''
+    problems = []
+    if len(content.strip()) == 0:
+        problems.append("empty")
+    for marker in ERROR_PAGE_MARKERS:
+        if marker in content:
+            problems.append(f"error page ({marker.decode()})")
+    title_match = re.search(error_title_regex, content[:4096])
+    if title_match is not None:
+        problems.append(f"error page (title mentions \"{title_match[1].decode()}\")")
+    if b"<html" in content[:1024].lower() and b"</html>" not in content[-1024:].lower():
+        problems.append("truncated")
+    return problems
+
+
+def verify_store(datadir, quarantine=True):
+    '''
+    Re-hash the documents in datadir/final_rules against the hashes recorded in their details.toml and check that they
+    look like real FR documents. Documents predating the recorded hashes get them added. Bad documents are moved to
+    datadir/final_rules_quarantine, so the next run of backend.py fetches them again. Returns the bad documents as a
+    dictionary {docno: [problem]}.
+    '''
+    final_rules = os.path.join(datadir, "final_rules")
+    path_index = load_path_index(datadir)
+    bad_docs = {}
+    doc_dirs = sorted(os.listdir(final_rules)) if os.path.isdir(final_rules) else []
+    for i, doc_dir in enumerate(doc_dirs):
+        docno = original_identifier(doc_dir, datadir, path_index)
+        print(f"[*] Verifying FR documents... {i+1}/{len(doc_dirs)}: {docno}", end="\r", flush=True)
+        document_dir = os.path.join(final_rules, doc_dir)
+        problems = []
+
+        details_path = os.path.join(document_dir, "details.toml")
+        try:
+            details = toml.load(details_path)
+        except (FileNotFoundError, toml.TomlDecodeError) as e:
+            problems.append(f"bad details.toml ({e})")
+            details = None
+
+        details_updated = False
+        for kind in ["html", "pdf"]:
+            try:
+                with open(os.path.join(document_dir, f"rule.{kind}"), "rb") as f:
+                    content = f.read()
+            except FileNotFoundError:
+                problems.append(f"missing rule.{kind}")
+                continue
+
+            sha256 = hashlib.sha256(content).hexdigest()
+            if details is not None:
+                if f"{kind}_sha256" not in details:
+                    details[f"{kind}_sha256"] = sha256
+                    details_updated = True
+                elif details[f"{kind}_sha256"] != sha256:
+                    problems.append(f"rule.{kind} doesn't match its recorded hash")
+
+            if kind == "html":
+                problems.extend(f"rule.html is {problem}" for problem in html_problems(content))
+            elif not content.startswith(b"%PDF"):
+                problems.append("rule.pdf isn't a PDF")
+
+        if details_updated and len(problems) == 0:
+            with open(details_path, "w") as f:
+                toml.dump(details, f)
+
+        if len(problems) > 0:
+            bad_docs[docno] = problems
+            if quarantine:
+                quarantine_dir = os.path.join(datadir, "final_rules_quarantine", doc_dir)
+                if os.path.exists(quarantine_dir):
+                    shutil.rmtree(quarantine_dir)
+                os.makedirs(os.path.dirname(quarantine_dir), exist_ok=True)
+                shutil.move(document_dir, quarantine_dir)
+
+    print(f"[*] Verifying FR documents... {len(doc_dirs) - len(bad_docs)}/{len(doc_dirs)} OK.", flush=True)
+    for docno, problems in bad_docs.items():
+        print(f"\t{docno}: {', '.join(problems)}")
+    if quarantine and len(bad_docs) > 0:
+        print(f"[*] Moved {len(bad_docs)} bad documents to {os.path.join(datadir, 'final_rules_quarantine')}. They will be re-fetched by the next run.")
+    return bad_docs
+
+
+# The files of each class of cached data. Paths are relative to a CFR Part's directory for rules and xml, to a snapshot's
+# structure directory for structure, and to an FR document's directory for llm, where they can be glob patterns, e.g. for
+# the indexes of other embeddings than Cohere's. docs are whole FR document directories.
+CACHE_CLASSES = {
+    "rules": [
+        "rules.json", "presidential_documents.json", "proposed_rules.json", "notices.json",
+        "rules.changes.json", "presidential_documents.changes.json", "proposed_rules.changes.json", "notices.changes.json",
+    ],
+    "xml": ["part.xml"],
+    "structure": ["title-{title}.json"],
+    "docs": [],
+    "llm": ["index", "index-*", "results.txt", "transcript.json.gz", "retrieval.json"],
+    "cases": ["court_cases.json"],
+}
+
+
+def clear_cache(datadir, cache_class, parts=[]):
+    '''
+    Delete one class of cached data from datadir, for every eCFR snapshot, so that the next run of backend.py fetches or
+    computes it again. parts is a list of (titleno, partno) strings limiting what is deleted to those CFR Parts. For FR
+    documents, that means the documents found by the Parts' cached searches. For structure, only the Title matters.
+    '''
+    paths = []
+    part_dirs = []
+    if len(parts) == 0:
+        part_dirs = glob.glob(os.path.join(datadir, "cfr-*", "title-*", "part-*"))
+    for titleno, partno in parts:
+        part_dirs.extend(glob.glob(os.path.join(datadir, "cfr-*", f"title-{titleno}", f"part-{partno}")))
+
+    if cache_class in ["rules", "xml", "cases"]:
+        for part_dir in part_dirs:
+            paths.extend(os.path.join(part_dir, name) for name in CACHE_CLASSES[cache_class])
+    elif cache_class == "structure":
+        titlenos = set(titleno for titleno, _ in parts) if len(parts) > 0 else ["*"]
+        for titleno in titlenos:
+            paths.extend(glob.glob(os.path.join(datadir, "cfr-*", "structure", f"title-{titleno}.json")))
+    if cache_class in ["docs", "llm", "cases"]:
+        if len(parts) == 0:
+            doc_dirs = glob.glob(os.path.join(datadir, "final_rules", "*"))
+        else:
+            doc_dirs = set()
+            for part_dir in part_dirs:
+                for name in CACHE_CLASSES["rules"]:
+                    if name.endswith(".changes.json"):
+                        continue
+                    try:
+                        with open(os.path.join(part_dir, name), "r") as f:
+                            search = json.load(f)
+                    except FileNotFoundError:
+                        continue
+                    doc_dirs.update(fr_doc_dir(datadir, doc["document_number"]) for doc in search.get("results", []))
+        if cache_class == "docs":
+            paths.extend(doc_dirs)
+        else:
+            for doc_dir in doc_dirs:
+                paths.extend(path for name in CACHE_CLASSES[cache_class] for path in glob.glob(os.path.join(doc_dir, name)))
+    elif cache_class not in CACHE_CLASSES:
+        raise ValueError(f"Unknown cache class {cache_class}")
+
+    num_removed = 0
+    for path in paths:
+        if os.path.isdir(path):
+            shutil.rmtree(path)
+        elif os.path.exists(path):
+            os.remove(path)
+        else:
+            continue
+        num_removed += 1
+    print(f"[*] Cleared {num_removed} cached {cache_class} entries.")
+    return num_removed
+
+
+def usage_summary(datadir):
+    '''
+    Summarize the usage stats that backend.py runs with --usage-stats recorded in datadir: how many runs there were and
+    when, how often each option was used, and the total and median duration of each stage of a run. None if there are
+    none.
+    '''
+    runs = []
+    try:
+        with open(os.path.join(datadir, "usage_stats.jsonl"), "r") as f:
+            runs = [json.loads(line) for line in f if line.strip()]
+    except FileNotFoundError:
+        pass
+    if len(runs) == 0:
+        return None
+
+    feature_counts = {}
+    for run in runs:
+        for feature in run["features"]:
+            feature_counts[feature] = feature_counts.get(feature, 0) + 1
+    stage_seconds = {}
+    for run in runs:
+        for stage, seconds in run["stage-seconds"].items():
+            stage_seconds.setdefault(stage, []).append(seconds)
+    return {
+        "runs": len(runs),
+        "first-run": runs[0]["date"],
+        "last-run": runs[-1]["date"],
+        "unsuccessful-runs": len([run for run in runs if run["exit-code"] != 0]),
+        "features": dict(sorted(feature_counts.items(), key=lambda item : -item[1])),
+        "stages": {
+            stage: {"total-seconds": sum(seconds), "median-seconds": sorted(seconds)[len(seconds) // 2], "runs": len(seconds)}
+            for stage, seconds in sorted(stage_seconds.items(), key=lambda item : -sum(item[1]))
+        },
+    }
+
+
+def usage_stats(datadir):
+    '''
+    Print the usage_summary of datadir.
+    '''
+    summary = usage_summary(datadir)
+    if summary is None:
+        print(f"[*] No usage stats in {datadir}. Run backend.py with --usage-stats to record them.")
+        return
+
+    print(f"[*] {summary['runs']} runs from {summary['first-run']} to {summary['last-run']}, {summary['unsuccessful-runs']} not successful.")
+    if len(summary["features"]) > 0:
+        print("[*] Options used:")
+        width = max(map(len, summary["features"]))
+        for feature, count in summary["features"].items():
+            print(f"\t{feature:<{width}}  {count} runs")
+    if len(summary["stages"]) > 0:
+        print("[*] Stages:")
+        width = max(map(len, summary["stages"]))
+        for stage, stats in summary["stages"].items():
+            print(f"\t{stage:<{width}}  {stats['total-seconds'] / 3600:.2f} h total, {stats['median-seconds']:.1f} s median over {stats['runs']} runs")
+
+
+def print_transcript(datadir, docno):
+    '''
+    Pretty-print the transcript of the LLM analysis of an FR document, i.e. the prompt, retrieved excerpts, and response of
+    each chat call, recorded by backend.py runs with --transcripts.
+    '''
+    path = os.path.join(fr_doc_dir(datadir, docno), "transcript.json.gz")
+    try:
+        with gzip.open(path, "rt") as f:
+            transcript = json.load(f)
+    except FileNotFoundError:
+        print(f"[!] No transcript of {docno} in {datadir}. Run backend.py with --transcripts to record them.")
+        return
+    for i, call in enumerate(transcript):
+        request, response = call["request"], call["response"]
+        print(f"{'=' * 40} Call {i + 1}/{len(transcript)}: {request['model']}{' (search queries only)' if request.get('search_queries_only') else ''} {'=' * 40}")
+        print(f"PREAMBLE:\n{request['preamble'].strip()}\n")
+        print(f"MESSAGE:\n{request['message'].strip()}\n")
+        if len(request.get("documents", [])) > 0:
+            print(f"DOCUMENTS ({len(request['documents'])}):")
+            for document in request["documents"]:
+                print(f"\t[{document.get('id', '')}] {document.get('title', '')}: {' '.join(str(document.get('text', '')).split())}")
+            print()
+        if len(response["search-queries"]) > 0:
+            print("SEARCH QUERIES:\n" + "\n".join(f"\t{query}" for query in response["search-queries"]) + "\n")
+        if response["text"]:
+            print(f"RESPONSE:\n{response['text'].strip()}\n")
+        if len(response["citations"]) > 0:
+            print("CITATIONS:\n" + "\n".join(f"\t{citation}" for citation in response["citations"]) + "\n")
+
+
+if __name__ == "__main__":
+    import argparse
+    from completion import CompletionAction
+    parser = argparse.ArgumentParser(description="Maintain the local database of documents created by backend.py", epilog="Run `python store.py COMMAND --help` for examples of each command. Load shell completion with `source <(python store.py --completion bash)`.")
+    parser.add_argument("--completion", action=CompletionAction, script="store.py", help="Print the shell completion script of store.py and exit.")
+    subparsers = parser.add_subparsers(dest="command", required=True)
+
+    verify_examples = '''
+examples:
+  # Report bad documents and move them aside so the next run re-fetches them
+  python store.py verify documents/
+
+  # Only report them
+  python store.py verify --dry-run documents/
+'''
+    verify_parser = subparsers.add_parser("verify", help="Check the stored FR documents for corruption and queue bad ones to be re-fetched.", epilog=verify_examples, formatter_class=argparse.RawDescriptionHelpFormatter)
+    verify_parser.add_argument("datadir", help="The directory holding the results and analyzed data")
+    verify_parser.add_argument("--dry-run", action="store_true", default=False, help="Only report bad documents, don't move them.")
+
+    clear_examples = '''
+examples:
+  # Re-fetch the FR searches of 40 CFR Part 63
+  python store.py clear documents/ --class rules --part 40:63
+
+  # Re-run the LLM analysis of every FR document
+  python store.py clear documents/ --class llm
+'''
+    clear_parser = subparsers.add_parser("clear", help="Delete one class of cached data, e.g. after an upstream data fix, so the next run fetches it again.", epilog=clear_examples, formatter_class=argparse.RawDescriptionHelpFormatter)
+    clear_parser.add_argument("datadir", help="The directory holding the results and analyzed data")
+    clear_parser.add_argument("--class", dest="cache_class", choices=list(CACHE_CLASSES), required=True, help="rules: FR searches per Part; xml: eCFR text per Part; structure: eCFR structure per Title; docs: FR documents; llm: embeddings and LLM results per FR document; cases: CourtListener searches per Part and FR document.")
+    clear_parser.add_argument("--part", action="append", default=[], metavar="TITLE:PART", help="Only clear data of this CFR Part (e.g., 40:63). This argument can be listed multiple times.")
+
+    stats_parser = subparsers.add_parser("stats", help="Summarize the usage stats recorded by backend.py runs with --usage-stats.")
+    stats_parser.add_argument("datadir", help="The directory holding the results and analyzed data")
+
+    cache_server_examples = '''
+examples:
+  # Share the documents/ cache with a team on port 8000. The analysts set CACHE_SERVER_URL=http://<this host>:8000 and the
+  # same CACHE_SERVER_TOKEN in their .env files.
+  CACHE_SERVER_TOKEN=SomeLongSecret python store.py cache-server --host 0.0.0.0 --port 8000 documents/
+'''
+    cache_server_parser = subparsers.add_parser("cache-server", help="Serve the eCFR, FederalRegister.gov, and GovInfo responses cached in datadir to other instances, fetching the ones that aren't cached yet.", epilog=cache_server_examples, formatter_class=argparse.RawDescriptionHelpFormatter)
+    cache_server_parser.add_argument("datadir", help="The directory holding the results and analyzed data")
+    cache_server_parser.add_argument("--host", default="127.0.0.1", help="The address to listen on, e.g. 0.0.0.0 for every network interface.")
+    cache_server_parser.add_argument("--port", type=int, default=8000, help="The port to listen on.")
+
+    transcript_examples = '''
+examples:
+  # Show what the LLM was asked about FR document 2024-12345 and what it answered
+  python store.py transcript documents/ 2024-12345 | less
+'''
+    transcript_parser = subparsers.add_parser("transcript", help="Pretty-print the prompts and responses of the LLM analysis of an FR document, recorded by runs with --transcripts.", epilog=transcript_examples, formatter_class=argparse.RawDescriptionHelpFormatter)
+    transcript_parser.add_argument("datadir", help="The directory holding the results and analyzed data")
+    transcript_parser.add_argument("docno", help="The FR document number")
+
+    runs_examples = '''
+examples:
+  # Find the baseline runs of months ago
+  python store.py runs documents/ --label post-election-baseline
+
+  # List the runs reviewed by jdoe
+  python store.py runs documents/ --tag reviewer=jdoe
+'''
+    runs_parser = subparsers.add_parser("runs", help="List the runs of backend.py in datadir with their labels and tags.", epilog=runs_examples, formatter_class=argparse.RawDescriptionHelpFormatter)
+    runs_parser.add_argument("datadir", help="The directory holding the results and analyzed data")
+    runs_parser.add_argument("--label", default=None, help="Only list the runs with this --label.")
+    runs_parser.add_argument("--tag", action="append", default=[], metavar="KEY=VALUE", help="Only list the runs with this --tag. This argument can be listed multiple times.")
+
+    args = parser.parse_args()
+    if args.command == "stats":
+        # Read-only, so this doesn't need the lock
+        usage_stats(args.datadir)
+        exit(0)
+    if args.command == "runs":
+        # Read-only too
+        try:
+            tags = parse_tags(args.tag)
+        except ValueError as e:
+            parser.error(f"bad --tag: {e}")
+        list_runs(args.datadir, args.label, tags)
+        exit(0)
+    if args.command == "transcript":
+        print_transcript(args.datadir, args.docno)
+        exit(0)
+    if args.command == "cache-server":
+        # Only writes complete files to its own cache directory, so this doesn't need the lock either. Its admin routes that
+        # change datadir take the lock themselves.
+        from frapi import CACHE_SERVER_TOKEN, serve_cache
+        if not CACHE_SERVER_TOKEN:
+            parser.error("set CACHE_SERVER_TOKEN in the environment or .env to the token the clients must send")
+        serve_cache(args.datadir, args.host, args.port)
+        exit(0)
+    if args.command == "clear":
+        parts = [tuple(part.split(":", 1)) for part in args.part]
+        if any(len(part) != 2 or "" in part for part in parts):
+            parser.error("--part must be TITLE:PART")
+    with DatadirLock(args.datadir):
+        if args.command == "verify":
+            verify_store(args.datadir, quarantine=not args.dry_run)
+        elif args.command == "clear":
+            clear_cache(args.datadir, args.cache_class, parts)